@@ -1,4 +1,6 @@
-use kong_rs::{ok_or_internal_error, KongError, Pdk, Phase, Plugin, PluginFactory, PluginResult, PluginServerBroker};
+use std::collections::{HashMap, HashSet};
+
+use kong_rs::{config::types::{HeaderName, Url}, ok_or_internal_error, ConfigFactory, FromConfig, KongResult, Pdk, Phase, Plugin, PluginResult, PluginServerBroker, Scopes};
 
 #[derive(Debug, Clone, serde::Serialize, serde::Deserialize, kong_rs::PluginConfig)]
 enum MyEnum {
@@ -8,7 +10,9 @@ enum MyEnum {
 }
 
 #[derive(Debug, Clone, serde::Serialize, serde::Deserialize, kong_rs::PluginConfig)]
+#[kong(conditional(if_field = c, if_eq = "Test3", then_field = b, then_required))]
 struct InnerConfig {
+  #[kong(description = "A short tag", len_min = 1, len_max = 32, match = "^[%w%-]+$")]
   a: String,
   b: Option<String>,
   c: MyEnum
@@ -26,8 +30,17 @@ impl Default for InnerConfig {
 
 #[derive(Debug, Clone, serde::Serialize, serde::Deserialize, kong_rs::PluginConfig)]
 struct LogPluginConfig {
+  #[kong(description = "Logged on every request", referenceable)]
   my_field: String,
+  #[kong(len_max = 10)]
   my_other_field: Vec<isize>,
+  #[kong(default = 3, between = [1, 10])]
+  retries: isize,
+  timeout_ms: u32,
+  sample_rate: f64,
+  collector: Option<Url>,
+  extra_headers: HashMap<HeaderName, String>,
+  tags: HashSet<String>,
   inner: InnerConfig
 }
 
@@ -36,6 +49,12 @@ impl Default for LogPluginConfig {
     Self {
       my_field: "Hello World".to_owned(),
       my_other_field: vec![42, 69, 420],
+      retries: 3,
+      timeout_ms: 1000,
+      sample_rate: 1.0,
+      collector: None,
+      extra_headers: HashMap::new(),
+      tags: HashSet::new(),
       inner: InnerConfig::default()
     }
   }
@@ -46,10 +65,11 @@ struct LogPlugin { }
 #[async_trait::async_trait]
 impl Plugin for LogPlugin {
   type Config = LogPluginConfig;
-  const NAME: &str = "log_plugin";
+  const NAME: &str = "kong-rs-log";
   const VERSION: &str = "0.1.1";
   const PRIORITY: i32 = 10;
   const PHASES: &[Phase] = &[Phase::Access];
+  const SCOPES: Scopes = Scopes { consumer: false, ..Scopes::ALL };
 
   async fn access(&self, pdk: &Pdk) -> PluginResult<Vec<u8>> {
     ok_or_internal_error(async move {
@@ -64,21 +84,53 @@ impl Plugin for LogPlugin {
   fn default_config() -> Self::Config { Self::Config::default() }
 }
 
-struct LogPluginFactory {}
-
 #[async_trait::async_trait]
-impl PluginFactory for LogPluginFactory {
-  type Plugin = LogPlugin;
-
-  async fn new(&self, config_data: &str) -> Self::Plugin {
-    println!("Data: {:?}", serde_json::from_str::<'_, LogPluginConfig>(config_data).unwrap());
-    LogPlugin {  } 
+impl FromConfig for LogPlugin {
+  async fn from_config(config: Self::Config) -> KongResult<Self> {
+    eprintln!("Data: {:?}", config);
+    Ok(LogPlugin { })
   }
 }
 
 #[tokio::main]
-async fn main() {
+async fn main() -> std::process::ExitCode {
   let broker = PluginServerBroker::new();
-  broker.register(LogPluginFactory {}).await;
-  broker.run(std::env::args()).await.unwrap();
+  if let Err(e) = broker.register(ConfigFactory::<LogPlugin>::new()).await {
+    eprintln!("{}", e);
+    return std::process::ExitCode::FAILURE;
+  }
+  broker.exec(std::env::args()).await
+}
+
+#[cfg(test)]
+mod tests {
+  use kong_rs::{plugin::ErasedPluginFactory, testing::MockKong, ConfigFactory, Phase, PluginServerBroker};
+  use kong_rs_protos::Route;
+
+  use super::LogPlugin;
+
+  #[tokio::test]
+  async fn logs_the_route() {
+    let plugin = ConfigFactory::<LogPlugin>::new().new("{}").await.unwrap();
+    let recorded = MockKong::new()
+      .with_route(Route { name: "users".to_owned(), ..Default::default() })
+      .run(plugin.as_ref(), Phase::Access).await.unwrap();
+
+    assert_eq!(recorded.logs_at("err"), vec!["Oh no! Anyway...", "Route: users"]);
+    assert_eq!(recorded.calls, vec!["kong.log.err", "kong.router.get_route", "kong.log.err"]);
+    assert!(recorded.exit.is_none());
+  }
+
+  #[tokio::test]
+  async fn rejects_an_invalid_config() {
+    let error = ConfigFactory::<LogPlugin>::new().new(r#"{ "retries": 11 }"#).await.err().unwrap();
+    assert!(error.to_string().contains("retries: value should be between 1 and 10"), "{}", error);
+  }
+
+  #[tokio::test]
+  async fn accepts_the_example_config() {
+    let broker = PluginServerBroker::new();
+    broker.register(ConfigFactory::<LogPlugin>::new()).await.unwrap();
+    assert_eq!(broker.validate_declarative(include_str!("../../kong.yml"), "log").await.unwrap(), 1);
+  }
 }
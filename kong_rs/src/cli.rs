@@ -0,0 +1,126 @@
+use std::{fmt::Display, path::{Path, PathBuf}};
+
+use crate::server::{LogLevel, WireMode};
+
+// Exit codes follow Go's flag package, which is what Kong expects of its plugin servers.
+pub const EXIT_FAILURE: u8 = 1;
+pub const EXIT_USAGE: u8 = 2;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Command {
+  Serve,
+  Dump,
+  DumpAllPlugins,
+  LuaSchema,
+  JsonSchema,
+  Validate(PathBuf),
+  Replay(PathBuf),
+  Help,
+  Version
+}
+
+#[derive(Debug, Clone)]
+pub struct Cli {
+  pub program: String,
+  pub command: Command,
+  pub kong_prefix: Option<String>,
+  pub socket: Option<PathBuf>,
+  pub log_level: Option<LogLevel>,
+  pub record: Option<PathBuf>,
+  pub wire_mode: Option<WireMode>,
+}
+
+#[derive(Debug, Clone)]
+pub struct UsageError {
+  pub program: String,
+  pub message: String
+}
+
+impl Display for UsageError {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    write!(f, "{}\n\n{}", self.message, Cli::usage(&self.program))
+  }
+}
+
+impl Cli {
+  // Flags may be given with one or two dashes, and values either as the next argument or after an '='.
+  pub fn parse<I: Iterator<Item = String>>(mut args: I) -> Result<Self, UsageError> {
+    let program = args.next()
+      .and_then(|x| Path::new(&x).file_name().map(|x| x.to_string_lossy().into_owned()))
+      .unwrap_or_else(|| "kong_rs".to_owned());
+
+    let mut cli = Cli { program: program.clone(), command: Command::Serve, kong_prefix: None, socket: None, log_level: None, record: None, wire_mode: None };
+    let error = |message: String| UsageError { program: program.clone(), message };
+
+    while let Some(arg) = args.next() {
+      let Some(flag) = arg.strip_prefix("--").or_else(|| arg.strip_prefix('-')).filter(|x| !x.is_empty()) else {
+        return Err(error(format!("Unexpected argument: {}", arg)));
+      };
+
+      let (flag, inline_value) = match flag.split_once('=') {
+        Some((flag, value)) => (flag, Some(value.to_owned())),
+        None => (flag, None)
+      };
+
+      let mut value = || inline_value.clone().or_else(|| args.next()).ok_or_else(|| error(format!("-{} requires a value", flag)));
+
+      let command = match flag {
+        "dump" => Some(Command::Dump),
+        "dump-all-plugins" => Some(Command::DumpAllPlugins),
+        "lua-schema" => Some(Command::LuaSchema),
+        "json-schema" => Some(Command::JsonSchema),
+        "validate" => Some(Command::Validate(PathBuf::from(value()?))),
+        "replay" => Some(Command::Replay(PathBuf::from(value()?))),
+        "help" | "h" => Some(Command::Help),
+        "version" => Some(Command::Version),
+        "kong-prefix" => { cli.kong_prefix = Some(value()?); None },
+        "socket" => { cli.socket = Some(PathBuf::from(value()?)); None },
+        "record" => { cli.record = Some(PathBuf::from(value()?)); None },
+        "log-level" => {
+          let level = value()?;
+          cli.log_level = Some(level.parse().map_err(|_| error(format!("Unknown log level: {}", level)))?);
+          None
+        },
+        "wire-mode" => {
+          let mode = value()?;
+          cli.wire_mode = Some(mode.parse().map_err(|_| error(format!("Unknown wire mode: {}", mode)))?);
+          None
+        },
+        _ => return Err(error(format!("Unknown flag: {}", arg)))
+      };
+
+      if let Some(command) = command {
+        if cli.command != Command::Serve && cli.command != command {
+          return Err(error(format!("-{} cannot be combined with other commands", flag)));
+        }
+        cli.command = command;
+      }
+    }
+
+    Ok(cli)
+  }
+
+  pub fn usage(program: &str) -> String {
+    format!(
+"Usage: {} [flags]
+
+Starts a Kong plugin server hosting the plugins registered in this binary.
+
+Flags:
+  -dump                  Print the plugin info Kong requests at startup, and exit
+  -dump-all-plugins      Print the info of every registered plugin, and exit
+  -lua-schema            Print the plugin's schema as a Kong schema.lua module, and exit
+  -json-schema           Print a JSON Schema for the plugin's entry in a declarative config, and exit
+  -validate <file>       Check the plugin's configs in a declarative config (kong.yml), and exit. Fails if there are none
+  -replay <file>         Re-run the events in a trace from -record and report changed PDK calls, and exit
+  -kong-prefix <dir>     Kong prefix directory to create the socket in (default $KONG_PREFIX or /usr/local/kong)
+  --socket <path>        Explicit socket path, overriding the Kong prefix (default $KONG_RS_SOCKET)
+  --log-level <level>    One of error, warn, info or debug (default warn)
+  -record <file>         Write every RPC frame and PDK call to a JSON lines trace while serving. Traces hold
+                         full headers and bodies, including credentials and cookies, unredacted
+  -wire-mode <mode>      lockstep, as Kong speaks (default), or multiplexed, an experimental kong_rs-only mode
+                         sending PDK calls as PdkCall messages, which stock Kong can't drive
+  -version               Print the version of each registered plugin, and exit
+  -help                  Print this message, and exit", program)
+  }
+}
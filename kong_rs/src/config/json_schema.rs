@@ -0,0 +1,169 @@
+use std::collections::HashMap;
+
+use serde_json::{json, Map, Value};
+
+use super::{EntityCheck, FieldMatch, RenderedConfigFieldVariant};
+
+// Translates a plugin schema into a JSON Schema (draft 2020-12) describing the plugin's entry in a declarative
+// config. Lua patterns have no JSON Schema equivalent, so `match` and `not_match` are left out.
+
+pub fn json_schema(name: &str, fields: &[HashMap<String, RenderedConfigFieldVariant>]) -> Value {
+  let mut schema = record(fields.iter().flatten(), None);
+  let obj = schema.as_object_mut().unwrap();
+  obj.insert("$schema".to_owned(), json!("https://json-schema.org/draft/2020-12/schema"));
+  obj.insert("title".to_owned(), json!(name));
+
+  let properties = obj.get_mut("properties").unwrap().as_object_mut().unwrap();
+  properties.insert("name".to_owned(), json!({ "const": name }));
+  properties.insert("enabled".to_owned(), json!({ "type": "boolean", "default": true }));
+  properties.insert("id".to_owned(), json!({ "type": "string" }));
+  properties.insert("instance_name".to_owned(), json!({ "type": ["string", "null"] }));
+  properties.insert("tags".to_owned(), json!({ "type": ["array", "null"], "items": { "type": "string" } }));
+
+  // Scopes the plugin doesn't forbid may reference their entity by name or id.
+  for scope in ["consumer", "route", "service", "consumer_group"] {
+    properties.entry(scope).or_insert(json!({ "type": ["string", "object", "null"] }));
+  }
+  obj.insert("required".to_owned(), json!(["name"]));
+
+  schema
+}
+
+fn record<'a>(fields: impl Iterator<Item = (&'a String, &'a RenderedConfigFieldVariant)>, checks: Option<&Vec<EntityCheck>>) -> Value {
+  let mut properties = Map::new();
+  let mut required = vec![];
+
+  for (name, field) in fields {
+    // Kong fills in defaults, so only fields without one have to be written out.
+    if field.required == Some(true) && field.default.is_none() {
+      required.push(json!(name));
+    }
+    properties.insert(name.clone(), field_schema(field));
+  }
+
+  let mut schema = json!({
+    "type": "object",
+    "properties": properties,
+    "additionalProperties": false
+  });
+  if !required.is_empty() {
+    schema["required"] = Value::Array(required);
+  }
+
+  let all_of: Vec<_> = checks.into_iter().flatten().flat_map(entity_check).collect();
+  if !all_of.is_empty() {
+    schema["allOf"] = Value::Array(all_of);
+  }
+
+  schema
+}
+
+fn field_schema(field: &RenderedConfigFieldVariant) -> Value {
+  let mut schema = match field.ty.as_str() {
+    "string" | "boolean" | "integer" | "number" => json!({ "type": field.ty }),
+    "array" | "set" => {
+      let mut schema = json!({ "type": "array" });
+      if let Some(elements) = &field.elements {
+        schema["items"] = field_schema(elements);
+      }
+      if field.ty == "set" {
+        schema["uniqueItems"] = json!(true);
+      }
+      schema
+    },
+    "map" => {
+      let mut schema = json!({ "type": "object" });
+      if let Some(keys) = &field.keys {
+        schema["propertyNames"] = field_schema(keys);
+      }
+      if let Some(values) = &field.values {
+        schema["additionalProperties"] = field_schema(values);
+      }
+      schema
+    },
+    "record" => match &field.fields {
+      Some(fields) => record(fields.iter().flatten(), field.entity_checks.as_ref()),
+      None => json!({ "type": "object" })
+    },
+    "foreign" if field.eq == Some(Value::Null) => return json!({ "type": "null" }),
+    _ => json!({})
+  };
+
+  let obj = schema.as_object_mut().unwrap();
+  let mut set = |key: &str, value: Option<Value>| {
+    if let Some(value) = value {
+      obj.insert(key.to_owned(), value);
+    }
+  };
+
+  set("description", field.description.clone().map(Value::String));
+  set("default", field.default.clone());
+  set("enum", field.one_of.clone().map(Value::Array));
+  set("const", field.eq.clone());
+  set("exclusiveMinimum", field.gt.clone());
+
+  if let Some([min, max]) = &field.between {
+    set("minimum", Some(min.clone()));
+    set("maximum", Some(max.clone()));
+  }
+
+  let (min_key, max_key) = match field.ty.as_str() {
+    "string" => ("minLength", "maxLength"),
+    _ => ("minItems", "maxItems")
+  };
+  set(min_key, field.len_min.map(Value::from));
+  set(max_key, field.len_max.map(Value::from));
+
+  // Optional fields may be explicitly null, which Kong treats as unset.
+  if field.required == Some(false) && let Some(Value::String(ty)) = obj.get("type").cloned() {
+    obj.insert("type".to_owned(), json!([ty, "null"]));
+    if let Some(Value::Array(one_of)) = obj.get_mut("enum") {
+      one_of.push(Value::Null);
+    }
+  }
+
+  schema
+}
+
+fn is_set(name: &str) -> Value {
+  json!({ "required": [name], "properties": { name: { "not": { "type": "null" } } } })
+}
+
+fn matches(name: &str, matcher: &FieldMatch) -> Value {
+  let mut property = Map::new();
+  if let Some(eq) = &matcher.eq {
+    property.insert("const".to_owned(), eq.clone());
+  }
+  if let Some(one_of) = &matcher.one_of {
+    property.insert("enum".to_owned(), Value::Array(one_of.clone()));
+  }
+
+  let mut schema = json!({ "properties": { name: property } });
+  if matcher.required == Some(true) {
+    schema["required"] = json!([name]);
+    schema["properties"][name]["not"] = json!({ "type": "null" });
+  }
+  schema
+}
+
+fn entity_check(check: &EntityCheck) -> Vec<Value> {
+  match check {
+    EntityCheck::AtLeastOneOf(names) => vec![json!({ "anyOf": names.iter().map(|x| is_set(x)).collect::<Vec<_>>() })],
+    EntityCheck::OnlyOneOf(names) => vec![json!({ "oneOf": names.iter().map(|x| is_set(x)).collect::<Vec<_>>() })],
+    EntityCheck::MutuallyExclusive(names) => names.iter().enumerate().flat_map(|(i, a)| {
+      names[i + 1..].iter().map(move |b| json!({ "not": { "allOf": [is_set(a), is_set(b)] } }))
+    }).collect(),
+    EntityCheck::MutuallyRequired(names) => vec![json!({
+      "anyOf": [
+        { "allOf": names.iter().map(|x| is_set(x)).collect::<Vec<_>>() },
+        { "not": { "anyOf": names.iter().map(|x| is_set(x)).collect::<Vec<_>>() } }
+      ]
+    })],
+    EntityCheck::Conditional { if_field, if_match, then_field, then_match } => {
+      let mut condition = matches(if_field, if_match);
+      condition["required"] = json!([if_field]);
+      condition["properties"][if_field]["not"] = json!({ "type": "null" });
+      vec![json!({ "if": condition, "then": matches(then_field, then_match) })]
+    }
+  }
+}
@@ -0,0 +1,110 @@
+use std::{collections::HashMap, fmt::Write};
+
+use serde_json::Value;
+
+use super::{to_value, RenderedConfigFieldVariant};
+
+// Renders a plugin schema as the schema.lua module a Lua plugin would ship, from the same tree sent in -dump.
+
+const KEYWORDS: &[&str] = &[
+  "and", "break", "do", "else", "elseif", "end", "false", "for", "function", "goto", "if", "in",
+  "local", "nil", "not", "or", "repeat", "return", "then", "true", "until", "while"
+];
+
+pub fn lua_schema(name: &str, fields: &[HashMap<String, RenderedConfigFieldVariant>]) -> String {
+  let mut out = String::from("return {\n");
+  writeln!(out, "  name = {},", string(name)).unwrap();
+  out.push_str("  fields = {\n");
+  for field in fields {
+    for (name, schema) in field {
+      out.push_str("    { ");
+      write_key(&mut out, name);
+      write_value(&mut out, &to_value(schema), 2);
+      out.push_str(" },\n");
+    }
+  }
+  out.push_str("  },\n}\n");
+  out
+}
+
+fn string(s: &str) -> String {
+  let mut out = String::from("\"");
+  for c in s.chars() {
+    match c {
+      '"' => out.push_str("\\\""),
+      '\\' => out.push_str("\\\\"),
+      '\n' => out.push_str("\\n"),
+      '\r' => out.push_str("\\r"),
+      '\t' => out.push_str("\\t"),
+      c if c.is_control() => write!(out, "\\{:03}", c as u32).unwrap(),
+      c => out.push(c)
+    }
+  }
+  out.push('"');
+  out
+}
+
+fn write_key(out: &mut String, key: &str) {
+  let is_ident = key.chars().next().is_some_and(|x| x.is_ascii_alphabetic() || x == '_')
+    && key.chars().all(|x| x.is_ascii_alphanumeric() || x == '_')
+    && !KEYWORDS.contains(&key);
+
+  match is_ident {
+    true => write!(out, "{} = ", key).unwrap(),
+    false => write!(out, "[{}] = ", string(key)).unwrap()
+  }
+}
+
+fn write_value(out: &mut String, value: &Value, depth: usize) {
+  let indent = "  ".repeat(depth + 1);
+  match value {
+    Value::Null => out.push_str("ngx.null"),
+    Value::Bool(b) => write!(out, "{}", b).unwrap(),
+    Value::Number(n) => write!(out, "{}", n).unwrap(),
+    Value::String(s) => out.push_str(&string(s)),
+    Value::Array(arr) if arr.iter().all(|x| !x.is_object() && !x.is_array()) => {
+      out.push_str("{ ");
+      for (i, x) in arr.iter().enumerate() {
+        if i > 0 {
+          out.push_str(", ");
+        }
+        write_value(out, x, depth);
+      }
+      out.push_str(if arr.is_empty() { "}" } else { " }" });
+    },
+    Value::Array(arr) => {
+      out.push_str("{\n");
+      for x in arr {
+        out.push_str(&indent);
+        match x {
+          // Entries of `fields` are written `{ name = { ... } }`, the way Kong's own schemas lay them out.
+          Value::Object(entry) if entry.len() == 1 => {
+            let (key, value) = entry.iter().next().unwrap();
+            out.push_str("{ ");
+            write_key(out, key);
+            write_value(out, value, depth + 1);
+            out.push_str(" }");
+          },
+          x => write_value(out, x, depth + 1)
+        }
+        out.push_str(",\n");
+      }
+      write!(out, "{}}}", "  ".repeat(depth)).unwrap();
+    },
+    Value::Object(obj) if obj.is_empty() => out.push_str("{}"),
+    Value::Object(obj) => {
+      // Lead with the type, as hand-written schemas do.
+      let mut keys: Vec<_> = obj.keys().collect();
+      keys.sort_by_key(|x| (*x != "type", *x));
+
+      out.push_str("{\n");
+      for key in keys {
+        out.push_str(&indent);
+        write_key(out, key);
+        write_value(out, &obj[key], depth + 1);
+        out.push_str(",\n");
+      }
+      write!(out, "{}}}", "  ".repeat(depth)).unwrap();
+    }
+  }
+}
@@ -0,0 +1,395 @@
+// Kong's `match` and `not_match` validators take Lua patterns rather than regular expressions. This is
+// a port of the matcher in Lua's lstrlib.c, enough to run `string.find(value, pattern)` the way Kong does
+// so configs are checked identically on both sides. Malformed patterns, which Lua raises an error for, never match.
+
+const MAX_DEPTH: usize = 200;
+const MAX_CAPTURES: usize = 32;
+const ESCAPE: u8 = b'%';
+
+#[derive(Clone, Copy)]
+enum Capture {
+  Unfinished,
+  Position,
+  Len(usize)
+}
+
+struct MatchState<'a> {
+  src: &'a [u8],
+  pat: &'a [u8],
+  depth: usize,
+  captures: Vec<(usize, Capture)>
+}
+
+pub fn find(subject: &str, pattern: &str) -> bool {
+  let src = subject.as_bytes();
+  let pat = pattern.as_bytes();
+  let anchor = pat.first() == Some(&b'^');
+  let start = if anchor { 1 } else { 0 };
+
+  if !well_formed(pat, start) {
+    return false;
+  }
+
+  let mut ms = MatchState { src, pat, depth: 0, captures: vec![] };
+  let mut s = 0;
+  loop {
+    ms.captures.clear();
+    ms.depth = 0;
+    if ms.do_match(s, start).is_some() {
+      return true;
+    }
+    s += 1;
+    if anchor || s > src.len() {
+      return false;
+    }
+  }
+}
+
+// Lua only raises an error once the matcher reaches the malformed part, or for an unfinished capture once a match
+// is found. Checking up front rules out both, and also catches patterns Lua happens to never get far enough into.
+fn well_formed(pat: &[u8], start: usize) -> bool {
+  let ms = MatchState { src: &[], pat, depth: 0, captures: vec![] };
+  // Whether each capture has been closed, in the order they were opened.
+  let mut closed: Vec<bool> = vec![];
+  let mut p = start;
+
+  while p < pat.len() {
+    let next = match pat[p] {
+      b'(' if closed.len() == MAX_CAPTURES => None,
+      b'(' if pat.get(p + 1) == Some(&b')') => {
+        closed.push(true);
+        Some(p + 2)
+      },
+      b'(' => {
+        closed.push(false);
+        Some(p + 1)
+      },
+      b')' => closed.iter().rposition(|x| !x).map(|i| {
+        closed[i] = true;
+        p + 1
+      }),
+      ESCAPE => match pat.get(p + 1) {
+        Some(b'b') => (p + 4 <= pat.len()).then_some(p + 4),
+        Some(b'f') if pat.get(p + 2) == Some(&b'[') => ms.class_end(p + 2),
+        Some(b'f') => None,
+        Some(&d) if d.is_ascii_digit() => {
+          let index = (d as usize).checked_sub(b'1' as usize);
+          index.and_then(|i| closed.get(i)).copied().unwrap_or(false).then_some(p + 2)
+        },
+        _ => ms.class_end(p)
+      },
+      _ => ms.class_end(p)
+    };
+
+    match next {
+      Some(next) => p = next,
+      None => return false
+    }
+  }
+  closed.iter().all(|x| *x)
+}
+
+fn match_class(c: u8, class: u8) -> bool {
+  let res = match class.to_ascii_lowercase() {
+    b'a' => c.is_ascii_alphabetic(),
+    b'c' => c.is_ascii_control(),
+    b'd' => c.is_ascii_digit(),
+    b'g' => c.is_ascii_graphic(),
+    b'l' => c.is_ascii_lowercase(),
+    b'p' => c.is_ascii_punctuation(),
+    b's' => c.is_ascii_whitespace() || c == 0x0b,
+    b'u' => c.is_ascii_uppercase(),
+    b'w' => c.is_ascii_alphanumeric(),
+    b'x' => c.is_ascii_hexdigit(),
+    _ => return class == c
+  };
+  if class.is_ascii_uppercase() { !res } else { res }
+}
+
+impl MatchState<'_> {
+  // Index just past the single character class starting at p.
+  fn class_end(&self, mut p: usize) -> Option<usize> {
+    let c = *self.pat.get(p)?;
+    p += 1;
+    match c {
+      ESCAPE => {
+        self.pat.get(p)?;
+        Some(p + 1)
+      },
+      b'[' => {
+        if self.pat.get(p) == Some(&b'^') {
+          p += 1;
+        }
+        loop {
+          let c = *self.pat.get(p)?;
+          p += 1;
+          if c == ESCAPE {
+            self.pat.get(p)?;
+            p += 1;
+          }
+          if *self.pat.get(p)? == b']' {
+            return Some(p + 1);
+          }
+        }
+      },
+      _ => Some(p)
+    }
+  }
+
+  // p is the opening '[' and ec the closing ']'.
+  fn match_bracket_class(&self, c: u8, mut p: usize, ec: usize) -> bool {
+    let mut sig = true;
+    if self.pat[p + 1] == b'^' {
+      sig = false;
+      p += 1;
+    }
+    p += 1;
+    while p < ec {
+      if self.pat[p] == ESCAPE {
+        p += 1;
+        if match_class(c, self.pat[p]) {
+          return sig;
+        }
+      } else if self.pat[p + 1] == b'-' && p + 2 < ec {
+        if self.pat[p] <= c && c <= self.pat[p + 2] {
+          return sig;
+        }
+        p += 2;
+      } else if self.pat[p] == c {
+        return sig;
+      }
+      p += 1;
+    }
+    !sig
+  }
+
+  fn single_match(&self, s: usize, p: usize, ep: usize) -> bool {
+    let Some(&c) = self.src.get(s) else { return false };
+    match self.pat[p] {
+      b'.' => true,
+      ESCAPE => match_class(c, self.pat[p + 1]),
+      b'[' => self.match_bracket_class(c, p, ep - 1),
+      pc => pc == c
+    }
+  }
+
+  fn do_match(&mut self, s: usize, p: usize) -> Option<usize> {
+    self.depth += 1;
+    if self.depth > MAX_DEPTH {
+      return None;
+    }
+    let result = self.do_match_inner(s, p);
+    self.depth -= 1;
+    result
+  }
+
+  fn do_match_inner(&mut self, mut s: usize, mut p: usize) -> Option<usize> {
+    loop {
+      let Some(&pc) = self.pat.get(p) else { return Some(s) };
+
+      match pc {
+        b'(' => {
+          return match self.pat.get(p + 1) {
+            Some(b')') => self.start_capture(s, p + 2, Capture::Position),
+            _ => self.start_capture(s, p + 1, Capture::Unfinished)
+          };
+        },
+        b')' => return self.end_capture(s, p + 1),
+        b'$' if p + 1 == self.pat.len() => {
+          return if s == self.src.len() { Some(s) } else { None };
+        },
+        ESCAPE if self.pat.get(p + 1) == Some(&b'b') => {
+          s = self.match_balance(s, p + 2)?;
+          p += 4;
+          continue;
+        },
+        ESCAPE if self.pat.get(p + 1) == Some(&b'f') => {
+          p += 2;
+          if self.pat.get(p) != Some(&b'[') {
+            return None;
+          }
+          let ep = self.class_end(p)?;
+          let prev = if s == 0 { 0 } else { self.src[s - 1] };
+          let cur = self.src.get(s).copied().unwrap_or(0);
+          if !self.match_bracket_class(prev, p, ep - 1) && self.match_bracket_class(cur, p, ep - 1) {
+            p = ep;
+            continue;
+          }
+          return None;
+        },
+        ESCAPE if self.pat.get(p + 1).is_some_and(u8::is_ascii_digit) => {
+          s = self.match_capture(s, self.pat[p + 1])?;
+          p += 2;
+          continue;
+        },
+        _ => ()
+      }
+
+      let ep = self.class_end(p)?;
+      let matched = self.single_match(s, p, ep);
+
+      match self.pat.get(ep) {
+        Some(b'?') => {
+          if matched && let Some(res) = self.do_match(s + 1, ep + 1) {
+            return Some(res);
+          }
+          p = ep + 1;
+        },
+        Some(b'+') => return if matched { self.max_expand(s + 1, p, ep) } else { None },
+        Some(b'*') => return self.max_expand(s, p, ep),
+        Some(b'-') => return self.min_expand(s, p, ep),
+        _ => {
+          if !matched {
+            return None;
+          }
+          s += 1;
+          p = ep;
+        }
+      }
+    }
+  }
+
+  fn max_expand(&mut self, s: usize, p: usize, ep: usize) -> Option<usize> {
+    let mut i = 0;
+    while self.single_match(s + i, p, ep) {
+      i += 1;
+    }
+    loop {
+      if let Some(res) = self.do_match(s + i, ep + 1) {
+        return Some(res);
+      }
+      if i == 0 {
+        return None;
+      }
+      i -= 1;
+    }
+  }
+
+  fn min_expand(&mut self, mut s: usize, p: usize, ep: usize) -> Option<usize> {
+    loop {
+      if let Some(res) = self.do_match(s, ep + 1) {
+        return Some(res);
+      }
+      if !self.single_match(s, p, ep) {
+        return None;
+      }
+      s += 1;
+    }
+  }
+
+  fn match_balance(&self, s: usize, p: usize) -> Option<usize> {
+    let (&open, &close) = (self.pat.get(p)?, self.pat.get(p + 1)?);
+    if self.src.get(s) != Some(&open) {
+      return None;
+    }
+    let mut depth = 1;
+    for (i, &c) in self.src.iter().enumerate().skip(s + 1) {
+      if c == close {
+        depth -= 1;
+        if depth == 0 {
+          return Some(i + 1);
+        }
+      } else if c == open {
+        depth += 1;
+      }
+    }
+    None
+  }
+
+  fn start_capture(&mut self, s: usize, p: usize, capture: Capture) -> Option<usize> {
+    self.captures.push((s, capture));
+    let res = self.do_match(s, p);
+    if res.is_none() {
+      self.captures.pop();
+    }
+    res
+  }
+
+  fn end_capture(&mut self, s: usize, p: usize) -> Option<usize> {
+    let l = self.captures.iter().rposition(|(_, c)| matches!(c, Capture::Unfinished))?;
+    self.captures[l].1 = Capture::Len(s - self.captures[l].0);
+    let res = self.do_match(s, p);
+    if res.is_none() {
+      self.captures[l].1 = Capture::Unfinished;
+    }
+    res
+  }
+
+  fn match_capture(&self, s: usize, digit: u8) -> Option<usize> {
+    let index = digit.checked_sub(b'1')?;
+    let (start, capture) = *self.captures.get(index as usize)?;
+    let len = match capture {
+      Capture::Len(len) => len,
+      _ => return None
+    };
+    let captured = &self.src[start..start + len];
+    if self.src.get(s..s + len) == Some(captured) {
+      Some(s + len)
+    } else {
+      None
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::find;
+
+  // Each expected result is what `string.find(subject, pattern) ~= nil` gives in Lua 5.1, with patterns Lua raises
+  // an error for expected not to match.
+  const CASES: &[(&str, &str, bool)] = &[
+    ("hello", "", true),
+    ("hello", "^", true),
+    ("hello", "^h", true),
+    ("hello", "^e", false),
+    ("hello", "l+", true),
+    ("hello", "^%a+$", true),
+    ("hello1", "^%a+$", false),
+    ("a-b", "^[%w%-]+$", true),
+    ("a_b", "^[%w%-]+$", false),
+    ("a c", "^[^%s]+$", false),
+    ("abc", "^[^%s]+$", true),
+    ("a]", "^[]a]+$", true),
+    ("", "^$", true),
+    ("x", "^$", false),
+    ("a$b", "^a$b$", true),
+    ("x.y", "^x%.y$", true),
+    ("xzy", "^x%.y$", false),
+    ("xzy", "^x.y$", true),
+    ("2024-01-31", "^%d%d%d%d%-%d%d%-%d%d$", true),
+    ("2024-1-31", "^%d%d%d%d%-%d%d%-%d%d$", false),
+    ("ab", "^a?b$", true),
+    ("b", "^a?b$", true),
+    ("aab", "^a?b$", false),
+    ("aaa", "^a-$", true),
+    ("aaa", "^a-b", false),
+    ("aaab", "^a*b$", true),
+    ("THE (quick) fox", "%((%a+)%)", true),
+    ("f(a(b)c)", "%b()", true),
+    ("f(a(b c", "%b()", false),
+    ("THE", "%f[%a]%a+", true),
+    ("abab", "^(ab)%1$", true),
+    ("abac", "^(ab)%1$", false),
+    ("ab", "^()a()b$", true),
+    ("x", "((((((((((((((((((((((((((((((((x))))))))))))))))))))))))))))))))", true),
+    // Malformed.
+    ("x", "(((((((((((((((((((((((((((((((((x)))))))))))))))))))))))))))))))))", false),
+    ("abc", "(abc", false),
+    ("abc", "abc)", false),
+    ("abc", "%", false),
+    ("abc", "[a", false),
+    ("aa", "(a)%0", false),
+    ("aa", "%1", false),
+    ("aa", "(a%1)", false),
+    ("a", "%f", false),
+    ("a", "%fa", false),
+    ("a", "%b(", false),
+  ];
+
+  #[test]
+  fn matches_like_lua() {
+    for (subject, pattern, expected) in CASES {
+      assert_eq!(find(subject, pattern), *expected, "string.find({:?}, {:?})", subject, pattern);
+    }
+  }
+}
@@ -0,0 +1,350 @@
+use std::{collections::{BTreeMap, BTreeSet, HashMap, HashSet}, fmt::Display, hash::Hash};
+
+use crate::{KongError, KongResult};
+
+mod json_schema;
+mod lua;
+mod lua_pattern;
+pub mod types;
+pub mod validate;
+
+pub use json_schema::json_schema;
+pub use lua::lua_schema;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ConfigError {
+  pub path: String,
+  pub message: String
+}
+
+impl ConfigError {
+  pub fn new<P: Into<String>, M: Into<String>>(path: P, message: M) -> Self {
+    Self { path: path.into(), message: message.into() }
+  }
+}
+
+impl Display for ConfigError {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    match self.path.as_str() {
+      "" => write!(f, "{}", self.message),
+      path => write!(f, "{}: {}", path, self.message)
+    }
+  }
+}
+
+// The inclusive [min, max] of a `between` validator.
+pub type Between = [serde_json::Value; 2];
+
+#[derive(Debug, Clone, Default, serde::Serialize)]
+pub struct RenderedConfigFieldVariant {
+  #[serde(rename = "type")]
+  pub ty: String,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub required: Option<bool>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub default: Option<serde_json::Value>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub description: Option<String>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub one_of: Option<Vec<serde_json::Value>>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub between: Option<Between>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub gt: Option<serde_json::Value>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub len_min: Option<usize>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub len_max: Option<usize>,
+  #[serde(rename = "match", skip_serializing_if = "Option::is_none")]
+  pub r#match: Option<String>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub not_match: Option<String>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub eq: Option<serde_json::Value>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub reference: Option<String>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub referenceable: Option<bool>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub encrypted: Option<bool>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub elements: Option<Box<Self>>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub keys: Option<Box<Self>>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub values: Option<Box<Self>>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub fields: Option<Vec<HashMap<String, Self>>>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub entity_checks: Option<Vec<EntityCheck>>,
+  // A #[kong(default = ...)] that disagrees with the default the field was rendered with. Reported by check_defaults.
+  #[serde(skip)]
+  pub default_conflict: Option<serde_json::Value>
+}
+
+// Cross-field checks on a record. Fields count as set when they are present and not null.
+#[derive(Debug, Clone, serde::Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum EntityCheck {
+  AtLeastOneOf(Vec<String>),
+  OnlyOneOf(Vec<String>),
+  MutuallyExclusive(Vec<String>),
+  MutuallyRequired(Vec<String>),
+  Conditional {
+    if_field: String,
+    if_match: FieldMatch,
+    then_field: String,
+    then_match: FieldMatch
+  }
+}
+
+#[derive(Debug, Clone, Default, serde::Serialize)]
+pub struct FieldMatch {
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub eq: Option<serde_json::Value>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub one_of: Option<Vec<serde_json::Value>>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub required: Option<bool>
+}
+
+pub trait PluginConfigFieldVariant : Sized + serde::Serialize {
+  fn ty() -> &'static str;
+  fn variants() -> Option<Vec<&'static str>> { None }
+  fn between() -> Option<Between> { None }
+  fn pattern() -> Option<&'static str> { None }
+  fn required() -> bool { true }
+
+  fn render_this(self) -> RenderedConfigFieldVariant {
+    Self::render(Some(self), true)
+  }
+
+  fn render(default: Option<Self>, skip_required: bool) -> RenderedConfigFieldVariant {
+    let mut required = Some(Self::required());
+    let mut default = default.map(|x| serde_json::to_value(x).unwrap());
+
+    if skip_required {
+      required = None;
+    }
+
+    if required == Some(false) {
+      default = None;
+    }
+
+    RenderedConfigFieldVariant {
+      ty: Self::ty().to_owned(),
+      required,
+      default,
+      one_of: Self::variants().map(|x| x.into_iter().map(to_value).collect()),
+      between: Self::between(),
+      r#match: Self::pattern().map(str::to_owned),
+      ..Default::default()
+    }
+  }
+}
+
+impl PluginConfigFieldVariant for String {
+  fn ty() -> &'static str { "string" }
+}
+
+impl PluginConfigFieldVariant for bool {
+  fn ty() -> &'static str { "boolean" }
+}
+
+// Every integer type is range checked to fit, so a config Kong accepts always deserializes. Bounds past 2^53 can't
+// be held exactly by Kong's Lua numbers, so they're enforced exactly only on our side.
+macro_rules! integer_field {
+  ($($ty:ty),*) => { $(
+    impl PluginConfigFieldVariant for $ty {
+      fn ty() -> &'static str { "integer" }
+      fn between() -> Option<Between> { Some([to_value(<$ty>::MIN), to_value(<$ty>::MAX)]) }
+    }
+  )* };
+}
+
+integer_field!(i8, i16, i32, i64, isize, u8, u16, u32, u64, usize);
+
+// Configs pass through serde_json, which only carries 64 bit integers, so the 128 bit types take the 64 bit range.
+impl PluginConfigFieldVariant for i128 {
+  fn ty() -> &'static str { "integer" }
+  fn between() -> Option<Between> { Some([to_value(i64::MIN), to_value(i64::MAX)]) }
+}
+
+impl PluginConfigFieldVariant for u128 {
+  fn ty() -> &'static str { "integer" }
+  fn between() -> Option<Between> { Some([to_value(0), to_value(u64::MAX)]) }
+}
+
+impl PluginConfigFieldVariant for f32 {
+  fn ty() -> &'static str { "number" }
+  fn between() -> Option<Between> { Some([to_value(f32::MIN), to_value(f32::MAX)]) }
+}
+
+impl PluginConfigFieldVariant for f64 {
+  fn ty() -> &'static str { "number" }
+}
+
+impl<T: PluginConfigFieldVariant> PluginConfigFieldVariant for Option<T> {
+  fn ty() -> &'static str { T::ty() }
+  fn required() -> bool { false }
+
+  fn render(default: Option<Self>, skip_required: bool) -> RenderedConfigFieldVariant {
+    let mut rendered = T::render(default.flatten(), skip_required);
+    if !skip_required {
+      rendered.required = Some(false);
+      rendered.default = None;
+    }
+    rendered
+  }
+}
+
+impl<T: PluginConfigFieldVariant> PluginConfigFieldVariant for Vec<T> {
+  fn ty() -> &'static str { "array" }
+
+  fn render(default: Option<Self>, _in_arr: bool) -> RenderedConfigFieldVariant {
+      RenderedConfigFieldVariant {
+        ty: Self::ty().to_owned(),
+        required: Some(true),
+        default: default.map(|x| serde_json::to_value(x).unwrap()),
+        elements: Some(Box::new(T::render(None, true))),
+        ..Default::default()
+      }
+  }
+}
+
+fn render_map<K: PluginConfigFieldVariant, V: PluginConfigFieldVariant, M: serde::Serialize>(default: Option<M>) -> RenderedConfigFieldVariant {
+  RenderedConfigFieldVariant {
+    ty: "map".to_owned(),
+    required: Some(true),
+    default: default.map(to_value),
+    keys: Some(Box::new(K::render(None, true))),
+    values: Some(Box::new(V::render(None, true))),
+    ..Default::default()
+  }
+}
+
+impl<K: PluginConfigFieldVariant + Eq + Hash, V: PluginConfigFieldVariant> PluginConfigFieldVariant for HashMap<K, V> {
+  fn ty() -> &'static str { "map" }
+
+  fn render(default: Option<Self>, _in_arr: bool) -> RenderedConfigFieldVariant {
+    render_map::<K, V, _>(default)
+  }
+}
+
+impl<K: PluginConfigFieldVariant + Ord, V: PluginConfigFieldVariant> PluginConfigFieldVariant for BTreeMap<K, V> {
+  fn ty() -> &'static str { "map" }
+
+  fn render(default: Option<Self>, _in_arr: bool) -> RenderedConfigFieldVariant {
+    render_map::<K, V, _>(default)
+  }
+}
+
+// Sets reject duplicate elements rather than letting a HashSet or BTreeSet collapse them.
+fn render_set<T: PluginConfigFieldVariant, S: serde::Serialize>(default: Option<S>) -> RenderedConfigFieldVariant {
+  RenderedConfigFieldVariant {
+    ty: "set".to_owned(),
+    required: Some(true),
+    default: default.map(to_value),
+    elements: Some(Box::new(T::render(None, true))),
+    ..Default::default()
+  }
+}
+
+impl<T: PluginConfigFieldVariant + Eq + Hash> PluginConfigFieldVariant for HashSet<T> {
+  fn ty() -> &'static str { "set" }
+
+  fn render(default: Option<Self>, _in_arr: bool) -> RenderedConfigFieldVariant {
+    render_set::<T, _>(default)
+  }
+}
+
+impl<T: PluginConfigFieldVariant + Ord> PluginConfigFieldVariant for BTreeSet<T> {
+  fn ty() -> &'static str { "set" }
+
+  fn render(default: Option<Self>, _in_arr: bool) -> RenderedConfigFieldVariant {
+    render_set::<T, _>(default)
+  }
+}
+
+pub trait PluginConfig : serde::de::DeserializeOwned + PluginConfigFieldVariant + Send {
+  // Checks beyond what the schema can express. Runs after deserialization, before the plugin is created.
+  fn validate(&self) -> Result<(), Vec<ConfigError>> { Ok(()) }
+}
+
+pub fn to_value<T: serde::Serialize>(value: T) -> serde_json::Value {
+  serde_json::to_value(value).unwrap()
+}
+
+// Applies #[kong(default = ...)]. A field rendered from a value, such as default_config(), already has a default, and
+// the two have to agree, or Kong would fill in a different value than the one default_config() holds. The derive
+// can't see inside Default impls, so a mismatch keeps the rendered default and is reported by check_defaults, which
+// PluginServerBroker::register runs.
+pub fn override_default(field: &mut RenderedConfigFieldVariant, _name: &str, value: serde_json::Value) {
+  match &field.default {
+    Some(rendered) if !same_value(rendered, &value) => field.default_conflict = Some(value),
+    _ => field.default = Some(value)
+  }
+}
+
+// Finds the #[kong(default = ...)]s that disagree with the default a schema was rendered with.
+pub fn check_defaults(fields: &[HashMap<String, RenderedConfigFieldVariant>]) -> Result<(), Vec<ConfigError>> {
+  fn check(field: &RenderedConfigFieldVariant, path: &str, errors: &mut Vec<ConfigError>) {
+    if let (Some(conflict), Some(rendered)) = (&field.default_conflict, &field.default) {
+      errors.push(ConfigError::new(path, format!("#[kong(default = {})] disagrees with the default of {} from the config's Default impl", conflict, rendered)));
+    }
+    for (name, field) in field.fields.iter().flatten().flatten() {
+      check(field, &format!("{}.{}", path, name), errors);
+    }
+    for (suffix, field) in [("[]", &field.elements), (".<key>", &field.keys), (".<value>", &field.values)] {
+      if let Some(field) = field {
+        check(field, &format!("{}{}", path, suffix), errors);
+      }
+    }
+  }
+
+  let mut errors = vec![];
+  for (name, field) in fields.iter().flatten() {
+    check(field, name, &mut errors);
+  }
+  match errors.is_empty() {
+    true => Ok(()),
+    false => Err(errors)
+  }
+}
+
+// Numbers compare by value, so `default = 1` agrees with 1.0 from an f64 field.
+fn same_value(a: &serde_json::Value, b: &serde_json::Value) -> bool {
+  use serde_json::Value;
+  match (a, b) {
+    (Value::Number(a), Value::Number(b)) => a.as_f64() == b.as_f64(),
+    (Value::Array(a), Value::Array(b)) => a.len() == b.len() && a.iter().zip(b).all(|(a, b)| same_value(a, b)),
+    (Value::Object(a), Value::Object(b)) => a.len() == b.len() && a.iter().all(|(k, v)| b.get(k).is_some_and(|x| same_value(v, x))),
+    (a, b) => a == b
+  }
+}
+
+// Kong validates configs against the schema before they reach us, but we check them again here so a plugin
+// never sees a config that its schema would reject (e.g. one written straight into a DB-less kong.yml).
+pub fn parse_config<C: PluginConfig>(config_data: &str, schema: &RenderedConfigFieldVariant) -> KongResult<C> {
+  let value = serde_json::from_str(config_data).map_err(|e| KongError::ConfigError(vec![ConfigError::new("", e.to_string())]))?;
+  parse_config_value(value, schema)
+}
+
+pub fn parse_config_value<C: PluginConfig>(mut value: serde_json::Value, schema: &RenderedConfigFieldVariant) -> KongResult<C> {
+  validate::apply_defaults(&mut value, schema);
+
+  let errors = validate::validate(&value, schema);
+  if !errors.is_empty() {
+    return Err(KongError::ConfigError(errors));
+  }
+
+  let config: C = serde_path_to_error::deserialize(value).map_err(|e| {
+    let path = match e.path().to_string() {
+      path if path == "." => String::new(),
+      path => path
+    };
+    KongError::ConfigError(vec![ConfigError::new(path, e.into_inner().to_string())])
+  })?;
+  config.validate().map_err(KongError::ConfigError)?;
+  Ok(config)
+}
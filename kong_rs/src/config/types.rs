@@ -0,0 +1,167 @@
+use std::{fmt::Display, net::IpAddr, ops::Deref, str::FromStr};
+
+use crate::KongError;
+
+use super::{to_value, Between, PluginConfigFieldVariant};
+
+// Counterparts to the typedefs Kong's own plugins use. Their custom validators can't be sent over the plugin
+// socket, so the schema carries what it can express and the rest is checked when the config is deserialized.
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash, serde::Serialize, serde::Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct Url(String);
+
+impl Url {
+  pub fn as_str(&self) -> &str { &self.0 }
+
+  pub fn uri(&self) -> http::Uri {
+    self.0.parse().unwrap()
+  }
+}
+
+impl TryFrom<String> for Url {
+  type Error = KongError;
+
+  fn try_from(value: String) -> Result<Self, Self::Error> {
+    match value.parse::<http::Uri>() {
+      Ok(uri) if uri.scheme().is_some() && uri.host().is_some() => Ok(Self(value)),
+      _ => Err(KongError::InvalidValueError(format!("'{}' is not a URL with a scheme and host", value)))
+    }
+  }
+}
+
+impl PluginConfigFieldVariant for Url {
+  fn ty() -> &'static str { "string" }
+  fn pattern() -> Option<&'static str> { Some("^%a[%w+%.%-]*://[^/?#]") }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord, serde::Serialize, serde::Deserialize)]
+#[serde(transparent)]
+pub struct Port(pub u16);
+
+impl PluginConfigFieldVariant for Port {
+  fn ty() -> &'static str { "integer" }
+  fn between() -> Option<Between> { Some([to_value(0), to_value(65535)]) }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord, serde::Serialize, serde::Deserialize)]
+#[serde(transparent)]
+pub struct Ip(pub IpAddr);
+
+impl PluginConfigFieldVariant for Ip {
+  fn ty() -> &'static str { "string" }
+  fn pattern() -> Option<&'static str> { Some("^[%x:%.]+$") }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, serde::Serialize, serde::Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct Cidr {
+  pub addr: IpAddr,
+  pub prefix: u8
+}
+
+impl Cidr {
+  pub fn contains(&self, ip: &IpAddr) -> bool {
+    match (self.addr, ip) {
+      (IpAddr::V4(net), IpAddr::V4(ip)) => {
+        let mask = u32::MAX.checked_shl(32 - self.prefix as u32).unwrap_or(0);
+        u32::from(net) & mask == u32::from(*ip) & mask
+      },
+      (IpAddr::V6(net), IpAddr::V6(ip)) => {
+        let mask = u128::MAX.checked_shl(128 - self.prefix as u32).unwrap_or(0);
+        u128::from(net) & mask == u128::from(*ip) & mask
+      },
+      _ => false
+    }
+  }
+}
+
+impl FromStr for Cidr {
+  type Err = KongError;
+
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    let invalid = || KongError::InvalidValueError(format!("'{}' is not a CIDR range", s));
+
+    let (addr, prefix) = s.split_once('/').ok_or_else(invalid)?;
+    let addr: IpAddr = addr.parse().map_err(|_| invalid())?;
+    let prefix: u8 = prefix.parse().map_err(|_| invalid())?;
+
+    match (addr, prefix) {
+      (IpAddr::V4(_), 0..=32) | (IpAddr::V6(_), 0..=128) => Ok(Self { addr, prefix }),
+      _ => Err(invalid())
+    }
+  }
+}
+
+impl TryFrom<String> for Cidr {
+  type Error = KongError;
+
+  fn try_from(value: String) -> Result<Self, Self::Error> {
+    value.parse()
+  }
+}
+
+impl Display for Cidr {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    write!(f, "{}/{}", self.addr, self.prefix)
+  }
+}
+
+impl From<Cidr> for String {
+  fn from(value: Cidr) -> Self {
+    value.to_string()
+  }
+}
+
+impl PluginConfigFieldVariant for Cidr {
+  fn ty() -> &'static str { "string" }
+  fn pattern() -> Option<&'static str> { Some("^[%x:%.]+/%d+$") }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash, serde::Serialize, serde::Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct HeaderName(String);
+
+impl HeaderName {
+  pub fn as_str(&self) -> &str { &self.0 }
+}
+
+impl TryFrom<String> for HeaderName {
+  type Error = KongError;
+
+  fn try_from(value: String) -> Result<Self, Self::Error> {
+    match !value.is_empty() && value.bytes().all(|c| c.is_ascii_alphanumeric() || c == b'-' || c == b'_') {
+      true => Ok(Self(value)),
+      false => Err(KongError::InvalidValueError(format!("'{}' is not a valid header name", value)))
+    }
+  }
+}
+
+impl PluginConfigFieldVariant for HeaderName {
+  fn ty() -> &'static str { "string" }
+  fn pattern() -> Option<&'static str> { Some("^[%w%-_]+$") }
+}
+
+macro_rules! string_newtype {
+  ($($ty:ty),*) => { $(
+    impl Deref for $ty {
+      type Target = str;
+      fn deref(&self) -> &str { &self.0 }
+    }
+
+    impl Display for $ty {
+      fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result { f.write_str(&self.0) }
+    }
+
+    impl From<$ty> for String {
+      fn from(value: $ty) -> Self { value.0 }
+    }
+
+    impl FromStr for $ty {
+      type Err = KongError;
+      fn from_str(s: &str) -> Result<Self, Self::Err> { s.to_owned().try_into() }
+    }
+  )* };
+}
+
+string_newtype!(Url, HeaderName);
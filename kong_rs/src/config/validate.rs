@@ -0,0 +1,246 @@
+use serde_json::Value;
+
+use super::{lua_pattern, ConfigError, EntityCheck, FieldMatch, RenderedConfigFieldVariant};
+
+// Mirrors the checks Kong's schema library runs for the validators we can render, with the same messages.
+
+pub fn apply_defaults(value: &mut Value, schema: &RenderedConfigFieldVariant) {
+  match (value, schema.ty.as_str()) {
+    (value @ Value::Null, "record") if schema.required != Some(false) => {
+      *value = Value::Object(Default::default());
+      apply_defaults(value, schema);
+    },
+    (Value::Object(obj), "record") => {
+      for (name, field) in schema.fields.iter().flatten().flatten() {
+        let entry = obj.entry(name.clone()).or_insert(Value::Null);
+        if entry.is_null() && let Some(default) = &field.default {
+          *entry = default.clone();
+        }
+        apply_defaults(entry, field);
+        if entry.is_null() {
+          obj.remove(name);
+        }
+      }
+    },
+    (Value::Object(obj), "map") => {
+      if let Some(values) = &schema.values {
+        obj.values_mut().for_each(|x| apply_defaults(x, values));
+      }
+    },
+    (Value::Array(arr), _) => {
+      if let Some(elements) = &schema.elements {
+        arr.iter_mut().for_each(|x| apply_defaults(x, elements));
+      }
+    },
+    _ => ()
+  }
+}
+
+pub fn validate(value: &Value, schema: &RenderedConfigFieldVariant) -> Vec<ConfigError> {
+  let mut errors = vec![];
+  validate_field(value, schema, "", &mut errors);
+  errors
+}
+
+fn join(path: &str, name: &str) -> String {
+  match path {
+    "" => name.to_owned(),
+    path => format!("{}.{}", path, name)
+  }
+}
+
+fn validate_field(value: &Value, schema: &RenderedConfigFieldVariant, path: &str, errors: &mut Vec<ConfigError>) {
+  let mut error = |message: String| errors.push(ConfigError::new(path, message));
+
+  if value.is_null() {
+    if schema.required == Some(true) {
+      error("required field missing".to_owned());
+    }
+    return;
+  }
+
+  let type_ok = match schema.ty.as_str() {
+    "string" => value.is_string(),
+    "boolean" => value.is_boolean(),
+    "integer" => value.is_i64() || value.is_u64(),
+    "number" => value.is_number(),
+    "array" | "set" => value.is_array(),
+    "record" | "map" => value.is_object(),
+    _ => true
+  };
+
+  if !type_ok {
+    let article = if schema.ty.starts_with(['a', 'e', 'i', 'o', 'u']) { "an" } else { "a" };
+    error(format!("expected {} {}", article, schema.ty));
+    return;
+  }
+
+  if let Some(one_of) = &schema.one_of && !one_of.contains(value) {
+    let options = one_of.iter().map(display).collect::<Vec<_>>().join(", ");
+    error(format!("expected one of: {}", options));
+  }
+
+  if let Some([min, max]) = &schema.between && let (Some(v), Some(lo), Some(hi)) = (value.as_f64(), min.as_f64(), max.as_f64()) && (v < lo || v > hi) {
+    error(format!("value should be between {} and {}", display(min), display(max)));
+  }
+
+  if let Some(gt) = &schema.gt && let (Some(v), Some(gt_v)) = (value.as_f64(), gt.as_f64()) && v <= gt_v {
+    error(format!("value must be greater than {}", display(gt)));
+  }
+
+  let len = match value {
+    Value::String(s) => Some(s.chars().count()),
+    Value::Array(arr) => Some(arr.len()),
+    _ => None
+  };
+
+  if let Some(len) = len {
+    if let Some(min) = schema.len_min && len < min {
+      error(format!("length must be at least {}", min));
+    }
+    if let Some(max) = schema.len_max && len > max {
+      error(format!("length must be at most {}", max));
+    }
+  }
+
+  if let Value::String(s) = value {
+    if let Some(pattern) = &schema.r#match && !lua_pattern::find(s, pattern) {
+      error(format!("invalid value: {}", s));
+    }
+    if let Some(pattern) = &schema.not_match && lua_pattern::find(s, pattern) {
+      error(format!("invalid value: {}", s));
+    }
+  }
+
+  match value {
+    Value::Array(arr) => {
+      for (i, x) in arr.iter().enumerate() {
+        let elem_path = format!("{}[{}]", path, i);
+        if schema.ty == "set" && arr[..i].contains(x) {
+          errors.push(ConfigError::new(&elem_path, format!("duplicate set element: {}", display(x))));
+        }
+        if let Some(elements) = &schema.elements {
+          validate_field(x, elements, &elem_path, errors);
+        }
+      }
+    },
+    Value::Object(obj) if schema.ty == "map" => {
+      for (key, value) in obj {
+        let key_path = join(path, key);
+        if let Some(keys) = &schema.keys && keys.ty == "string" {
+          validate_field(&Value::String(key.clone()), keys, &key_path, errors);
+        }
+        if let Some(values) = &schema.values {
+          validate_field(value, values, &key_path, errors);
+        }
+      }
+    },
+    Value::Object(obj) if schema.ty == "record" => {
+      let Some(fields) = &schema.fields else { return };
+
+      for (name, field) in fields.iter().flatten() {
+        validate_field(obj.get(name).unwrap_or(&Value::Null), field, &join(path, name), errors);
+      }
+
+      for name in obj.keys() {
+        if !fields.iter().any(|x| x.contains_key(name)) {
+          errors.push(ConfigError::new(join(path, name), "unknown field"));
+        }
+      }
+
+      for check in schema.entity_checks.iter().flatten() {
+        validate_entity_check(obj, check, path, errors);
+      }
+    },
+    _ => ()
+  }
+}
+
+fn validate_entity_check(obj: &serde_json::Map<String, Value>, check: &EntityCheck, path: &str, errors: &mut Vec<ConfigError>) {
+  let is_set = |name: &String| obj.get(name).is_some_and(|x| !x.is_null());
+  let list = |names: &[String]| names.iter().map(|x| format!("'{}'", x)).collect::<Vec<_>>().join(", ");
+
+  let message = match check {
+    EntityCheck::AtLeastOneOf(names) if !names.iter().any(is_set) =>
+      format!("at least one of these fields must be non-empty: {}", list(names)),
+    EntityCheck::OnlyOneOf(names) if names.iter().filter(|x| is_set(x)).count() != 1 =>
+      format!("exactly one of these fields must be non-empty: {}", list(names)),
+    EntityCheck::MutuallyExclusive(names) if names.iter().filter(|x| is_set(x)).count() > 1 =>
+      format!("only one or none of these fields must be set: {}", list(names)),
+    EntityCheck::MutuallyRequired(names) if names.iter().any(is_set) && !names.iter().all(is_set) =>
+      format!("all or none of these fields must be set: {}", list(names)),
+    EntityCheck::Conditional { if_field, if_match, then_field, then_match } => {
+      let null = Value::Null;
+      let if_value = obj.get(if_field).unwrap_or(&null);
+      let then_value = obj.get(then_field).unwrap_or(&null);
+
+      if if_value.is_null() || !field_matches(if_value, if_match) || field_matches(then_value, then_match) {
+        return;
+      }
+      errors.push(ConfigError::new(join(path, then_field), format!("failed conditional validation given value of field '{}'", if_field)));
+      return;
+    },
+    _ => return
+  };
+
+  errors.push(ConfigError::new(path, message));
+}
+
+fn field_matches(value: &Value, matcher: &FieldMatch) -> bool {
+  if value.is_null() {
+    return matcher.required != Some(true);
+  }
+  if let Some(eq) = &matcher.eq && eq != value {
+    return false;
+  }
+  if let Some(one_of) = &matcher.one_of && !one_of.contains(value) {
+    return false;
+  }
+  true
+}
+
+fn display(value: &Value) -> String {
+  match value {
+    Value::String(s) => s.clone(),
+    value => value.to_string()
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use std::collections::{BTreeSet, HashSet};
+
+  use serde_json::json;
+
+  use crate::config::{types::Ip, PluginConfigFieldVariant};
+
+  use super::validate;
+
+  fn messages<T: PluginConfigFieldVariant>(value: serde_json::Value) -> Vec<String> {
+    validate(&value, &T::render(None, true)).into_iter().map(|x| x.to_string()).collect()
+  }
+
+  #[test]
+  fn sets_reject_duplicates() {
+    assert_eq!(messages::<HashSet<String>>(json!(["a", "b", "a"])), vec!["[2]: duplicate set element: a"]);
+    assert_eq!(messages::<BTreeSet<u8>>(json!([1, 1])), vec!["[1]: duplicate set element: 1"]);
+    assert!(messages::<HashSet<String>>(json!(["a", "b"])).is_empty());
+    assert!(messages::<Vec<String>>(json!(["a", "a"])).is_empty());
+  }
+
+  #[test]
+  fn integers_are_range_checked() {
+    for ty in [i64::render(None, true), isize::render(None, true), u64::render(None, true), i128::render(None, true), u128::render(None, true)] {
+      assert!(ty.between.is_some(), "{} has no between", ty.ty);
+    }
+    assert_eq!(messages::<u64>(json!(-1)), vec![format!("value should be between 0 and {}", u64::MAX)]);
+    assert!(messages::<i128>(json!(i64::MIN)).is_empty());
+  }
+
+  #[test]
+  fn ips_are_matched() {
+    assert!(messages::<Ip>(json!("10.0.0.1")).is_empty());
+    assert!(messages::<Ip>(json!("::1")).is_empty());
+    assert_eq!(messages::<Ip>(json!("localhost")), vec!["invalid value: localhost"]);
+  }
+}
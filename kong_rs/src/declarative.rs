@@ -0,0 +1,53 @@
+use serde_json::Value;
+
+use crate::{config::ConfigError, KongError, KongResult};
+
+// Plugins can be configured globally or on any of these entities, which may also nest each other (e.g. routes
+// listed under a service).
+const ENTITIES: &[&str] = &["services", "routes", "consumers", "consumer_groups"];
+
+#[derive(Debug, Clone)]
+pub struct PluginEntry {
+  // Where the entry's config sits in the document, e.g. services[0].plugins[1].config
+  pub path: String,
+  pub name: String,
+  pub config: Value
+}
+
+// Reads a declarative config. YAML is a superset of JSON, so either format is accepted.
+pub fn parse(source: &str) -> KongResult<Value> {
+  serde_norway::from_str(source).map_err(|e| KongError::ConfigError(vec![ConfigError::new("", e.to_string())]))
+}
+
+pub fn plugin_entries(document: &Value) -> Vec<PluginEntry> {
+  let mut entries = vec![];
+  collect(document, "", &mut entries);
+  entries
+}
+
+fn collect(entity: &Value, path: &str, entries: &mut Vec<PluginEntry>) {
+  let prefix = match path {
+    "" => String::new(),
+    path => format!("{}.", path)
+  };
+
+  if let Some(plugins) = entity.get("plugins").and_then(Value::as_array) {
+    for (i, plugin) in plugins.iter().enumerate() {
+      let Some(name) = plugin.get("name").and_then(Value::as_str) else { continue };
+      // Kong fills in every default when the config is left out entirely.
+      let config = match plugin.get("config") {
+        None | Some(Value::Null) => Value::Object(Default::default()),
+        Some(config) => config.clone()
+      };
+      entries.push(PluginEntry { path: format!("{}plugins[{}].config", prefix, i), name: name.to_owned(), config });
+    }
+  }
+
+  for key in ENTITIES {
+    if let Some(children) = entity.get(key).and_then(Value::as_array) {
+      for (i, child) in children.iter().enumerate() {
+        collect(child, &format!("{}{}[{}]", prefix, key, i), entries);
+      }
+    }
+  }
+}
@@ -1,15 +1,22 @@
+pub mod cli;
 pub mod config;
+pub mod declarative;
+mod multiplex;
 pub mod pdk;
 pub mod plugin;
 pub mod server;
 pub mod stream;
+#[cfg(feature = "testing")]
+pub mod testing;
+pub mod trace;
+pub mod transport;
 
 use http::{Response, StatusCode};
 pub use kong_rs_macros::PluginConfig;
 
-pub use pdk::Pdk;
-pub use plugin::{Phase, Plugin, PluginFactory, PluginResult};
-pub use server::PluginServerBroker;
+pub use pdk::{Pdk, StreamPdk};
+pub use plugin::{ConfigFactory, FromConfig, Phase, Plugin, PluginFactory, PluginResult, Protocol, Scopes, TypedPluginFactory};
+pub use server::{LogLevel, PluginServerBroker, WireMode};
 
 #[derive(Debug)]
 pub enum KongError {
@@ -20,9 +27,41 @@ pub enum KongError {
   SerdeError(serde_json::Error),
   EncodingError(std::str::Utf8Error),
   InvalidValueError(String),
-  BodyError(String)
+  BodyError(String),
+  ConnectionClosed,
+  FrameError(stream::FrameError),
+  PanicError(String),
+  ConfigError(Vec<config::ConfigError>)
 }
 
+impl std::fmt::Display for KongError {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    match self {
+      KongError::IOError(e) => write!(f, "I/O error: {}", e),
+      KongError::ProtobufDecodeError(e) => write!(f, "Protobuf decode error: {}", e),
+      KongError::HeaderParseError(e) => write!(f, "Header parse error: {}", e),
+      KongError::LaunchError(e) => write!(f, "Launch error: {}", e),
+      KongError::SerdeError(e) => write!(f, "Serialization error: {}", e),
+      KongError::EncodingError(e) => write!(f, "Encoding error: {}", e),
+      KongError::InvalidValueError(e) => write!(f, "Invalid value: {}", e),
+      KongError::BodyError(e) => write!(f, "Body error: {}", e),
+      KongError::ConnectionClosed => write!(f, "Connection closed"),
+      KongError::FrameError(e) => write!(f, "Frame error: {}", e),
+      KongError::PanicError(e) => write!(f, "Plugin panicked: {}", e),
+      KongError::ConfigError(errors) => {
+        write!(f, "Invalid config: ")?;
+        for (i, e) in errors.iter().enumerate() {
+          if i > 0 { write!(f, "; ")?; }
+          write!(f, "{}", e)?;
+        }
+        Ok(())
+      },
+    }
+  }
+}
+
+impl std::error::Error for KongError { }
+
 impl From<std::io::Error> for KongError {
   fn from(value: std::io::Error) -> Self {
     Self::IOError(value)
@@ -47,6 +86,12 @@ impl From<serde_json::Error> for KongError {
   }
 }
 
+impl From<stream::FrameError> for KongError {
+  fn from(value: stream::FrameError) -> Self {
+    Self::FrameError(value)
+  }
+}
+
 impl From<std::str::Utf8Error> for KongError {
   fn from(value: std::str::Utf8Error) -> Self {
     Self::EncodingError(value)
@@ -63,6 +108,7 @@ impl KongError {
   }
 }
 
+#[allow(clippy::result_large_err)]
 pub fn ok_or_internal_error<T>(result: KongResult<T>) -> std::result::Result<T, Response<Vec<u8>>> {
   match result {
     Ok(ok) => Ok(ok),
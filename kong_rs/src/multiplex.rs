@@ -0,0 +1,643 @@
+use std::{collections::HashMap, sync::{atomic::{AtomicI64, Ordering}, Arc, Mutex}};
+
+use kong_rs_protos::{
+  pdk_arg::Data, raw_body_result, AuthenticateArgs, ByteString, ConsumerSpec, ExitArgs, Kv, PdkArg, PdkCall, PdkReturn,
+  RawBodyResult, StringMap, Target
+};
+use prost::Message;
+use prost_types::{value::Kind, ListValue, Struct, Value};
+use tokio::sync::oneshot;
+
+use crate::{pdk::{client, ctx, log, ngx, request, response, router, service}, stream::Stream, transport::Transport, KongError, KongResult};
+
+// The multiplexed wire mode, an experimental extension of kong_rs's own. Stock Kong only speaks the lockstep mode and
+// can't drive this one: the message types are in the protocol, but Kong has no encoding of PDK arguments as PdkArgs,
+// so the layouts below are kong_rs's, and only a peer built against them (such as testing::KongClient) can talk to
+// a server in this mode.
+//
+// PDK calls are sent as PdkCalls tagged with the event they belong to and answered with PdkReturns, so several
+// events can be in flight on one connection. An event's id is the sequence of the CmdHandleEvent that started it.
+// RpcCall and RpcReturn never set field 2, so a frame with a non-zero event_id is always a PDK message, and replies
+// always carry their sequence.
+//
+// PdkArg only has a handful of types, so each method's arguments and reply are laid out as PdkArgs according to
+// their shape, as listed on Args and Reply. Three encodings of kong_rs's own stand in for the types PdkArg doesn't
+// have:
+//
+//   value  Dynamic values (kong.ctx, kong.log) are `s` holding the value's JSON text, or nil for null.
+//   map    Headers, query args and other tables are `m`, a StringMap whose values are the JSON text of each
+//          entry, so repeated headers stay separate values and nested or null entries survive.
+//   bytes  Bodies are `s` holding the bytes hex-encoded, as protobuf strings have to be UTF-8.
+//
+// Every layout round-trips exactly. The one reply with no encoding is a request body Kong buffered to a file, which
+// fails the call, as does a value JSON can't hold (NaN or an infinity).
+
+#[derive(Debug, Clone, Copy)]
+enum Args {
+  // No args.
+  None,
+  // [s]
+  String,
+  // [i]
+  Int,
+  // [b]
+  Bool,
+  // [s key, value]
+  Kv,
+  // One value per arg.
+  List,
+  // [map]
+  Map,
+  // [bytes]
+  Bytes,
+  // [i status, bytes body, map headers], with the headers left off when there are none.
+  Exit,
+  // [s host, i port]
+  Target,
+  // [s id, b by_username]
+  ConsumerSpec,
+  // [consumer, credential], either of which may be nil.
+  Authenticate
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Reply {
+  // No arg.
+  None,
+  // s
+  String,
+  // i
+  Int,
+  // b
+  Bool,
+  // f
+  Number,
+  // value
+  Value,
+  // map
+  Map,
+  // bytes
+  Bytes,
+  // bytes, an error when Kong couldn't read the body, or nil when there is none.
+  RawBody,
+  // The entity itself, or nil when there is none.
+  Route,
+  Service,
+  Consumer,
+  Credential
+}
+
+fn shape(method: &str) -> KongResult<(Args, Reply)> {
+  let shape = if let Ok(m) = method.parse::<client::Methods>() {
+    use client::Methods::*;
+    match m {
+      GetIp | GetForwardedIp => (Args::None, Reply::String),
+      GetPort | GetForwardedPort => (Args::None, Reply::Int),
+      GetCredential => (Args::None, Reply::Credential),
+      LoadConsumer => (Args::ConsumerSpec, Reply::Consumer),
+      GetConsumer => (Args::None, Reply::Consumer),
+      Authenticate => (Args::Authenticate, Reply::None),
+      GetProtocol => (Args::Bool, Reply::String),
+    }
+  } else if let Ok(m) = method.parse::<ctx::Methods>() {
+    use ctx::Methods::*;
+    match m {
+      SharedSet | Set => (Args::Kv, Reply::None),
+      SharedGet | Get => (Args::String, Reply::Value),
+    }
+  } else if let Ok(m) = method.parse::<log::Methods>() {
+    use log::Methods::*;
+    match m {
+      Alert | Crit | Error | Warn | Notice | Info | Debug => (Args::List, Reply::None),
+      Serialize => (Args::None, Reply::String),
+      SetSerializeValue => (Args::Kv, Reply::None),
+    }
+  } else if let Ok(m) = method.parse::<ngx::Methods>() {
+    use ngx::Methods::*;
+    match m {
+      GetVar => (Args::String, Reply::String),
+      GetTls1VersionStr | GetSubsystem => (Args::None, Reply::String),
+      ReqStartTime => (Args::None, Reply::Number),
+    }
+  } else if let Ok(m) = method.parse::<request::Methods>() {
+    use request::Methods::*;
+    match m {
+      GetScheme | GetHost | GetForwardedScheme | GetForwardedHost | GetMethod | GetPath | GetPathWithQuery | GetRawQuery => (Args::None, Reply::String),
+      GetPort | GetForwardedPort => (Args::None, Reply::Int),
+      GetHttpVersion => (Args::None, Reply::Number),
+      GetQueryArg | GetHeader => (Args::String, Reply::String),
+      GetQuery | GetHeaders => (Args::Int, Reply::Map),
+      GetRawBody => (Args::None, Reply::RawBody),
+    }
+  } else if let Ok(m) = method.parse::<response::Methods>() {
+    use response::Methods::*;
+    match m {
+      GetStatus => (Args::None, Reply::Int),
+      GetHeader => (Args::String, Reply::String),
+      GetHeaders => (Args::Int, Reply::Map),
+      GetSource => (Args::None, Reply::String),
+      SetStatus => (Args::Int, Reply::None),
+      SetHeader | AddHeader => (Args::Kv, Reply::None),
+      ClearHeader => (Args::String, Reply::None),
+      SetHeaders => (Args::Map, Reply::None),
+      Exit => (Args::Exit, Reply::None),
+    }
+  } else if let Ok(m) = method.parse::<router::Methods>() {
+    match m {
+      router::Methods::GetRoute => (Args::None, Reply::Route),
+      router::Methods::GetService => (Args::None, Reply::Service),
+    }
+  } else if let Ok(m) = method.parse::<service::Methods>() {
+    match m {
+      service::Methods::SetUpstream => (Args::String, Reply::Bool),
+      service::Methods::SetTarget => (Args::Target, Reply::None),
+    }
+  } else if let Ok(m) = method.parse::<service::request::Methods>() {
+    use service::request::Methods::*;
+    match m {
+      SetScheme | SetPath | SetRawQuery | SetMethod | ClearHeader => (Args::String, Reply::None),
+      SetQuery | SetHeaders => (Args::Map, Reply::None),
+      SetHeader | AddHeader => (Args::Kv, Reply::None),
+      SetRawBody => (Args::Bytes, Reply::None),
+    }
+  } else if let Ok(m) = method.parse::<service::response::Methods>() {
+    use service::response::Methods::*;
+    match m {
+      GetStatus => (Args::None, Reply::Int),
+      GetHeader => (Args::String, Reply::String),
+      GetHeaders => (Args::Int, Reply::Map),
+      GetRawBody => (Args::None, Reply::Bytes),
+    }
+  } else {
+    return Err(KongError::InvalidValueError(format!("{} has no multiplexed encoding", method)));
+  };
+  Ok(shape)
+}
+
+// Lays out the protobuf args of a PDK call as PdkArgs.
+pub(crate) fn encode_args(method: &str, args: &[u8]) -> KongResult<Vec<PdkArg>> {
+  Ok(match shape(method)?.0 {
+    Args::None => vec![],
+    Args::String => vec![arg(Data::S(kong_rs_protos::String::decode(args)?.v))],
+    Args::Int => vec![arg(Data::I(kong_rs_protos::Int::decode(args)?.v as i64))],
+    Args::Bool => vec![arg(Data::B(kong_rs_protos::Bool::decode(args)?.v))],
+    Args::Kv => {
+      let kv = Kv::decode(args)?;
+      vec![arg(Data::S(kv.k)), value_to_arg(kv.v)?]
+    },
+    Args::List => ListValue::decode(args)?.values.into_iter().map(|x| value_to_arg(Some(x))).collect::<KongResult<_>>()?,
+    Args::Map => vec![arg(Data::M(struct_to_map(Struct::decode(args)?)?))],
+    Args::Bytes => vec![arg(Data::S(hex::encode(ByteString::decode(args)?.v)))],
+    Args::Exit => {
+      let exit = ExitArgs::decode(args)?;
+      let mut args = vec![arg(Data::I(exit.status as i64)), arg(Data::S(hex::encode(exit.body)))];
+      if let Some(headers) = exit.headers {
+        args.push(arg(Data::M(struct_to_map(headers)?)));
+      }
+      args
+    },
+    Args::Target => {
+      let target = Target::decode(args)?;
+      vec![arg(Data::S(target.host)), arg(Data::I(target.port as i64))]
+    },
+    Args::ConsumerSpec => {
+      let spec = ConsumerSpec::decode(args)?;
+      vec![arg(Data::S(spec.id)), arg(Data::B(spec.by_username))]
+    },
+    Args::Authenticate => {
+      let auth = AuthenticateArgs::decode(args)?;
+      vec![
+        auth.consumer.map_or_else(PdkArg::default, |x| arg(Data::Consumer(x))),
+        auth.credential.map_or_else(PdkArg::default, |x| arg(Data::Credential(x)))
+      ]
+    }
+  })
+}
+
+// The reverse of encode_args, for the Kong side of the connection.
+#[cfg(any(test, feature = "testing"))]
+pub(crate) fn decode_args(method: &str, args: Vec<PdkArg>) -> KongResult<Vec<u8>> {
+  let mut args = args.into_iter().map(|x| x.data);
+  let mut next = || args.next().flatten();
+  Ok(match shape(method)?.0 {
+    Args::None => vec![],
+    Args::String => kong_rs_protos::String { v: string(next())? }.encode_to_vec(),
+    Args::Int => kong_rs_protos::Int { v: int(next())? as i32 }.encode_to_vec(),
+    Args::Bool => kong_rs_protos::Bool { v: boolean(next())? }.encode_to_vec(),
+    Args::Kv => Kv { k: string(next())?, v: Some(data_to_value(next())?) }.encode_to_vec(),
+    Args::List => ListValue { values: std::iter::from_fn(|| args.next()).map(data_to_value).collect::<KongResult<_>>()? }.encode_to_vec(),
+    Args::Map => map_to_struct(map(next())?)?.encode_to_vec(),
+    Args::Bytes => ByteString { v: bytes(next())? }.encode_to_vec(),
+    Args::Exit => ExitArgs {
+      status: int(next())? as i32,
+      body: bytes(next())?,
+      headers: next().map(|x| map_to_struct(map(Some(x))?)).transpose()?
+    }.encode_to_vec(),
+    Args::Target => Target { host: string(next())?, port: int(next())? as i32 }.encode_to_vec(),
+    Args::ConsumerSpec => ConsumerSpec { id: string(next())?, by_username: boolean(next())? }.encode_to_vec(),
+    Args::Authenticate => AuthenticateArgs {
+      consumer: match next() {
+        None => None,
+        Some(Data::Consumer(x)) => Some(x),
+        Some(other) => return Err(mismatch("a consumer", other))
+      },
+      credential: match next() {
+        None => None,
+        Some(Data::Credential(x)) => Some(x),
+        Some(other) => return Err(mismatch("a credential", other))
+      }
+    }.encode_to_vec()
+  })
+}
+
+// Lays out the protobuf reply to a PDK call as a PdkArg, for the Kong side of the connection.
+#[cfg(any(test, feature = "testing"))]
+pub(crate) fn encode_reply(method: &str, reply: &[u8]) -> KongResult<Option<PdkArg>> {
+  let data = match shape(method)?.1 {
+    Reply::None => return Ok(None),
+    Reply::String => Data::S(kong_rs_protos::String::decode(reply)?.v),
+    Reply::Int => Data::I(kong_rs_protos::Int::decode(reply)?.v as i64),
+    Reply::Bool => Data::B(kong_rs_protos::Bool::decode(reply)?.v),
+    Reply::Number => Data::F(kong_rs_protos::Number::decode(reply)?.v),
+    Reply::Value => return value_to_arg(Some(Value::decode(reply)?)).map(Some),
+    Reply::Map => Data::M(struct_to_map(Struct::decode(reply)?)?),
+    Reply::Bytes => Data::S(hex::encode(ByteString::decode(reply)?.v)),
+    Reply::RawBody => match RawBodyResult::decode(reply)?.kind {
+      None => return Ok(None),
+      Some(raw_body_result::Kind::Content(body)) => Data::S(hex::encode(body)),
+      Some(raw_body_result::Kind::Error(e)) => Data::Error(e),
+      Some(raw_body_result::Kind::BodyFilepath(path)) => return Err(KongError::InvalidValueError(format!(
+        "The body was buffered to {}, which has no multiplexed encoding", path
+      )))
+    },
+    Reply::Route => Data::Route(kong_rs_protos::Route::decode(reply)?),
+    Reply::Service => Data::Service(kong_rs_protos::Service::decode(reply)?),
+    Reply::Consumer => Data::Consumer(kong_rs_protos::Consumer::decode(reply)?),
+    Reply::Credential => Data::Credential(kong_rs_protos::AuthenticatedCredential::decode(reply)?),
+  };
+  Ok(Some(arg(data)))
+}
+
+// Turns the PdkArg of a PdkReturn back into the protobuf reply the PDK expects. A nil arg reads as an empty message.
+pub(crate) fn decode_reply(method: &str, reply: Option<PdkArg>) -> KongResult<Vec<u8>> {
+  let data = reply.and_then(|x| x.data);
+  let shape = shape(method)?.1;
+
+  if let Some(Data::Error(e)) = data {
+    return match shape {
+      Reply::RawBody => Ok(RawBodyResult { kind: Some(raw_body_result::Kind::Error(e)) }.encode_to_vec()),
+      _ => Err(KongError::InvalidValueError(format!("{} failed: {}", method, e)))
+    };
+  }
+
+  Ok(match shape {
+    Reply::None => vec![],
+    Reply::String => kong_rs_protos::String { v: string(data)? }.encode_to_vec(),
+    Reply::Int => kong_rs_protos::Int { v: int(data)? as i32 }.encode_to_vec(),
+    Reply::Bool => kong_rs_protos::Bool { v: boolean(data)? }.encode_to_vec(),
+    Reply::Number => kong_rs_protos::Number { v: number(data)? }.encode_to_vec(),
+    Reply::Value => data_to_value(data)?.encode_to_vec(),
+    Reply::Map => map_to_struct(map(data)?)?.encode_to_vec(),
+    Reply::Bytes => ByteString { v: bytes(data)? }.encode_to_vec(),
+    Reply::RawBody => match data {
+      None => vec![],
+      data => RawBodyResult { kind: Some(raw_body_result::Kind::Content(bytes(data)?)) }.encode_to_vec()
+    },
+    Reply::Route => match data {
+      None => vec![],
+      Some(Data::Route(x)) => x.encode_to_vec(),
+      Some(other) => return Err(mismatch("a route", other))
+    },
+    Reply::Service => match data {
+      None => vec![],
+      Some(Data::Service(x)) => x.encode_to_vec(),
+      Some(other) => return Err(mismatch("a service", other))
+    },
+    Reply::Consumer => match data {
+      None => vec![],
+      Some(Data::Consumer(x)) => x.encode_to_vec(),
+      Some(other) => return Err(mismatch("a consumer", other))
+    },
+    Reply::Credential => match data {
+      None => vec![],
+      Some(Data::Credential(x)) => x.encode_to_vec(),
+      Some(other) => return Err(mismatch("a credential", other))
+    },
+  })
+}
+
+// Frames from Kong are either RpcCalls or PdkReturns.
+pub(crate) fn pdk_return(frame: &[u8]) -> Option<PdkReturn> {
+  PdkReturn::decode(frame).ok().filter(|x| x.event_id != 0)
+}
+
+// Frames from the plugin server are either RpcReturns or PdkCalls.
+#[cfg(feature = "testing")]
+pub(crate) fn pdk_call(frame: &[u8]) -> Option<PdkCall> {
+  PdkCall::decode(frame).ok().filter(|x| x.event_id != 0)
+}
+
+// PDK calls waiting on their PdkReturn, by event id and sequence.
+#[derive(Clone, Default)]
+pub(crate) struct Pending(Arc<Mutex<HashMap<(i64, i64), Waiting>>>);
+
+type Waiting = oneshot::Sender<PdkReturn>;
+
+impl Pending {
+  fn wait(&self, event_id: i64, sequence: i64) -> oneshot::Receiver<PdkReturn> {
+    let (tx, rx) = oneshot::channel();
+    self.0.lock().unwrap().insert((event_id, sequence), tx);
+    rx
+  }
+
+  // Returns false if no call was waiting on the return.
+  pub(crate) fn resolve(&self, ret: PdkReturn) -> bool {
+    let waiting = self.0.lock().unwrap().remove(&(ret.event_id, ret.sequence));
+    waiting.is_some_and(|tx| tx.send(ret).is_ok())
+  }
+}
+
+// The PDK transport for one event on a multiplexed connection. Replies are read by the connection's handler and
+// handed over through `pending`.
+pub(crate) struct MultiplexedTransport {
+  stream: Stream,
+  pending: Pending,
+  event_id: i64,
+  sequence: AtomicI64
+}
+
+impl MultiplexedTransport {
+  pub(crate) fn new(stream: Stream, pending: Pending, event_id: i64) -> Self {
+    Self { stream, pending, event_id, sequence: AtomicI64::new(1) }
+  }
+}
+
+#[async_trait::async_trait]
+impl Transport for MultiplexedTransport {
+  async fn call(&self, method: &str, args: &[u8]) -> KongResult<Vec<u8>> {
+    let args = encode_args(method, args)?;
+    let sequence = self.sequence.fetch_add(1, Ordering::Relaxed);
+    let reply = self.pending.wait(self.event_id, sequence);
+
+    self.stream.write_message(&PdkCall { sequence, event_id: self.event_id, cmd: method.to_owned(), args }).await?;
+    let reply = reply.await.map_err(|_| KongError::ConnectionClosed)?;
+    decode_reply(method, reply.arg)
+  }
+}
+
+fn arg(data: Data) -> PdkArg {
+  PdkArg { data: Some(data) }
+}
+
+fn mismatch(expected: &str, data: Data) -> KongError {
+  KongError::InvalidValueError(format!("Expected {} argument, got {:?}", expected, data))
+}
+
+fn value_to_arg(value: Option<Value>) -> KongResult<PdkArg> {
+  Ok(match value.and_then(|x| x.kind) {
+    None | Some(Kind::NullValue(_)) => PdkArg::default(),
+    Some(kind) => arg(Data::S(to_json(kind)?.to_string()))
+  })
+}
+
+fn data_to_value(data: Option<Data>) -> KongResult<Value> {
+  match data {
+    None => Ok(Value { kind: Some(Kind::NullValue(0)) }),
+    Some(Data::S(s)) => Ok(from_json(serde_json::from_str(&s)?)),
+    Some(other) => Err(mismatch("a value", other))
+  }
+}
+
+fn struct_to_map(st: Struct) -> KongResult<StringMap> {
+  let m = st.fields.into_iter()
+    .map(|(k, v)| Ok((k, to_json(v.kind.unwrap_or(Kind::NullValue(0)))?.to_string())))
+    .collect::<KongResult<_>>()?;
+  Ok(StringMap { m })
+}
+
+fn map_to_struct(map: StringMap) -> KongResult<Struct> {
+  let fields = map.m.into_iter()
+    .map(|(k, v)| Ok((k, from_json(serde_json::from_str(&v)?))))
+    .collect::<KongResult<_>>()?;
+  Ok(Struct { fields })
+}
+
+fn to_json(kind: Kind) -> KongResult<serde_json::Value> {
+  Ok(match kind {
+    Kind::NullValue(_) => serde_json::Value::Null,
+    Kind::NumberValue(n) => serde_json::Number::from_f64(n).map(serde_json::Value::Number)
+      .ok_or_else(|| KongError::InvalidValueError(format!("{} has no multiplexed encoding", n)))?,
+    Kind::StringValue(s) => serde_json::Value::String(s),
+    Kind::BoolValue(b) => serde_json::Value::Bool(b),
+    Kind::StructValue(st) => serde_json::Value::Object(
+      st.fields.into_iter().map(|(k, v)| Ok((k, to_json(v.kind.unwrap_or(Kind::NullValue(0)))?))).collect::<KongResult<_>>()?
+    ),
+    Kind::ListValue(list) => serde_json::Value::Array(
+      list.values.into_iter().map(|v| to_json(v.kind.unwrap_or(Kind::NullValue(0)))).collect::<KongResult<_>>()?
+    )
+  })
+}
+
+fn from_json(json: serde_json::Value) -> Value {
+  let kind = match json {
+    serde_json::Value::Null => Kind::NullValue(0),
+    serde_json::Value::Bool(b) => Kind::BoolValue(b),
+    serde_json::Value::Number(n) => Kind::NumberValue(n.as_f64().unwrap_or_default()),
+    serde_json::Value::String(s) => Kind::StringValue(s),
+    serde_json::Value::Array(values) => Kind::ListValue(ListValue { values: values.into_iter().map(from_json).collect() }),
+    serde_json::Value::Object(fields) => Kind::StructValue(Struct { fields: fields.into_iter().map(|(k, v)| (k, from_json(v))).collect() })
+  };
+  Value { kind: Some(kind) }
+}
+
+fn string(data: Option<Data>) -> KongResult<String> {
+  match data {
+    None => Ok(String::new()),
+    Some(Data::S(s)) => Ok(s),
+    Some(other) => Err(mismatch("a string", other))
+  }
+}
+
+fn int(data: Option<Data>) -> KongResult<i64> {
+  match data {
+    None => Ok(0),
+    Some(Data::I(n)) => Ok(n),
+    Some(Data::F(n)) => Ok(n as i64),
+    Some(other) => Err(mismatch("an integer", other))
+  }
+}
+
+fn bytes(data: Option<Data>) -> KongResult<Vec<u8>> {
+  hex::decode(string(data)?).map_err(|e| KongError::InvalidValueError(format!("Expected hex-encoded bytes: {}", e)))
+}
+
+fn number(data: Option<Data>) -> KongResult<f64> {
+  match data {
+    None => Ok(0.0),
+    Some(Data::F(n)) => Ok(n),
+    Some(Data::I(n)) => Ok(n as f64),
+    Some(other) => Err(mismatch("a number", other))
+  }
+}
+
+fn boolean(data: Option<Data>) -> KongResult<bool> {
+  match data {
+    None => Ok(false),
+    Some(Data::B(b)) => Ok(b),
+    Some(other) => Err(mismatch("a bool", other))
+  }
+}
+
+fn map(data: Option<Data>) -> KongResult<StringMap> {
+  match data {
+    None => Ok(StringMap::default()),
+    Some(Data::M(m)) => Ok(m),
+    Some(other) => Err(mismatch("a map", other))
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use kong_rs_protos::{
+    raw_body_result, AuthenticateArgs, AuthenticatedCredential, ByteString, Consumer, ConsumerSpec, ExitArgs, Kv, RawBodyResult, Route,
+    Service, Target
+  };
+  use prost::Message;
+  use prost_types::{value::Kind, ListValue, Struct, Value};
+  use strum::IntoEnumIterator;
+
+  use crate::pdk::{client, ctx, log, ngx, request, response, router, service};
+
+  use super::{decode_args, decode_reply, encode_args, encode_reply, shape, Args, Reply};
+
+  const NOT_UTF8: &[u8] = b"\xff\x00\xc3\x28 body";
+
+  fn value(kind: Kind) -> Value {
+    Value { kind: Some(kind) }
+  }
+
+  fn list(values: Vec<Kind>) -> Kind {
+    Kind::ListValue(ListValue { values: values.into_iter().map(value).collect() })
+  }
+
+  // Everything a Value can hold, nested: repeated headers, nulls, numbers and a struct within a struct.
+  fn table() -> Struct {
+    Struct { fields: [
+      ("set-cookie".to_owned(), value(list(vec![Kind::StringValue("a=1".to_owned()), Kind::StringValue("b=2, c".to_owned())]))),
+      ("flag".to_owned(), value(Kind::BoolValue(true))),
+      ("count".to_owned(), value(Kind::NumberValue(1.5))),
+      ("unset".to_owned(), value(Kind::NullValue(0))),
+      ("nested".to_owned(), value(Kind::StructValue(Struct { fields: [
+        ("list".to_owned(), value(list(vec![Kind::NumberValue(1.0), Kind::NullValue(0)])))
+      ].into() })))
+    ].into() }
+  }
+
+  fn args(shape: Args) -> Vec<Vec<u8>> {
+    match shape {
+      Args::None => vec![vec![]],
+      Args::String => vec![kong_rs_protos::String { v: "ünïcode".to_owned() }.encode_to_vec()],
+      Args::Int => vec![kong_rs_protos::Int { v: -7 }.encode_to_vec()],
+      Args::Bool => vec![kong_rs_protos::Bool { v: true }.encode_to_vec()],
+      Args::Kv => vec![
+        Kv { k: "key".to_owned(), v: Some(value(Kind::StructValue(table()))) }.encode_to_vec(),
+        Kv { k: "key".to_owned(), v: Some(value(list(vec![Kind::StringValue("x".to_owned())]))) }.encode_to_vec()
+      ],
+      Args::List => vec![ListValue { values: vec![
+        value(Kind::StringValue("message".to_owned())), value(Kind::NumberValue(42.0)), value(Kind::NullValue(0)),
+        value(Kind::StructValue(table())), value(list(vec![]))
+      ] }.encode_to_vec()],
+      Args::Map => vec![table().encode_to_vec()],
+      Args::Bytes => vec![ByteString { v: NOT_UTF8.to_vec() }.encode_to_vec()],
+      Args::Exit => vec![
+        ExitArgs { status: 403, body: NOT_UTF8.to_vec(), headers: Some(table()) }.encode_to_vec(),
+        ExitArgs { status: 204, body: vec![], headers: None }.encode_to_vec()
+      ],
+      Args::Target => vec![Target { host: "example.com".to_owned(), port: 8443 }.encode_to_vec()],
+      Args::ConsumerSpec => vec![ConsumerSpec { id: "alice".to_owned(), by_username: true }.encode_to_vec()],
+      Args::Authenticate => vec![
+        AuthenticateArgs { consumer: Some(Consumer { id: "c".to_owned(), ..Default::default() }), credential: None }.encode_to_vec(),
+        AuthenticateArgs { consumer: None, credential: Some(AuthenticatedCredential { id: "k".to_owned(), consumer_id: "c".to_owned() }) }.encode_to_vec()
+      ]
+    }
+  }
+
+  fn replies(shape: Reply) -> Vec<Vec<u8>> {
+    match shape {
+      Reply::None => vec![vec![]],
+      Reply::String => vec![kong_rs_protos::String { v: "ünïcode".to_owned() }.encode_to_vec()],
+      Reply::Int => vec![kong_rs_protos::Int { v: 8000 }.encode_to_vec()],
+      Reply::Bool => vec![kong_rs_protos::Bool { v: true }.encode_to_vec()],
+      Reply::Number => vec![kong_rs_protos::Number { v: 1.1 }.encode_to_vec()],
+      Reply::Value => vec![
+        value(Kind::StructValue(table())).encode_to_vec(),
+        value(list(vec![Kind::BoolValue(false)])).encode_to_vec(),
+        value(Kind::StringValue("plain".to_owned())).encode_to_vec()
+      ],
+      Reply::Map => vec![table().encode_to_vec()],
+      Reply::Bytes => vec![ByteString { v: NOT_UTF8.to_vec() }.encode_to_vec()],
+      Reply::RawBody => vec![
+        vec![],
+        RawBodyResult { kind: Some(raw_body_result::Kind::Content(NOT_UTF8.to_vec())) }.encode_to_vec(),
+        RawBodyResult { kind: Some(raw_body_result::Kind::Error("too large".to_owned())) }.encode_to_vec()
+      ],
+      Reply::Route => vec![vec![], Route { name: "users".to_owned(), ..Default::default() }.encode_to_vec()],
+      Reply::Service => vec![vec![], Service { name: "backend".to_owned(), ..Default::default() }.encode_to_vec()],
+      Reply::Consumer => vec![vec![], Consumer { username: "alice".to_owned(), ..Default::default() }.encode_to_vec()],
+      Reply::Credential => vec![vec![], AuthenticatedCredential { id: "k".to_owned(), consumer_id: "c".to_owned() }.encode_to_vec()]
+    }
+  }
+
+  fn methods() -> Vec<&'static str> {
+    fn names<M: IntoEnumIterator + Into<&'static str>>() -> impl Iterator<Item = &'static str> {
+      M::iter().map(Into::into)
+    }
+
+    names::<client::Methods>()
+      .chain(names::<ctx::Methods>())
+      .chain(names::<log::Methods>())
+      .chain(names::<ngx::Methods>())
+      .chain(names::<request::Methods>())
+      .chain(names::<response::Methods>())
+      .chain(names::<router::Methods>())
+      .chain(names::<service::Methods>())
+      .chain(names::<service::request::Methods>())
+      .chain(names::<service::response::Methods>())
+      .collect()
+  }
+
+  #[test]
+  fn every_method_round_trips() {
+    for method in methods() {
+      let (args_shape, reply_shape) = shape(method).unwrap();
+
+      for sent in args(args_shape) {
+        let received = decode_args(method, encode_args(method, &sent).unwrap()).unwrap();
+        assert_eq!(received, sent, "args of {}", method);
+      }
+
+      for sent in replies(reply_shape) {
+        let received = decode_reply(method, encode_reply(method, &sent).unwrap()).unwrap();
+        assert_eq!(received, sent, "reply to {}", method);
+      }
+    }
+  }
+
+  #[test]
+  fn bodies_keep_their_bytes() {
+    let sent = ByteString { v: NOT_UTF8.to_vec() }.encode_to_vec();
+    let args = encode_args("kong.service.request.set_raw_body", &sent).unwrap();
+    assert_eq!(decode_args("kong.service.request.set_raw_body", args).unwrap(), sent);
+  }
+
+  #[test]
+  fn unencodable_values_fail() {
+    let nan = Kv { k: "key".to_owned(), v: Some(value(Kind::NumberValue(f64::NAN))) }.encode_to_vec();
+    let error = encode_args("kong.ctx.shared.set", &nan).unwrap_err();
+    assert!(error.to_string().contains("NaN has no multiplexed encoding"), "{}", error);
+
+    let filepath = RawBodyResult { kind: Some(raw_body_result::Kind::BodyFilepath("/tmp/body".to_owned())) }.encode_to_vec();
+    let error = encode_reply("kong.request.get_raw_body", &filepath).unwrap_err();
+    assert!(error.to_string().contains("buffered to /tmp/body"), "{}", error);
+  }
+}
@@ -1,10 +1,13 @@
+use std::sync::Arc;
+
 use kong_rs_protos::{AuthenticateArgs, AuthenticatedCredential, Consumer, ConsumerSpec};
 use strum::{EnumString, IntoStaticStr};
 
-use crate::{stream::Stream, KongResult};
+use crate::{transport::Transport, KongResult};
 
 
 #[derive(Debug, PartialEq, IntoStaticStr, EnumString)]
+#[cfg_attr(test, derive(strum::EnumIter))]
 pub(crate) enum Methods {
   #[strum(serialize = "kong.client.get_ip")]
   GetIp,
@@ -28,47 +31,47 @@ pub(crate) enum Methods {
 
 #[derive(Clone)]
 pub struct ClientPDK {
-  stream: Stream
+  transport: Arc<dyn Transport>
 }
 
 impl ClientPDK {
-  pub fn new(stream: Stream) -> Self {
-    Self { stream }
+  pub fn new(transport: Arc<dyn Transport>) -> Self {
+    Self { transport }
   }
 
   pub async fn get_ip(&self) -> KongResult<String> {
-    self.stream.ask_string(Methods::GetIp.into()).await
+    self.transport.ask_string(Methods::GetIp.into()).await
   }
 
   pub async fn get_forwarded_ip(&self) -> KongResult<String> {
-    self.stream.ask_string(Methods::GetForwardedIp.into()).await
+    self.transport.ask_string(Methods::GetForwardedIp.into()).await
   }
 
   pub async fn get_port(&self) -> KongResult<usize> {
-    self.stream.ask_int(Methods::GetPort.into()).await.map(|port| port as usize)
+    self.transport.ask_int(Methods::GetPort.into()).await.map(|port| port as usize)
   }
 
   pub async fn get_forwarded_port(&self) -> KongResult<usize> {
-    self.stream.ask_int(Methods::GetForwardedPort.into()).await.map(|port| port as usize)
+    self.transport.ask_int(Methods::GetForwardedPort.into()).await.map(|port| port as usize)
   }
 
   pub async fn get_credential(&self) -> KongResult<AuthenticatedCredential> {
-    self.stream.ask_message(Methods::GetCredential.into()).await
+    self.transport.ask_message(Methods::GetCredential.into()).await
   }
 
   pub async fn load_consumer(&self, consumer: ConsumerSpec) -> KongResult<Consumer> {
-    self.stream.ask_message_with_args(Methods::LoadConsumer.into(), &consumer).await
+    self.transport.ask_message_with_args(Methods::LoadConsumer.into(), &consumer).await
   }
 
   pub async fn get_consumer(&self) -> KongResult<Consumer> {
-    self.stream.ask_message(Methods::GetConsumer.into()).await
+    self.transport.ask_message(Methods::GetConsumer.into()).await
   }
 
   pub async fn authenticate(&self, auth: AuthenticateArgs) -> KongResult<()> {
-    self.stream.ask(Methods::Authenticate.into(), &auth).await
+    self.transport.ask(Methods::Authenticate.into(), &auth).await
   }
 
   pub async fn get_protocol(&self, allow_terminated: bool) -> KongResult<String> {
-    self.stream.ask_string_with_args(Methods::GetProtocol.into(), &kong_rs_protos::Bool { v: allow_terminated }).await
+    self.transport.ask_string_with_args(Methods::GetProtocol.into(), &kong_rs_protos::Bool { v: allow_terminated }).await
   }
 }
\ No newline at end of file
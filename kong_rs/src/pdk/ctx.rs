@@ -1,11 +1,14 @@
+use std::sync::Arc;
+
 use kong_rs_protos::Kv;
 use strum::{EnumString, IntoStaticStr};
 
-use crate::{stream::Stream, KongResult};
+use crate::{transport::Transport, KongResult};
 
 use super::Value;
 
 #[derive(Debug, PartialEq, IntoStaticStr, EnumString)]
+#[cfg_attr(test, derive(strum::EnumIter))]
 pub(crate) enum Methods {
   #[strum(serialize = "kong.ctx.shared.set")]
   SharedSet,
@@ -19,12 +22,12 @@ pub(crate) enum Methods {
 
 #[derive(Clone)]
 pub struct CtxPDK {
-  stream: Stream
+  transport: Arc<dyn Transport>
 }
 
 impl CtxPDK {
-  pub fn new(stream: Stream) -> Self {
-    Self { stream }
+  pub fn new(transport: Arc<dyn Transport>) -> Self {
+    Self { transport }
   }
 
   pub async fn shared_set<K: Into<String>>(&self, key: K, value: Value) -> KongResult<()> {
@@ -33,12 +36,12 @@ impl CtxPDK {
         x => Some(x.into())
     };
 
-    let kv = Kv { k: key.into(), v: Some(prost_types::Value { kind: kind }) };
-    self.stream.ask_message_with_args(Methods::SharedSet.into(), &kv).await
+    let kv = Kv { k: key.into(), v: Some(prost_types::Value { kind }) };
+    self.transport.ask_message_with_args(Methods::SharedSet.into(), &kv).await
   }
 
   pub async fn shared_get<K: Into<String>>(&self, key: K) -> KongResult<Value> {
-    let v: prost_types::Value = self.stream.ask_message_with_args(
+    let v: prost_types::Value = self.transport.ask_message_with_args(
       Methods::SharedGet.into(),
       &kong_rs_protos::String { v: key.into() }
     ).await?;
@@ -52,12 +55,12 @@ impl CtxPDK {
         x => Some(x.into())
     };
 
-    let kv = Kv { k: key.into(), v: Some(prost_types::Value { kind: kind }) };
-    self.stream.ask_message_with_args(Methods::Set.into(), &kv).await
+    let kv = Kv { k: key.into(), v: Some(prost_types::Value { kind }) };
+    self.transport.ask_message_with_args(Methods::Set.into(), &kv).await
   }
 
   pub async fn get<K: Into<String>>(&self, key: K) -> KongResult<Value> {
-    let v: prost_types::Value = self.stream.ask_message_with_args(
+    let v: prost_types::Value = self.transport.ask_message_with_args(
       Methods::Get.into(),
       &kong_rs_protos::String { v: key.into() }
     ).await?;
@@ -1,8 +1,14 @@
+use std::{collections::BTreeMap, sync::Arc};
+
+use kong_rs_protos::Kv;
 use strum::{EnumString, IntoStaticStr};
 
-use crate::{stream::Stream, KongResult};
+use crate::{transport::Transport, KongResult};
+
+use super::Value;
 
 #[derive(Debug, PartialEq, IntoStaticStr, EnumString)]
+#[cfg_attr(test, derive(strum::EnumIter))]
 pub(crate) enum Methods {
   #[strum(serialize = "kong.log.alert")]
   Alert,
@@ -20,20 +26,85 @@ pub(crate) enum Methods {
   Debug,
   #[strum(serialize = "kong.log.serialize")]
   Serialize,
+  #[strum(serialize = "kong.log.set_serialize_value")]
+  SetSerializeValue,
+}
+
+// The shape of kong.log.serialize(). Entities and anything added through
+// set_serialize_value are left as raw JSON, as their contents depend on the Kong version
+// and the other plugins in the chain.
+#[derive(Debug, Clone, Default, serde::Deserialize)]
+#[serde(default)]
+pub struct SerializedLog {
+  pub request: SerializedRequest,
+  pub response: SerializedResponse,
+  pub upstream_uri: Option<String>,
+  pub upstream_status: Option<String>,
+  pub latencies: SerializedLatencies,
+  pub tries: Vec<SerializedTry>,
+  pub client_ip: Option<String>,
+  pub started_at: Option<u64>,
+  pub source: Option<String>,
+  pub workspace: Option<String>,
+  pub workspace_name: Option<String>,
+  pub route: Option<serde_json::Value>,
+  pub service: Option<serde_json::Value>,
+  pub consumer: Option<serde_json::Value>,
+  pub authenticated_entity: Option<serde_json::Value>,
+  #[serde(flatten)]
+  pub extra: BTreeMap<String, serde_json::Value>,
+}
+
+#[derive(Debug, Clone, Default, serde::Deserialize)]
+#[serde(default)]
+pub struct SerializedRequest {
+  pub method: String,
+  pub uri: String,
+  pub url: String,
+  pub size: u64,
+  pub querystring: BTreeMap<String, serde_json::Value>,
+  pub headers: BTreeMap<String, serde_json::Value>,
+  pub tls: Option<serde_json::Value>,
+}
+
+#[derive(Debug, Clone, Default, serde::Deserialize)]
+#[serde(default)]
+pub struct SerializedResponse {
+  pub status: u16,
+  pub size: u64,
+  pub headers: BTreeMap<String, serde_json::Value>,
+}
+
+#[derive(Debug, Clone, Default, serde::Deserialize)]
+#[serde(default)]
+pub struct SerializedLatencies {
+  pub kong: Option<f64>,
+  pub proxy: Option<f64>,
+  pub request: Option<f64>,
+  pub receive: Option<f64>,
+}
+
+#[derive(Debug, Clone, Default, serde::Deserialize)]
+#[serde(default)]
+pub struct SerializedTry {
+  pub ip: Option<String>,
+  pub port: Option<u16>,
+  pub balancer_start: Option<u64>,
+  pub balancer_latency: Option<f64>,
 }
 
 #[derive(Clone)]
 pub struct LogPDK {
-  stream: Stream
+  transport: Arc<dyn Transport>
 }
 
 impl LogPDK {
-  pub fn new(stream: Stream) -> Self {
-    Self { stream }
+  pub fn new(transport: Arc<dyn Transport>) -> Self {
+    Self { transport }
   }
 
   async fn do_log(&self, method: Methods, args: String) -> KongResult<()> {
-    self.stream.ask(method.into(), &prost_types::ListValue {
+    self.transport.ask(method.into(), &prost_types::ListValue {
       values: vec![prost_types::Value { kind: Some(prost_types::value::Kind::StringValue(args)) }]
     }).await
   }
@@ -67,6 +138,85 @@ impl LogPDK {
   }
 
   pub async fn serialize(&self) -> KongResult<String> {
-    self.stream.ask_string(Methods::Serialize.into()).await
+    self.transport.ask_string(Methods::Serialize.into()).await
+  }
+
+  pub async fn serialized(&self) -> KongResult<SerializedLog> {
+    self.serialized_as().await
   }
-}
\ No newline at end of file
+
+  pub async fn serialized_as<T: serde::de::DeserializeOwned>(&self) -> KongResult<T> {
+    Ok(serde_json::from_str(&self.serialize().await?)?)
+  }
+
+  pub async fn set_serialize_value<K: Into<String>>(&self, key: K, value: Value) -> KongResult<()> {
+    let kind = match value {
+        Value::Null => None,
+        x => Some(x.into())
+    };
+
+    let kv = Kv { k: key.into(), v: Some(prost_types::Value { kind }) };
+    self.transport.ask(Methods::SetSerializeValue.into(), &kv).await
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use serde_json::json;
+
+  use super::SerializedLog;
+
+  // Payloads in the shape Kong 3.x's kong.log.serialize() produces, as documented for its http-log plugin: one for a
+  // proxied request, and one an auth plugin rejected before it reached the upstream.
+  const PROXIED: &str = include_str!("../../tests/fixtures/serialize_proxied.json");
+  const SHORT_CIRCUITED: &str = include_str!("../../tests/fixtures/serialize_short_circuited.json");
+
+  #[test]
+  fn reads_a_proxied_request() {
+    let log: SerializedLog = serde_json::from_str(PROXIED).unwrap();
+
+    assert_eq!((log.request.method.as_str(), log.request.uri.as_str(), log.request.size), ("GET", "/log?a=1&a=2&debug", 138));
+    assert_eq!(log.request.querystring["a"], json!(["1", "2"]));
+    assert_eq!(log.request.querystring["debug"], json!(true));
+    assert_eq!(log.request.headers["x-forwarded-for"], json!(["10.0.0.1", "10.0.0.2"]));
+    assert_eq!(log.request.tls.unwrap()["version"], json!("TLSv1.3"));
+
+    assert_eq!((log.response.status, log.response.size), (200, 811));
+    assert_eq!(log.response.headers["via"], json!("kong/3.4.2"));
+
+    assert_eq!(log.upstream_uri.as_deref(), Some("/anything?a=1&a=2&debug"));
+    assert_eq!(log.upstream_status.as_deref(), Some("200"));
+    assert_eq!((log.latencies.kong, log.latencies.proxy, log.latencies.request, log.latencies.receive), (Some(9.0), Some(1430.0), Some(1921.0), Some(0.0)));
+
+    assert_eq!(log.tries.len(), 1);
+    let attempt = &log.tries[0];
+    assert_eq!((attempt.ip.as_deref(), attempt.port, attempt.balancer_start, attempt.balancer_latency), (Some("18.211.130.98"), Some(80), Some(1697630668399), Some(0.0)));
+
+    assert_eq!(log.client_ip.as_deref(), Some("192.168.144.1"));
+    assert_eq!(log.started_at, Some(1697630668342));
+    assert_eq!(log.source.as_deref(), Some("upstream"));
+    assert_eq!(log.workspace_name.as_deref(), Some("default"));
+    assert_eq!(log.route.unwrap()["name"], json!("log"));
+    assert_eq!(log.service.unwrap()["host"], json!("httpbin.org"));
+    assert_eq!(log.consumer.unwrap()["username"], json!("alice"));
+    assert!(log.authenticated_entity.is_some());
+
+    // Values other plugins added through set_serialize_value.
+    assert_eq!(log.extra.keys().collect::<Vec<_>>(), vec!["my_plugin"]);
+  }
+
+  #[test]
+  fn reads_a_request_kong_answered_itself() {
+    let log: SerializedLog = serde_json::from_str(SHORT_CIRCUITED).unwrap();
+
+    assert_eq!(log.response.status, 401);
+    assert!(log.request.querystring.is_empty());
+    assert!(log.request.tls.is_none());
+    assert_eq!(log.upstream_status, None);
+    assert_eq!(log.latencies.proxy, Some(-1.0));
+    assert!(log.tries.is_empty());
+    assert_eq!(log.source.as_deref(), Some("kong"));
+    assert!(log.consumer.is_none() && log.authenticated_entity.is_none());
+    assert!(log.extra.is_empty());
+  }
+}
@@ -1,4 +1,4 @@
-use std::collections::BTreeMap;
+use std::{collections::BTreeMap, str::FromStr, sync::Arc};
 
 use client::ClientPDK;
 use ctx::CtxPDK;
@@ -9,7 +9,9 @@ use response::ResponsePDK;
 use router::RouterPDK;
 use service::ServicePDK;
 
-use crate::stream::Stream;
+use http::{HeaderMap, HeaderName, HeaderValue};
+
+use crate::{transport::Transport, KongResult};
 
 pub mod client;
 pub mod ctx;
@@ -20,7 +22,7 @@ pub mod response;
 pub mod router;
 pub mod service;
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq)]
 pub enum Value {
   Null,
   Number(f64),
@@ -47,9 +49,9 @@ impl From<prost_types::value::Kind> for Value {
   }
 }
 
-impl Into<prost_types::value::Kind> for Value {
-  fn into(self) -> prost_types::value::Kind {
-    match self {
+impl From<Value> for prost_types::value::Kind {
+  fn from(value: Value) -> Self {
+    match value {
       Value::Null => prost_types::value::Kind::NullValue(0),
       Value::Number(number) => prost_types::value::Kind::NumberValue(number),
       Value::String(str) => prost_types::value::Kind::StringValue(str),
@@ -64,6 +66,44 @@ impl Into<prost_types::value::Kind> for Value {
   }
 }
 
+fn unwrap_single_header(name: &HeaderName, kind: prost_types::value::Kind, ret: &mut HeaderMap) -> KongResult<()> {
+  match kind {
+    prost_types::value::Kind::NullValue(_) => (),
+    prost_types::value::Kind::NumberValue(n) => {
+      ret.append(name, HeaderValue::from_str(&n.to_string())?);
+    }
+    prost_types::value::Kind::StringValue(str) => {
+      ret.append(name, HeaderValue::from_str(&str)?);
+    },
+    prost_types::value::Kind::BoolValue(b) => {
+      ret.append(name, HeaderValue::from_str(&b.to_string())?);
+    }
+    prost_types::value::Kind::StructValue(_) => {
+      // TODO: How do?
+    },
+    prost_types::value::Kind::ListValue(l) => {
+      for v in l.values {
+        if let Some(kind) = v.kind {
+          unwrap_single_header(name, kind, ret)?;
+        }
+      }
+    }
+  }
+  Ok(())
+}
+
+pub(crate) fn unwrap_headers(st: prost_types::Struct) -> KongResult<HeaderMap> {
+  let mut ret = HeaderMap::default();
+
+  for (name, v) in st.fields {
+    if let Some(kind) = v.kind {
+      let name = HeaderName::from_str(&name).unwrap();
+      unwrap_single_header(&name, kind, &mut ret)?;
+    }
+  }
+
+  Ok(ret)
+}
 
 pub struct Pdk {
   client: ClientPDK,
@@ -77,16 +117,16 @@ pub struct Pdk {
 }
 
 impl Pdk {
-  pub fn new(stream: Stream) -> Self {
+  pub fn new(transport: Arc<dyn Transport>) -> Self {
     Self {
-      client: ClientPDK::new(stream.clone()),
-      ctx: CtxPDK::new(stream.clone()),
-      log: LogPDK::new(stream.clone()),
-      ngx: NgxPDK::new(stream.clone()),
-      request: RequestPDK::new(stream.clone()),
-      response: ResponsePDK::new(stream.clone()),
-      router: RouterPDK::new(stream.clone()),
-      service: ServicePDK::new(stream.clone()),
+      client: ClientPDK::new(transport.clone()),
+      ctx: CtxPDK::new(transport.clone()),
+      log: LogPDK::new(transport.clone()),
+      ngx: NgxPDK::new(transport.clone()),
+      request: RequestPDK::new(transport.clone()),
+      response: ResponsePDK::new(transport.clone()),
+      router: RouterPDK::new(transport.clone()),
+      service: ServicePDK::new(transport.clone()),
     }
   }
 
@@ -122,3 +162,57 @@ impl Pdk {
     &self.service
   }
 }
+
+// The subset of the PDK that Kong makes available to the stream (L4) subsystem.
+#[derive(Clone)]
+pub struct StreamPdk {
+  client: ClientPDK,
+  ctx: CtxPDK,
+  log: LogPDK,
+  ngx: NgxPDK,
+  router: RouterPDK
+}
+
+impl StreamPdk {
+  pub fn new(transport: Arc<dyn Transport>) -> Self {
+    Self {
+      client: ClientPDK::new(transport.clone()),
+      ctx: CtxPDK::new(transport.clone()),
+      log: LogPDK::new(transport.clone()),
+      ngx: NgxPDK::new(transport.clone()),
+      router: RouterPDK::new(transport.clone()),
+    }
+  }
+
+  pub fn client(&self) -> &ClientPDK {
+    &self.client
+  }
+
+  pub fn ctx(&self) -> &CtxPDK {
+    &self.ctx
+  }
+
+  pub fn log(&self) -> &LogPDK {
+    &self.log
+  }
+
+  pub fn ngx(&self) -> &NgxPDK {
+    &self.ngx
+  }
+
+  pub fn router(&self) -> &RouterPDK {
+    &self.router
+  }
+}
+
+impl From<&Pdk> for StreamPdk {
+  fn from(pdk: &Pdk) -> Self {
+    Self {
+      client: pdk.client.clone(),
+      ctx: pdk.ctx.clone(),
+      log: pdk.log.clone(),
+      ngx: pdk.ngx.clone(),
+      router: pdk.router.clone(),
+    }
+  }
+}
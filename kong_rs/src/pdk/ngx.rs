@@ -1,24 +1,61 @@
+use std::sync::Arc;
+
 use strum::{EnumString, IntoStaticStr};
 
-use crate::{stream::Stream, KongResult};
+use crate::{transport::Transport, KongResult, KongError};
 
 #[derive(Debug, PartialEq, IntoStaticStr, EnumString)]
+#[cfg_attr(test, derive(strum::EnumIter))]
 pub(crate) enum Methods {
-  #[strum(serialize = "kong.ngx.get_var")]
+  #[strum(serialize = "kong.nginx.get_var")]
   GetVar,
+  #[strum(serialize = "kong.nginx.get_tls1_version_str")]
+  GetTls1VersionStr,
+  #[strum(serialize = "kong.nginx.req_start_time")]
+  ReqStartTime,
+  #[strum(serialize = "kong.nginx.get_subsystem")]
+  GetSubsystem,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, IntoStaticStr, EnumString)]
+pub enum Subsystem {
+  #[strum(serialize = "http")]
+  Http,
+  #[strum(serialize = "stream")]
+  Stream,
 }
 
 #[derive(Clone)]
 pub struct NgxPDK {
-  stream: Stream
+  transport: Arc<dyn Transport>
 }
 
 impl NgxPDK {
-  pub fn new(stream: Stream) -> Self {
-    Self { stream }
+  pub fn new(transport: Arc<dyn Transport>) -> Self {
+    Self { transport }
   }
 
   pub async fn get_var<K: Into<String>>(&self, key: K) -> KongResult<String> {
-    self.stream.ask_string_with_args(Methods::GetVar.into(), &kong_rs_protos::String { v: key.into() }).await
+    self.transport.ask_string_with_args(Methods::GetVar.into(), &kong_rs_protos::String { v: key.into() }).await
+  }
+
+  // The SNI sent by the client in the TLS handshake, or an empty string for plain-text connections. This reads
+  // $ssl_server_name, and ngx.var can't be read during the handshake, so it fails in the certificate phase. Kong's PDK
+  // has no call wrapping ngx.ssl.server_name() for that phase.
+  pub async fn get_sni(&self) -> KongResult<String> {
+    self.get_var("ssl_server_name").await
+  }
+
+  pub async fn get_tls1_version_str(&self) -> KongResult<String> {
+    self.transport.ask_string(Methods::GetTls1VersionStr.into()).await
+  }
+
+  pub async fn req_start_time(&self) -> KongResult<f64> {
+    self.transport.ask_number(Methods::ReqStartTime.into()).await
+  }
+
+  pub async fn get_subsystem(&self) -> KongResult<Subsystem> {
+    let subsystem = self.transport.ask_string(Methods::GetSubsystem.into()).await?;
+    subsystem.parse().map_err(|_| KongError::InvalidValueError(format!("Unknown subsystem: {}", subsystem)))
   }
 }
@@ -1,8 +1,10 @@
+use std::sync::Arc;
+
 use http::HeaderMap;
 use kong_rs_protos::RawBodyResult;
 use strum::{EnumString, IntoStaticStr};
 
-use crate::{stream::Stream, KongError, KongResult};
+use crate::{pdk::unwrap_headers, transport::Transport, KongError, KongResult};
 
 pub enum Body {
   Content(Vec<u8>),
@@ -11,6 +13,8 @@ pub enum Body {
 }
 
 #[derive(Debug, PartialEq, IntoStaticStr, EnumString)]
+#[cfg_attr(test, derive(strum::EnumIter))]
+#[allow(clippy::enum_variant_names)]
 pub(crate) enum Methods {
   #[strum(serialize = "kong.request.get_scheme")]
   GetScheme,
@@ -48,104 +52,104 @@ pub(crate) enum Methods {
 
 #[derive(Clone)]
 pub struct RequestPDK {
-  stream: Stream,
+  transport: Arc<dyn Transport>,
 }
 
 impl RequestPDK {
-  pub fn new(stream: Stream) -> Self {
-    Self { stream }
+  pub fn new(transport: Arc<dyn Transport>) -> Self {
+    Self { transport }
   }
 
   pub async fn get_scheme(&self) -> KongResult<String> {
-    self.stream.ask_string(Methods::GetScheme.into()).await
+    self.transport.ask_string(Methods::GetScheme.into()).await
   }
 
   pub async fn get_host(&self) -> KongResult<String> {
-    self.stream.ask_string(Methods::GetHost.into()).await
+    self.transport.ask_string(Methods::GetHost.into()).await
   }
 
   pub async fn get_port(&self) -> KongResult<usize> {
-    self.stream
+    self.transport
       .ask_int(Methods::GetPort.into())
       .await
       .map(|port| port as usize)
   }
 
   pub async fn get_forwarded_scheme(&self) -> KongResult<String> {
-    self.stream
+    self.transport
       .ask_string(Methods::GetForwardedScheme.into())
       .await
   }
 
   pub async fn get_forwarded_host(&self) -> KongResult<String> {
-    self.stream
+    self.transport
       .ask_string(Methods::GetForwardedHost.into())
       .await
   }
 
   pub async fn get_forwarded_port(&self) -> KongResult<usize> {
-    self.stream
+    self.transport
       .ask_int(Methods::GetForwardedPort.into())
       .await
       .map(|port| port as usize)
   }
 
   pub async fn get_http_version(&self) -> KongResult<f64> {
-    self.stream
-      .ask_number(Methods::GetForwardedPort.into())
+    self.transport
+      .ask_number(Methods::GetHttpVersion.into())
       .await
   }
 
   pub async fn get_method(&self) -> KongResult<String> {
-    self.stream.ask_string(Methods::GetMethod.into()).await
+    self.transport.ask_string(Methods::GetMethod.into()).await
   }
 
   pub async fn get_path(&self) -> KongResult<String> {
-    self.stream.ask_string(Methods::GetPath.into()).await
+    self.transport.ask_string(Methods::GetPath.into()).await
   }
 
   pub async fn get_path_with_query(&self) -> KongResult<String> {
-    self.stream
+    self.transport
       .ask_string(Methods::GetPathWithQuery.into())
       .await
   }
 
   pub async fn get_raw_query(&self) -> KongResult<String> {
-    self.stream.ask_string(Methods::GetRawQuery.into()).await
+    self.transport.ask_string(Methods::GetRawQuery.into()).await
   }
 
   pub async fn get_query_arg(&self, name: String) -> KongResult<String> {
-    self.stream
+    self.transport
       .ask_string_with_args(Methods::GetQueryArg.into(), &kong_rs_protos::String { v: name })
       .await
   }
 
   pub async fn get_query(&self, max_args: Option<usize>) -> KongResult<HeaderMap> {
     let max_args = max_args.unwrap_or(100);
-    let headers: prost_types::Struct = self.stream.ask_message_with_args(
+    let headers: prost_types::Struct = self.transport.ask_message_with_args(
       Methods::GetQuery.into(),
       &kong_rs_protos::Int { v: max_args as i32 }
     ).await?;
-    self.stream.unwrap_headers(headers)
+    unwrap_headers(headers)
   }
 
   pub async fn get_header(&self, name: String) -> KongResult<String> {
-    self.stream
+    self.transport
       .ask_string_with_args(Methods::GetHeader.into(), &kong_rs_protos::String { v: name })
       .await
   }
 
   pub async fn get_headers(&self, max_headers: Option<usize>) -> KongResult<HeaderMap> {
     let max_headers = max_headers.unwrap_or(100);
-    let headers: prost_types::Struct = self.stream.ask_message_with_args(
+    let headers: prost_types::Struct = self.transport.ask_message_with_args(
       Methods::GetHeaders.into(),
       &kong_rs_protos::Int { v: max_headers as i32 }
     ).await?;
-    self.stream.unwrap_headers(headers)
+    unwrap_headers(headers)
   }
 
   pub async fn get_raw_body(&self) -> KongResult<Body> {
-    let body: RawBodyResult = self.stream.ask_message(Methods::GetRawBody.into()).await?;
+    let body: RawBodyResult = self.transport.ask_message(Methods::GetRawBody.into()).await?;
     match body.kind {
       Some(kind) => match kind {
         kong_rs_protos::raw_body_result::Kind::Content(items) => {
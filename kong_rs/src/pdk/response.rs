@@ -1,11 +1,14 @@
+use std::sync::Arc;
+
 use http::HeaderMap;
 use kong_rs_protos::{ExitArgs, Kv};
 use prost_types::ListValue;
 use strum::{EnumString, IntoStaticStr};
 
-use crate::{stream::Stream, KongResult};
+use crate::{pdk::unwrap_headers, transport::Transport, KongResult};
 
 #[derive(Debug, PartialEq, IntoStaticStr, EnumString)]
+#[cfg_attr(test, derive(strum::EnumIter))]
 pub(crate) enum Methods {
   #[strum(serialize = "kong.response.get_status")]
   GetStatus,
@@ -31,63 +34,63 @@ pub(crate) enum Methods {
 
 #[derive(Clone)]
 pub struct ResponsePDK {
-  stream: Stream,
+  transport: Arc<dyn Transport>,
 }
 
 impl ResponsePDK {
-  pub fn new(stream: Stream) -> Self {
-    Self { stream }
+  pub fn new(transport: Arc<dyn Transport>) -> Self {
+    Self { transport }
   }
 
   pub async fn get_status(&self) -> KongResult<usize> {
-    self.stream
+    self.transport
       .ask_int(Methods::GetStatus.into())
       .await
       .map(|port| port as usize)
   }
   
   pub async fn get_header(&self, name: String) -> KongResult<String> {
-    self.stream
+    self.transport
       .ask_string_with_args(Methods::GetHeader.into(), &kong_rs_protos::String { v: name })
       .await
   }
 
   pub async fn get_headers(&self, max_headers: Option<usize>) -> KongResult<HeaderMap> {
     let max_headers = max_headers.unwrap_or(100);
-    let headers: prost_types::Struct = self.stream.ask_message_with_args(
+    let headers: prost_types::Struct = self.transport.ask_message_with_args(
       Methods::GetHeaders.into(),
       &kong_rs_protos::Int { v: max_headers as i32 }
     ).await?;
-    self.stream.unwrap_headers(headers)
+    unwrap_headers(headers)
   }
 
   pub async fn get_source(&self) -> KongResult<String> {
-    self.stream.ask_string(Methods::GetStatus.into()).await
+    self.transport.ask_string(Methods::GetSource.into()).await
   }
 
   pub async fn set_status(&self, status: usize) -> KongResult<()> {
-    self.stream.send_int(Methods::SetStatus.into(), status as i32).await
+    self.transport.send_int(Methods::SetStatus.into(), status as i32).await
   }
 
   pub async fn set_header(&self, name: &str, value: &str) -> KongResult<()> {
-    self.stream.ask(Methods::SetHeader.into(), &Kv {
+    self.transport.ask(Methods::SetHeader.into(), &Kv {
       k: name.to_owned(),
       v: Some(prost_types::Value { kind: Some(prost_types::value::Kind::StringValue(value.to_owned())) })
     }).await
   }
 
   pub async fn add_header(&self, name: &str, value: &str) -> KongResult<()> {
-    self.stream.ask(Methods::AddHeader.into(), &Kv {
+    self.transport.ask(Methods::AddHeader.into(), &Kv {
       k: name.to_owned(),
       v: Some(prost_types::Value { kind: Some(prost_types::value::Kind::StringValue(value.to_owned())) })
     }).await
   }
 
   pub async fn clear_header(&self, name: &str) -> KongResult<()> {
-    self.stream.ask(Methods::ClearHeader.into(), &kong_rs_protos::String { v: name.to_owned() }).await
+    self.transport.ask(Methods::ClearHeader.into(), &kong_rs_protos::String { v: name.to_owned() }).await
   }
 
-  fn headers_to_struct(headers: HeaderMap) -> prost_types::Struct {
+  pub(crate) fn headers_to_struct(headers: HeaderMap) -> prost_types::Struct {
     let mut s = prost_types::Struct { ..Default::default() };
 
     for key in headers.keys() {
@@ -109,11 +112,11 @@ impl ResponsePDK {
 
   pub async fn set_headers(&self, headers: HeaderMap) -> KongResult<()> {
     let s = Self::headers_to_struct(headers);
-    self.stream.ask(Methods::SetHeaders.into(), &s).await
+    self.transport.ask(Methods::SetHeaders.into(), &s).await
   }
 
   pub async fn exit(&self, status: usize, body: Vec<u8>, headers: Option<HeaderMap>) -> KongResult<()> {
     let exit_args = ExitArgs { status: status as i32, body, headers: headers.map(Self::headers_to_struct) };
-    self.stream.ask(Methods::Exit.into(), &exit_args).await
+    self.transport.ask(Methods::Exit.into(), &exit_args).await
   }
 }
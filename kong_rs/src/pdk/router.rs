@@ -1,9 +1,12 @@
+use std::sync::Arc;
+
 use kong_rs_protos::{Route, Service};
 use strum::{EnumString, IntoStaticStr};
 
-use crate::{stream::Stream, KongResult};
+use crate::{transport::Transport, KongResult};
 
 #[derive(Debug, PartialEq, IntoStaticStr, EnumString)]
+#[cfg_attr(test, derive(strum::EnumIter))]
 pub(crate) enum Methods {
   #[strum(serialize = "kong.router.get_route")]
   GetRoute,
@@ -13,19 +16,19 @@ pub(crate) enum Methods {
 
 #[derive(Clone)]
 pub struct RouterPDK {
-  stream: Stream
+  transport: Arc<dyn Transport>
 }
 
 impl RouterPDK {
-  pub fn new(stream: Stream) -> Self {
-    Self { stream }
+  pub fn new(transport: Arc<dyn Transport>) -> Self {
+    Self { transport }
   }
 
   pub async fn get_route(&self) -> KongResult<Route> {
-    self.stream.ask_message(Methods::GetRoute.into()).await
+    self.transport.ask_message(Methods::GetRoute.into()).await
   }
 
   pub async fn get_service(&self) -> KongResult<Service> {
-    self.stream.ask_message(Methods::GetService.into()).await
+    self.transport.ask_message(Methods::GetService.into()).await
   }
 }
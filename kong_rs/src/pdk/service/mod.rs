@@ -1,14 +1,17 @@
+use std::sync::Arc;
+
 use kong_rs_protos::Target;
 use request::ServiceRequestPDK;
 use response::ServiceResponsePDK;
 use strum::{EnumString, IntoStaticStr};
 
-use crate::{stream::Stream, KongResult};
+use crate::{transport::Transport, KongResult};
 
 pub mod request;
 pub mod response;
 
 #[derive(Debug, PartialEq, IntoStaticStr, EnumString)]
+#[cfg_attr(test, derive(strum::EnumIter))]
 pub(crate) enum Methods {
   #[strum(serialize = "kong.service.set_upstream")]
   SetUpstream,
@@ -18,28 +21,28 @@ pub(crate) enum Methods {
 
 #[derive(Clone)]
 pub struct ServicePDK {
-  stream: Stream,
+  transport: Arc<dyn Transport>,
 
   request: ServiceRequestPDK,
   response: ServiceResponsePDK
 }
 
 impl ServicePDK {
-  pub fn new(stream: Stream) -> Self {
+  pub fn new(transport: Arc<dyn Transport>) -> Self {
     Self {
-      stream: stream.clone(),
-      request: ServiceRequestPDK::new(stream.clone()),
-      response: ServiceResponsePDK::new(stream.clone()),
+      transport: transport.clone(),
+      request: ServiceRequestPDK::new(transport.clone()),
+      response: ServiceResponsePDK::new(transport.clone()),
     }
   }
 
   pub async fn set_upstream<A: Into<String>>(&self, addr: A) -> KongResult<bool> {
-    let r: kong_rs_protos::Bool = self.stream.ask_message_with_args(Methods::SetUpstream.into(), &kong_rs_protos::String { v: addr.into() }).await?;
+    let r: kong_rs_protos::Bool = self.transport.ask_message_with_args(Methods::SetUpstream.into(), &kong_rs_protos::String { v: addr.into() }).await?;
     Ok(r.v)
   }
 
   pub async fn set_target<H: Into<String>>(&self, host: H, port: usize) -> KongResult<()> {
-    self.stream.ask_message_with_args(Methods::SetTarget.into(), &Target { host: host.into(), port: port as i32 }).await
+    self.transport.ask_message_with_args(Methods::SetTarget.into(), &Target { host: host.into(), port: port as i32 }).await
   }
 
   pub fn request(&self) -> &ServiceRequestPDK {
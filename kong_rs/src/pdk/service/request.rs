@@ -1,13 +1,14 @@
-use std::collections::BTreeMap;
+use std::{collections::BTreeMap, sync::Arc};
 
 use http::HeaderMap;
 use kong_rs_protos::Kv;
 use prost_types::ListValue;
 use strum::{EnumString, IntoStaticStr};
 
-use crate::{pdk::Value, stream::Stream, KongResult};
+use crate::{pdk::Value, transport::Transport, KongResult};
 
 #[derive(Debug, PartialEq, IntoStaticStr, EnumString)]
+#[cfg_attr(test, derive(strum::EnumIter))]
 pub(crate) enum Methods {
   #[strum(serialize = "kong.service.request.set_scheme")]
   SetScheme,
@@ -33,52 +34,52 @@ pub(crate) enum Methods {
 
 #[derive(Clone)]
 pub struct ServiceRequestPDK {
-  stream: Stream,
+  transport: Arc<dyn Transport>,
 }
 
 impl ServiceRequestPDK {
-  pub fn new(stream: Stream) -> Self {
-    Self { stream }
+  pub fn new(transport: Arc<dyn Transport>) -> Self {
+    Self { transport }
   }
 
   pub async fn set_scheme<S: Into<String>>(&self, scheme: S) -> KongResult<()> {
-    self.stream.send_string(Methods::SetScheme.into(), scheme.into()).await
+    self.transport.send_string(Methods::SetScheme.into(), scheme.into()).await
   }
 
   pub async fn set_path<S: Into<String>>(&self, path: S) -> KongResult<()> {
-    self.stream.send_string(Methods::SetPath.into(), path.into()).await
+    self.transport.send_string(Methods::SetPath.into(), path.into()).await
   }
 
   pub async fn set_raw_query<S: Into<String>>(&self, query: S) -> KongResult<()> {
-    self.stream.send_string(Methods::SetRawQuery.into(), query.into()).await
+    self.transport.send_string(Methods::SetRawQuery.into(), query.into()).await
   }
 
   pub async fn set_method<S: Into<String>>(&self, method: S) -> KongResult<()> {
-    self.stream.send_string(Methods::SetMethod.into(), method.into()).await
+    self.transport.send_string(Methods::SetMethod.into(), method.into()).await
   }
 
   pub async fn set_query<S: Into<String>>(&self, query: BTreeMap<String, Value>) -> KongResult<()> {
-    self.stream.ask_message_with_args(Methods::SetQuery.into(), &prost_types::Struct {
+    self.transport.ask_message_with_args(Methods::SetQuery.into(), &prost_types::Struct {
       fields: query.into_iter().map(|(k, v)| (k, prost_types::Value { kind: Some(v.into()) })).collect()
     }).await
   }
 
   pub async fn set_header(&self, name: &str, value: &str) -> KongResult<()> {
-    self.stream.ask(Methods::SetHeader.into(), &Kv {
+    self.transport.ask(Methods::SetHeader.into(), &Kv {
       k: name.to_owned(),
       v: Some(prost_types::Value { kind: Some(prost_types::value::Kind::StringValue(value.to_owned())) })
     }).await
   }
 
   pub async fn add_header(&self, name: &str, value: &str) -> KongResult<()> {
-    self.stream.ask(Methods::AddHeader.into(), &Kv {
+    self.transport.ask(Methods::AddHeader.into(), &Kv {
       k: name.to_owned(),
       v: Some(prost_types::Value { kind: Some(prost_types::value::Kind::StringValue(value.to_owned())) })
     }).await
   }
 
   pub async fn clear_header(&self, name: &str) -> KongResult<()> {
-    self.stream.ask(Methods::ClearHeader.into(), &kong_rs_protos::String { v: name.to_owned() }).await
+    self.transport.ask(Methods::ClearHeader.into(), &kong_rs_protos::String { v: name.to_owned() }).await
   }
 
   fn headers_to_struct(headers: HeaderMap) -> prost_types::Struct {
@@ -103,11 +104,11 @@ impl ServiceRequestPDK {
 
   pub async fn set_headers(&self, headers: HeaderMap) -> KongResult<()> {
     let s = Self::headers_to_struct(headers);
-    self.stream.ask(Methods::SetHeaders.into(), &s).await
+    self.transport.ask(Methods::SetHeaders.into(), &s).await
   }
 
   pub async fn set_body(&self, body: Vec<u8>) -> KongResult<()> {
     let bs = kong_rs_protos::ByteString { v: body };
-    self.stream.ask(Methods::SetRawBody.into(), &bs).await
+    self.transport.ask(Methods::SetRawBody.into(), &bs).await
   }
 }
@@ -1,9 +1,13 @@
+use std::sync::Arc;
+
 use http::HeaderMap;
 use strum::{EnumString, IntoStaticStr};
 
-use crate::{stream::Stream, KongResult};
+use crate::{pdk::unwrap_headers, transport::Transport, KongResult};
 
 #[derive(Debug, PartialEq, IntoStaticStr, EnumString)]
+#[cfg_attr(test, derive(strum::EnumIter))]
+#[allow(clippy::enum_variant_names)]
 pub(crate) enum Methods {
   #[strum(serialize = "kong.service.response.get_status")]
   GetStatus,
@@ -17,38 +21,38 @@ pub(crate) enum Methods {
 
 #[derive(Clone)]
 pub struct ServiceResponsePDK {
-  stream: Stream,
+  transport: Arc<dyn Transport>,
 }
 
 impl ServiceResponsePDK {
-  pub fn new(stream: Stream) -> Self {
-    Self { stream }
+  pub fn new(transport: Arc<dyn Transport>) -> Self {
+    Self { transport }
   }
 
   pub async fn get_status(&self) -> KongResult<usize> {
-    self.stream
+    self.transport
       .ask_int(Methods::GetStatus.into())
       .await
       .map(|port| port as usize)
   }
 
   pub async fn get_header(&self, name: String) -> KongResult<String> {
-    self.stream
+    self.transport
       .ask_string_with_args(Methods::GetHeader.into(), &kong_rs_protos::String { v: name })
       .await
   }
 
   pub async fn get_headers(&self, max_headers: Option<usize>) -> KongResult<HeaderMap> {
     let max_headers = max_headers.unwrap_or(100);
-    let headers: prost_types::Struct = self.stream.ask_message_with_args(
+    let headers: prost_types::Struct = self.transport.ask_message_with_args(
       Methods::GetHeaders.into(),
       &kong_rs_protos::Int { v: max_headers as i32 }
     ).await?;
-    self.stream.unwrap_headers(headers)
+    unwrap_headers(headers)
   }
 
   pub async fn get_raw_body(&self) -> KongResult<Vec<u8>> {
-    let body: kong_rs_protos::ByteString = self.stream.ask_message(Methods::GetRawBody.into()).await?;
+    let body: kong_rs_protos::ByteString = self.transport.ask_message(Methods::GetRawBody.into()).await?;
     Ok(body.v)
   }
 }
@@ -1,18 +1,30 @@
+use std::{collections::HashMap, future::Future, marker::PhantomData, panic::AssertUnwindSafe, pin::Pin, task::{Context, Poll}};
+
 use http::Response;
 
-use crate::{config::{PluginConfig, PluginConfigFieldVariant as _}, pdk::Pdk};
+use crate::{config::{parse_config, parse_config_value, to_value, PluginConfig, PluginConfigFieldVariant as _, RenderedConfigFieldVariant}, pdk::{ngx::Subsystem, Pdk, StreamPdk}, KongError, KongResult};
 
 pub type PluginResult<T> = std::result::Result<Option<Response<T>>, Response<T>>;
 
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub enum Phase {
-  Access
+  Certificate,
+  Rewrite,
+  Access,
+  Response,
+  Preread,
+  Log
 }
 
-impl Into<&'static str> for Phase {
-  fn into(self) -> &'static str {
-    match self {
+impl From<Phase> for &'static str {
+  fn from(value: Phase) -> Self {
+    match value {
+      Phase::Certificate => "certificate",
+      Phase::Rewrite => "rewrite",
       Phase::Access => "access",
+      Phase::Response => "response",
+      Phase::Preread => "preread",
+      Phase::Log => "log",
     }
   }
 }
@@ -21,14 +33,56 @@ impl TryFrom<&str> for Phase {
   type Error = ();
 
   fn try_from(value: &str) -> std::result::Result<Self, Self::Error> {
-    if value == "access" {
-      Ok(Phase::Access)
-    } else {
-      Err(())
+    match value {
+      "certificate" => Ok(Phase::Certificate),
+      "rewrite" => Ok(Phase::Rewrite),
+      "access" => Ok(Phase::Access),
+      "response" => Ok(Phase::Response),
+      "preread" => Ok(Phase::Preread),
+      "log" => Ok(Phase::Log),
+      _ => Err(())
     }
   }
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Protocol {
+  Grpc,
+  Grpcs,
+  Http,
+  Https,
+  Tcp,
+  Tls,
+  TlsPassthrough,
+  Udp,
+  Ws,
+  Wss
+}
+
+impl Protocol {
+  pub const ALL: &[Protocol] = &[
+    Protocol::Grpc, Protocol::Grpcs, Protocol::Http, Protocol::Https, Protocol::Tcp,
+    Protocol::Tls, Protocol::TlsPassthrough, Protocol::Udp, Protocol::Ws, Protocol::Wss
+  ];
+  pub const HTTP: &[Protocol] = &[Protocol::Grpc, Protocol::Grpcs, Protocol::Http, Protocol::Https];
+  pub const STREAM: &[Protocol] = &[Protocol::Tcp, Protocol::Tls, Protocol::TlsPassthrough, Protocol::Udp];
+}
+
+// Which entities an instance of the plugin may be attached to. A plugin can always be configured globally.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Scopes {
+  pub consumer: bool,
+  pub route: bool,
+  pub service: bool,
+  pub consumer_group: bool
+}
+
+impl Scopes {
+  pub const ALL: Scopes = Scopes { consumer: true, route: true, service: true, consumer_group: true };
+  pub const GLOBAL: Scopes = Scopes { consumer: false, route: false, service: false, consumer_group: false };
+}
+
 #[async_trait::async_trait]
 pub trait Plugin : Send + Sync {
   type Config : PluginConfig;
@@ -38,25 +92,80 @@ pub trait Plugin : Send + Sync {
   const PRIORITY: i32;
 
   const PHASES: &[Phase];
-  
+
+  // The protocols Kong will let the plugin be enabled for. Stream plugins should list Protocol::STREAM.
+  const PROTOCOLS: &[Protocol] = Protocol::HTTP;
+  const SCOPES: Scopes = Scopes::ALL;
+
   fn default_config() -> Self::Config;
-  async fn access(&self, pdk: &Pdk) -> PluginResult<Vec<u8>>;
+
+  // Certificate runs during the TLS handshake, before there is any request to respond to.
+  // The TLS version is available through pdk.ngx().get_tls1_version_str(), but the SNI is not: get_sni() reads an
+  // nginx variable, and those can't be read until the handshake is done.
+  async fn certificate(&self, _pdk: &Pdk) -> KongResult<()> { Ok(()) }
+
+  // Only called for plugins that list Phase::Rewrite in PHASES. Rewrite runs before the
+  // router, so it is only ever invoked for plugins configured globally.
+  async fn rewrite(&self, _pdk: &Pdk) -> PluginResult<Vec<u8>> { Ok(None) }
+  async fn access(&self, _pdk: &Pdk) -> PluginResult<Vec<u8>> { Ok(None) }
+
+  // Listing Phase::Response makes Kong buffer the upstream reply. It can be read through
+  // pdk.service().response(), and its status and headers changed through pdk.response(). Kong's PDK has no call to
+  // replace just the body, so to send a different one return a response (or call pdk.response().exit()), which
+  // replaces the whole reply.
+  async fn response(&self, _pdk: &Pdk) -> PluginResult<Vec<u8>> { Ok(None) }
+
+  // Log runs after the response has been sent to the client, so it cannot exit early.
+  // Use pdk.log().serialized() to get the request log Kong would hand to its own loggers.
+  async fn log(&self, _pdk: &Pdk) -> KongResult<()> { Ok(()) }
+
+  // Stream (L4) subsystem hooks. Kong runs preread and log for TCP/TLS routes, where the
+  // HTTP request and response PDK modules are unavailable. Listing Phase::Preread marks the
+  // plugin as a stream plugin, and its log events are routed by the connection's subsystem.
+  async fn preread(&self, _pdk: &StreamPdk) -> KongResult<()> { Ok(()) }
+  async fn stream_log(&self, _pdk: &StreamPdk) -> KongResult<()> { Ok(()) }
+
+  // Called when Kong closes the instance, or when the plugin server shuts down.
+  async fn shutdown(&self) { }
 }
 
 #[async_trait::async_trait]
 pub trait ErasedPlugin {
-  async fn _call_phase(&self, phase: &Phase, pdk: &Pdk);
+  async fn _call_phase(&self, phase: &Phase, pdk: &Pdk) -> KongResult<()>;
+  async fn _shutdown(&self);
   fn name(&self) -> String;
 }
 
 #[async_trait::async_trait]
 impl<P: Plugin> ErasedPlugin for P {
-  async fn _call_phase(&self, phase: &Phase, pdk: &Pdk) {
+  async fn _call_phase(&self, phase: &Phase, pdk: &Pdk) -> KongResult<()> {
+    let phase_name: &str = phase.clone().into();
     let result = match phase {
-      Phase::Access => self.access(pdk).await,
+      Phase::Rewrite => catch_unwind(self.rewrite(pdk)).await,
+      Phase::Access => catch_unwind(self.access(pdk)).await,
+      Phase::Response => catch_unwind(self.response(pdk)).await,
+      Phase::Certificate | Phase::Preread | Phase::Log => {
+        // These phases have no response to exit with, so errors can only be logged.
+        let result = match catch_unwind(Box::pin(call_non_exiting_phase(self, phase, pdk))).await {
+          Ok(result) => result,
+          Err(panic) => Err(panic),
+        };
+        if let Err(e) = result {
+          pdk.log().err(format!("Error in {} phase: {}", phase_name, e)).await.ok();
+        }
+        return Ok(());
+      },
     };
 
     let result = match result {
+      Ok(result) => result,
+      Err(panic) => {
+        pdk.log().err(format!("Error in {} phase: {}", phase_name, panic)).await.ok();
+        Err(panic.to_internal_error())
+      }
+    };
+
+    match result {
       Ok(Some(ok_response)) => {
         pdk.response().exit(ok_response.status().as_u16() as usize, ok_response.body().to_vec(), Some(ok_response.headers().clone())).await
       },
@@ -64,9 +173,11 @@ impl<P: Plugin> ErasedPlugin for P {
       Err(err_response) => {
         pdk.response().exit(err_response.status().as_u16() as usize, err_response.body().to_vec(), Some(err_response.headers().clone())).await
       },
-    };
+    }
+  }
 
-    result.expect("Unknown error during early exit. Killing the process as a precaution.");
+  async fn _shutdown(&self) {
+    self.shutdown().await
   }
 
   fn name(&self) -> String {
@@ -74,30 +185,128 @@ impl<P: Plugin> ErasedPlugin for P {
   }
 }
 
+// Polls a plugin hook, turning a panic inside it into an error so one misbehaving request cannot take
+// down the connection, or the other instances sharing the process.
+struct CatchUnwind<F>(F);
+
+impl<F: Future + Unpin> Future for CatchUnwind<F> {
+  type Output = Result<F::Output, KongError>;
+
+  fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+    match std::panic::catch_unwind(AssertUnwindSafe(|| Pin::new(&mut self.0).poll(cx))) {
+      Ok(poll) => poll.map(Ok),
+      Err(payload) => {
+        let message = payload.downcast_ref::<&str>().map(|x| x.to_string())
+          .or_else(|| payload.downcast_ref::<String>().cloned())
+          .unwrap_or_else(|| "Unknown panic".to_owned());
+        Poll::Ready(Err(KongError::PanicError(message)))
+      }
+    }
+  }
+}
+
+fn catch_unwind<F: Future + Unpin>(future: F) -> CatchUnwind<F> {
+  CatchUnwind(future)
+}
+
+async fn call_non_exiting_phase<P: Plugin>(plugin: &P, phase: &Phase, pdk: &Pdk) -> KongResult<()> {
+  match phase {
+    Phase::Certificate => plugin.certificate(pdk).await,
+    Phase::Preread => match pdk.ngx().get_subsystem().await? {
+      Subsystem::Stream => plugin.preread(&StreamPdk::from(pdk)).await,
+      subsystem => Err(KongError::InvalidValueError(format!("Preread called in the {:?} subsystem", subsystem))),
+    },
+    Phase::Log => {
+      let subsystem = if P::PHASES.contains(&Phase::Preread) {
+        pdk.ngx().get_subsystem().await?
+      } else {
+        Subsystem::Http
+      };
+      match subsystem {
+        Subsystem::Stream => plugin.stream_log(&StreamPdk::from(pdk)).await,
+        Subsystem::Http => plugin.log(pdk).await,
+      }
+    },
+    _ => Ok(())
+  }
+}
+
 pub struct PluginInfo {
   pub name: String,
   pub phases: Vec<Phase>,
   pub version: String,
   pub priority: i32,
-  pub fields: serde_json::Value,
+  pub fields: Vec<HashMap<String, RenderedConfigFieldVariant>>,
 }
 
 #[async_trait::async_trait]
+#[allow(clippy::new_ret_no_self, clippy::wrong_self_convention)]
 pub trait PluginFactory {
   type Plugin: Plugin + 'static;
-  async fn new(&self, config_data: &str) -> Self::Plugin;
+  async fn new(&self, config_data: &str) -> KongResult<Self::Plugin>;
+}
+
+// A factory that receives the plugin's config already deserialized and validated. Implementing this
+// provides PluginFactory. A config that fails validation never reaches new_with_config: the server prints the
+// errors to stderr and sends Kong an empty reply, which Kong logs as a failure to start the instance.
+#[async_trait::async_trait]
+pub trait TypedPluginFactory {
+  type Plugin: Plugin + 'static;
+  async fn new_with_config(&self, config: <Self::Plugin as Plugin>::Config) -> KongResult<Self::Plugin>;
+}
+
+#[async_trait::async_trait]
+impl<F: TypedPluginFactory + Send + Sync> PluginFactory for F {
+  type Plugin = F::Plugin;
+
+  async fn new(&self, config_data: &str) -> KongResult<Self::Plugin> {
+    let config = parse_config(config_data, &F::Plugin::default_config().render_this())?;
+    self.new_with_config(config).await
+  }
+}
+
+// For plugins that need nothing but their config to be built. Register with ConfigFactory::<P>::new().
+#[async_trait::async_trait]
+pub trait FromConfig: Plugin + Sized {
+  async fn from_config(config: Self::Config) -> KongResult<Self>;
+}
+
+pub struct ConfigFactory<P>(PhantomData<fn() -> P>);
+
+impl<P> ConfigFactory<P> {
+  pub fn new() -> Self {
+    Self(PhantomData)
+  }
+}
+
+impl<P> Default for ConfigFactory<P> {
+  fn default() -> Self {
+    Self::new()
+  }
 }
 
 #[async_trait::async_trait]
+impl<P: FromConfig + 'static> TypedPluginFactory for ConfigFactory<P> {
+  type Plugin = P;
+
+  async fn new_with_config(&self, config: P::Config) -> KongResult<P> {
+    P::from_config(config).await
+  }
+}
+
+#[async_trait::async_trait]
+#[allow(clippy::new_ret_no_self, clippy::wrong_self_convention)]
 pub trait ErasedPluginFactory: Send + Sync {
-  async fn new(&self, config_data: &str) -> Box<dyn ErasedPlugin + Send + Sync>;
+  async fn new(&self, config_data: &str) -> KongResult<Box<dyn ErasedPlugin + Send + Sync>>;
   fn get_info(&self) -> PluginInfo;
+  // Checks a config the way new() would, without creating a plugin.
+  fn validate_config(&self, config: serde_json::Value) -> KongResult<()>;
 }
 
 #[async_trait::async_trait]
 impl<F: PluginFactory + Send + Sync> ErasedPluginFactory for F {
-  async fn new(&self, config_data: &str) -> Box<dyn ErasedPlugin + Send + Sync> {
-    Box::new(<F as PluginFactory>::new(self, config_data).await)
+  async fn new(&self, config_data: &str) -> KongResult<Box<dyn ErasedPlugin + Send + Sync>> {
+    Ok(Box::new(<F as PluginFactory>::new(self, config_data).await?))
   }
 
   fn get_info(&self) -> PluginInfo {
@@ -106,9 +315,53 @@ impl<F: PluginFactory + Send + Sync> ErasedPluginFactory for F {
       phases: F::Plugin::PHASES.to_vec(),
       version: F::Plugin::VERSION.to_owned(),
       priority: F::Plugin::PRIORITY,
-      fields: serde_json::json!([{
-        "config": F::Plugin::default_config().render_this()
-      }])
+      fields: schema_fields::<F::Plugin>()
     }
   }
+
+  fn validate_config(&self, config: serde_json::Value) -> KongResult<()> {
+    parse_config_value::<<F::Plugin as Plugin>::Config>(config, &F::Plugin::default_config().render_this()).map(|_| ())
+  }
+}
+
+// The equivalent of Kong's typedefs.no_consumer and friends, forbidding the plugin from being scoped to an entity.
+fn no_foreign(reference: &str) -> RenderedConfigFieldVariant {
+  RenderedConfigFieldVariant {
+    ty: "foreign".to_owned(),
+    reference: Some(reference.to_owned()),
+    eq: Some(serde_json::Value::Null),
+    ..Default::default()
+  }
+}
+
+fn schema_fields<P: Plugin>() -> Vec<HashMap<String, RenderedConfigFieldVariant>> {
+  let mut fields = vec![];
+
+  let scopes = [
+    ("consumer", "consumers", P::SCOPES.consumer),
+    ("route", "routes", P::SCOPES.route),
+    ("service", "services", P::SCOPES.service),
+    ("consumer_group", "consumer_groups", P::SCOPES.consumer_group)
+  ];
+  for (name, reference, allowed) in scopes {
+    if !allowed {
+      fields.push(HashMap::from([(name.to_owned(), no_foreign(reference))]));
+    }
+  }
+
+  let protocols = RenderedConfigFieldVariant {
+    ty: "set".to_owned(),
+    required: Some(true),
+    default: Some(to_value(P::PROTOCOLS)),
+    elements: Some(Box::new(RenderedConfigFieldVariant {
+      ty: "string".to_owned(),
+      one_of: Some(P::PROTOCOLS.iter().map(to_value).collect()),
+      ..Default::default()
+    })),
+    ..Default::default()
+  };
+  fields.push(HashMap::from([("protocols".to_owned(), protocols)]));
+  fields.push(HashMap::from([("config".to_owned(), P::default_config().render_this())]));
+
+  fields
 }
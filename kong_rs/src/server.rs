@@ -1,16 +1,19 @@
-use std::{collections::HashMap, path::Path, sync::{atomic::AtomicI32, Arc}, time::SystemTime};
+use std::{collections::HashMap, fmt::Display, path::PathBuf, process::ExitCode, sync::{atomic::AtomicI32, Arc}, time::{Duration, SystemTime}};
 
 use kong_rs_protos::{rpc_call::Call, rpc_return::Return, InstanceStatus, PluginInfo, PluginNames, RpcCall, RpcReturn};
-use tokio::{net::UnixListener, sync::RwLock};
+use prost::Message;
+use strum::{EnumString, IntoStaticStr};
+use tokio::{net::UnixListener, signal::unix::{signal, SignalKind}, sync::{watch, RwLock}, task::JoinSet};
 
-use crate::{pdk::Pdk, plugin::{ErasedPlugin, ErasedPluginFactory, Phase}, stream::Stream, KongError, KongResult};
-
-// TODO: At the moment, each plugin server can only host a single plugin (Kong limitation.)
+use crate::{cli::{self, Cli, Command}, config::{self, ConfigError, RenderedConfigFieldVariant}, declarative, multiplex::{self, MultiplexedTransport, Pending}, pdk::Pdk, plugin::{self, ErasedPlugin, ErasedPluginFactory, Phase}, stream::{self, Stream}, trace::{Recorder, ReplayReport, Trace, TraceEntry}, transport::Transport, KongError, KongResult};
 
+// Events clone their instance out of the map, so a long-running event doesn't hold the lock that starting and
+// closing instances need.
+#[derive(Clone)]
 struct Instance {
   id: i32,
   start_time: SystemTime,
-  plugin: Box<dyn ErasedPlugin + Send + Sync>
+  plugin: Arc<dyn ErasedPlugin + Send + Sync>
 }
 
 struct RegisteredFactory {
@@ -21,7 +24,7 @@ struct RegisteredFactory {
 #[derive(Clone, serde::Serialize)]
 struct Schema {
   name: String,
-  fields: serde_json::Value
+  fields: Vec<HashMap<String, RenderedConfigFieldVariant>>
 }
 
 #[derive(Clone, serde::Serialize)]
@@ -34,97 +37,602 @@ struct ServerInfo {
   Phases: Vec<String>
 }
 
+impl ServerInfo {
+  fn new(name: String, info: plugin::PluginInfo) -> Self {
+    Self {
+      Name: name,
+      Priority: info.priority,
+      Version: info.version,
+      Schema: Schema { name: info.name, fields: info.fields },
+      Phases: info.phases.into_iter().map(|x| Into::<&str>::into(x).to_owned()).collect()
+    }
+  }
+}
+
+#[derive(Clone, serde::Serialize)]
+#[allow(non_snake_case)]
+struct DumpInfo {
+  Protocol: &'static str,
+  Plugins: Vec<ServerInfo>
+}
+
+// When a process hosts a single plugin, Kong knows it by the name of the executable rather than the
+// name the plugin was registered with.
+fn find_factory<'a>(factories: &'a HashMap<String, RegisteredFactory>, name: &str) -> Option<&'a RegisteredFactory> {
+  factories.get(name).or_else(|| match factories.len() {
+    1 => factories.values().next(),
+    _ => None
+  })
+}
+
+pub const DEFAULT_KONG_PREFIX: &str = "/usr/local/kong";
+pub const SOCKET_PATH_ENV: &str = "KONG_RS_SOCKET";
+pub const KONG_PREFIX_ENV: &str = "KONG_PREFIX";
+pub const DEFAULT_SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, IntoStaticStr, EnumString)]
+#[strum(serialize_all = "lowercase", ascii_case_insensitive)]
+pub enum LogLevel {
+  Error,
+  Warn,
+  Info,
+  Debug
+}
+
+// How PDK calls are framed on a connection. Kong itself speaks Lockstep, where an event has the connection to itself
+// until it returns. Multiplexed is experimental and specific to kong_rs: it sends PDK calls as PdkCall/PdkReturn
+// messages tagged with their event, so several events can be handled on one connection at once, but its argument
+// encodings are its own and stock Kong can't drive it. Only use it with a peer built for it, such as KongClient.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, IntoStaticStr, EnumString)]
+#[strum(serialize_all = "lowercase", ascii_case_insensitive)]
+pub enum WireMode {
+  #[default]
+  Lockstep,
+  Multiplexed
+}
+
+pub type ErrorHandler = Arc<dyn Fn(&KongError) + Send + Sync>;
+
 pub struct PluginServerBroker {
   plugin_factories: Arc<RwLock<HashMap<String, RegisteredFactory>>>,
+  socket_path: Option<PathBuf>,
+  log_level: LogLevel,
+  shutdown_timeout: Duration,
+  shutdown: watch::Sender<bool>,
+  error_handler: Option<ErrorHandler>,
+  max_frame_size: usize,
+  record_path: Option<PathBuf>,
+  wire_mode: WireMode,
+}
+
+impl Default for PluginServerBroker {
+  fn default() -> Self {
+    Self::new()
+  }
 }
 
 impl PluginServerBroker {
   pub fn new() -> Self {
     Self {
       plugin_factories: Arc::new(RwLock::new(HashMap::new())),
+      socket_path: None,
+      log_level: LogLevel::Warn,
+      shutdown_timeout: DEFAULT_SHUTDOWN_TIMEOUT,
+      shutdown: watch::Sender::new(false),
+      error_handler: None,
+      max_frame_size: stream::DEFAULT_MAX_FRAME_SIZE,
+      record_path: None,
+      wire_mode: WireMode::default(),
     }
   }
 
-  pub async fn register<F: ErasedPluginFactory + 'static>(&self, factory: F) {
-    self.plugin_factories.write().await.insert(factory.get_info().name, RegisteredFactory { time: SystemTime::now(), factory: Box::new(factory) });
+  // Frames from Kong larger than this are rejected, closing the connection they arrived on.
+  pub fn with_max_frame_size(mut self, max_frame_size: usize) -> Self {
+    self.max_frame_size = max_frame_size;
+    self
   }
 
-  pub async fn run<'a, I: Iterator<Item = String>>(&self, mut args: I) -> KongResult<()> {
-    let name = args.next().ok_or(KongError::LaunchError("No plugin name provided".to_owned()))?;
-    let basename = Path::new(&name).file_name().unwrap().to_str().unwrap();
+  // Called with the error that ended a connection to Kong. By default these are logged to stderr.
+  pub fn on_connection_error<F: Fn(&KongError) + Send + Sync + 'static>(mut self, handler: F) -> Self {
+    self.error_handler = Some(Arc::new(handler));
+    self
+  }
+
+  // How long in-flight events are given to finish once the server starts shutting down.
+  pub fn with_shutdown_timeout(mut self, timeout: Duration) -> Self {
+    self.shutdown_timeout = timeout;
+    self
+  }
+
+  // Writes a trace of every RPC frame and PDK call to this file while serving, for -replay. Overridden by -record.
+  // Traces hold requests and responses in full, headers and bodies included, so any credentials, tokens or cookies
+  // they carry end up in the file unredacted. Only record where that's acceptable, and treat the file accordingly.
+  pub fn with_recording<P: Into<PathBuf>>(mut self, path: P) -> Self {
+    self.record_path = Some(path.into());
+    self
+  }
+
+  // Overridden by -wire-mode.
+  pub fn with_wire_mode(mut self, wire_mode: WireMode) -> Self {
+    self.wire_mode = wire_mode;
+    self
+  }
+
+  // Stops a running server as if it had received SIGTERM.
+  pub fn shutdown(&self) {
+    self.shutdown.send_replace(true);
+  }
+
+  // Verbosity of the plugin server's own diagnostics on stderr. Overridden by --log-level. Instances that fail to
+  // start, for example because Kong sent a config that doesn't validate, are reported at any level.
+  pub fn with_log_level(mut self, log_level: LogLevel) -> Self {
+    self.log_level = log_level;
+    self
+  }
+
+  // Takes precedence over the KONG_RS_SOCKET environment variable and the Kong prefix, but not --socket.
+  pub fn with_socket_path<P: Into<PathBuf>>(mut self, path: P) -> Self {
+    self.socket_path = Some(path.into());
+    self
+  }
 
-    if args.any(|x| x == "-dump") {
-      // Dump
-      let factory = self.plugin_factories.read().await;
-      if factory.len() != 1 {
-        Err(KongError::LaunchError("Currently, kong only supports a single plugin per process.".to_owned()))?;
+  // Resolves the socket in order of: with_socket_path, $KONG_RS_SOCKET, then {prefix}/{name}.socket where
+  // the prefix is -kong-prefix, $KONG_PREFIX or /usr/local/kong.
+  pub fn socket_path(&self, name: &str, kong_prefix: Option<&str>) -> PathBuf {
+    if let Some(path) = &self.socket_path {
+      return path.clone();
+    }
+
+    if let Some(path) = std::env::var_os(SOCKET_PATH_ENV).filter(|x| !x.is_empty()) {
+      return PathBuf::from(path);
+    }
+
+    let prefix = kong_prefix.map(PathBuf::from)
+      .or_else(|| std::env::var_os(KONG_PREFIX_ENV).filter(|x| !x.is_empty()).map(PathBuf::from))
+      .unwrap_or_else(|| PathBuf::from(DEFAULT_KONG_PREFIX));
+
+    prefix.join(format!("{}.socket", name))
+  }
+
+  // Fails if the plugin's schema is inconsistent, such as a #[kong(default = ...)] disagreeing with default_config().
+  pub async fn register<F: ErasedPluginFactory + 'static>(&self, factory: F) -> KongResult<()> {
+    let info = factory.get_info();
+    config::check_defaults(&info.fields).map_err(KongError::ConfigError)?;
+    self.plugin_factories.write().await.insert(info.name, RegisteredFactory { time: SystemTime::now(), factory: Box::new(factory) });
+    Ok(())
+  }
+
+  // Validates the config of every plugin hosted here in a declarative (kong.yml) config, returning how many were
+  // checked. Entries are matched by plugin name, or by `program` as in -dump, and other plugins are skipped.
+  pub async fn validate_declarative(&self, source: &str, program: &str) -> KongResult<usize> {
+    let document = declarative::parse(source)?;
+    let factories = self.plugin_factories.read().await;
+
+    let mut checked = 0;
+    let mut errors = vec![];
+    for entry in declarative::plugin_entries(&document) {
+      let factory = match factories.get(&entry.name) {
+        Some(factory) => factory,
+        None if entry.name == program => match find_factory(&factories, program) {
+          Some(factory) => factory,
+          None => continue
+        },
+        None => continue
+      };
+
+      checked += 1;
+      match factory.factory.validate_config(entry.config) {
+        Ok(()) => (),
+        Err(KongError::ConfigError(config_errors)) => errors.extend(config_errors.into_iter().map(|e| match e.path.as_str() {
+          "" => ConfigError::new(entry.path.clone(), e.message),
+          path => ConfigError::new(format!("{}.{}", entry.path, path), e.message)
+        })),
+        Err(e) => errors.push(ConfigError::new(entry.path, e.to_string()))
       }
+    }
+
+    match errors.is_empty() {
+      true => Ok(checked),
+      false => Err(KongError::ConfigError(errors))
+    }
+  }
 
-      let factory = factory.values().next().unwrap();
-      let info = factory.factory.get_info();
+  // Re-runs the events of a recorded trace against fresh instances of the plugins registered here, reporting how
+  // the PDK calls each one makes differ from the recording. Events whose instance start isn't in the trace are
+  // skipped, as there is no config to create the plugin with.
+  pub async fn replay(&self, trace: &Trace) -> KongResult<ReplayReport> {
+    let factories = self.plugin_factories.read().await;
+    let mut instances: HashMap<i32, Box<dyn ErasedPlugin + Send + Sync>> = HashMap::new();
 
-      let infos = format!("{{\"Protocol\":\"ProtoBuf:1\",\"Plugins\":[{}]}}", serde_json::to_string(&ServerInfo {
-        Name: basename.to_owned(),
-        Priority: info.priority as i32,
-        Version: info.version,
-        Schema: Schema { name: info.name, fields: info.fields },
-        Phases: info.phases.into_iter().map(|x| Into::<&str>::into(x).to_owned()).collect()
-      })?);
+    let mut report = ReplayReport::default();
+    for event in trace.events() {
+      let (Some(name), Some(config)) = (&event.plugin, &event.config) else {
+        report.skipped.push(event);
+        continue;
+      };
+      let plugin = match instances.entry(event.instance_id) {
+        std::collections::hash_map::Entry::Occupied(entry) => entry.into_mut(),
+        std::collections::hash_map::Entry::Vacant(entry) => {
+          let factory = find_factory(&factories, name).ok_or_else(|| KongError::LaunchError(format!("No plugin named {} is registered", name)))?;
+          entry.insert(factory.factory.new(config).await?)
+        }
+      };
 
-      println!("{}", infos);
+      let diffs = event.replay(plugin.as_ref()).await?;
+      report.replayed.push((event, diffs));
+    }
 
-      return Ok(())
+    for (_, plugin) in instances {
+      plugin._shutdown().await;
     }
+    Ok(report)
+  }
 
-    let socket_addr = format!("/usr/local/kong/{}.socket", basename);
-    std::fs::remove_file(&socket_addr).ok();   // Remove if exists, otherwise no-op
+  pub async fn run<I: Iterator<Item = String>>(&self, args: I) -> KongResult<()> {
+    let cli = Cli::parse(args).map_err(|e| KongError::LaunchError(e.to_string()))?;
+    self.run_cli(cli).await
+  }
 
-    let listener = UnixListener::bind(&socket_addr)?;
+  // Entry point for plugin server binaries. Usage errors are printed along with the usage text, and
+  // failures are mapped onto the process exit code.
+  pub async fn exec<I: Iterator<Item = String>>(&self, args: I) -> ExitCode {
+    let cli = match Cli::parse(args) {
+      Ok(cli) => cli,
+      Err(e) => {
+        eprintln!("{}", e);
+        return ExitCode::from(cli::EXIT_USAGE);
+      }
+    };
 
-    let server = PluginServer::new(self.plugin_factories.clone());
-    loop {
-      let (stream, _addr) = listener.accept().await?;
-      let server = server.clone();
-      tokio::spawn(async move { server.handle(Stream::new(stream)).await.unwrap() });
+    match self.run_cli(cli).await {
+      Ok(()) => ExitCode::SUCCESS,
+      Err(e) => {
+        eprintln!("{}", e);
+        ExitCode::from(cli::EXIT_FAILURE)
+      }
+    }
+  }
+
+  pub async fn run_cli(&self, cli: Cli) -> KongResult<()> {
+    match cli.command {
+      Command::Help => {
+        println!("{}", Cli::usage(&cli.program));
+        Ok(())
+      },
+      Command::Version => {
+        println!("{} (kong_rs {})", cli.program, env!("CARGO_PKG_VERSION"));
+        let factories = self.plugin_factories.read().await;
+        let mut infos: Vec<_> = factories.values().map(|factory| factory.factory.get_info()).collect();
+        infos.sort_by(|a, b| a.name.cmp(&b.name));
+        for info in infos {
+          println!("{} {}", info.name, info.version);
+        }
+        Ok(())
+      },
+      Command::DumpAllPlugins => {
+        let factories = self.plugin_factories.read().await;
+        let mut plugins: Vec<ServerInfo> = factories.values().map(|factory| {
+          let info = factory.factory.get_info();
+          ServerInfo::new(info.name.clone(), info)
+        }).collect();
+        plugins.sort_by(|a, b| a.Name.cmp(&b.Name));
+
+        println!("{}", serde_json::to_string(&DumpInfo { Protocol: "ProtoBuf:1", Plugins: plugins })?);
+        Ok(())
+      },
+      Command::Validate(ref path) => {
+        let source = std::fs::read_to_string(path)
+          .map_err(|e| KongError::LaunchError(format!("Could not read {}: {}", path.display(), e)))?;
+
+        // A file with no entries for our plugins is most likely the wrong file, or names them differently, so
+        // it fails rather than passing with nothing checked.
+        match self.validate_declarative(&source, &cli.program).await {
+          Ok(0) => Err(KongError::InvalidValueError(format!("{} has no plugin entries for the plugins hosted here", path.display()))),
+          Ok(checked) => {
+            println!("{}: {} plugin config(s) valid", path.display(), checked);
+            Ok(())
+          },
+          Err(KongError::ConfigError(errors)) => {
+            for error in &errors {
+              eprintln!("{}: {}", path.display(), error);
+            }
+            Err(KongError::InvalidValueError(format!("{} error(s) in {}", errors.len(), path.display())))
+          },
+          Err(e) => Err(e)
+        }
+      },
+      Command::Replay(ref path) => {
+        let ReplayReport { replayed, skipped } = self.replay(&Trace::read(path)?).await?;
+
+        for event in &skipped {
+          eprintln!("{}: event {} ({}): skipped, the trace doesn't have the start of instance {}", path.display(), event.event, event.phase, event.instance_id);
+        }
+        if replayed.is_empty() {
+          return Err(KongError::InvalidValueError(format!("None of the {} event(s) in {} could be replayed", skipped.len(), path.display())));
+        }
+
+        let mut diverged = 0;
+        for (event, diffs) in &replayed {
+          if !diffs.is_empty() {
+            diverged += 1;
+          }
+          for diff in diffs {
+            eprintln!("{}: event {} ({} {}): {}", path.display(), event.event, event.plugin.as_deref().unwrap_or(""), event.phase, diff);
+          }
+        }
+
+        match diverged {
+          0 => {
+            println!("{}: {} event(s) replayed, {} skipped", path.display(), replayed.len(), skipped.len());
+            Ok(())
+          },
+          diverged => Err(KongError::InvalidValueError(format!("{} of {} event(s) in {} diverged", diverged, replayed.len(), path.display())))
+        }
+      },
+      Command::Dump | Command::LuaSchema | Command::JsonSchema => {
+        let factories = self.plugin_factories.read().await;
+        let factory = find_factory(&factories, &cli.program).ok_or_else(|| KongError::LaunchError(format!(
+          "No plugin named {} is registered. Use -dump-all-plugins when hosting more than one plugin.", cli.program
+        )))?;
+
+        let info = factory.factory.get_info();
+        match cli.command {
+          Command::LuaSchema => print!("{}", config::lua_schema(&cli.program, &info.fields)),
+          Command::JsonSchema => println!("{}", serde_json::to_string_pretty(&config::json_schema(&cli.program, &info.fields))?),
+          _ => println!("{}", serde_json::to_string(&DumpInfo { Protocol: "ProtoBuf:1", Plugins: vec![ServerInfo::new(cli.program.clone(), info)] })?)
+        }
+        Ok(())
+      },
+      Command::Serve => {
+        let log_level = cli.log_level.unwrap_or(self.log_level);
+        let socket_addr = cli.socket.clone().unwrap_or_else(|| self.socket_path(&cli.program, cli.kong_prefix.as_deref()));
+        std::fs::remove_file(&socket_addr).ok();   // Remove if exists, otherwise no-op
+
+        let listener = UnixListener::bind(&socket_addr)
+          .map_err(|e| KongError::LaunchError(format!("Could not bind {}: {}", socket_addr.display(), e)))?;
+
+        let recorder = cli.record.as_ref().or(self.record_path.as_ref()).map(Recorder::create).transpose()?;
+        let wire_mode = cli.wire_mode.unwrap_or(self.wire_mode);
+        let server = PluginServer::new(self.plugin_factories.clone(), log_level, self.error_handler.clone(), recorder, wire_mode);
+        server.log(LogLevel::Info, format!("Listening on {}", socket_addr.display()));
+        if wire_mode == WireMode::Multiplexed {
+          server.log(LogLevel::Warn, "Using the experimental multiplexed wire mode, which only kong_rs peers speak and stock Kong can't drive");
+        }
+
+        let result = self.serve(&server, listener).await;
+        if let Some(recorder) = &server.recorder {
+          recorder.flush().await;
+        }
+
+        std::fs::remove_file(&socket_addr).ok();
+        server.log(LogLevel::Info, "Shut down");
+        result
+      }
     }
   }
 }
 
+impl PluginServerBroker {
+  async fn serve(&self, server: &PluginServer, listener: UnixListener) -> KongResult<()> {
+    let mut sigterm = signal(SignalKind::terminate())?;
+    let mut sigint = signal(SignalKind::interrupt())?;
+    let mut shutdown = self.shutdown.subscribe();
+    let mut connections = JoinSet::new();
+
+    let result = loop {
+      tokio::select! {
+        accepted = listener.accept() => match accepted {
+          Ok((stream, _addr)) => {
+            server.log(LogLevel::Debug, "Accepted connection");
+            let server = server.clone();
+            let max_frame_size = self.max_frame_size;
+            connections.spawn(async move {
+              if let Err(e) = server.handle(Stream::new(stream).with_max_frame_size(max_frame_size)).await {
+                (server.error_handler)(&e);
+              }
+            });
+          },
+          Err(e) => break Err(e.into()),
+        },
+        Some(_) = connections.join_next(), if !connections.is_empty() => (),
+        _ = sigterm.recv() => break Ok(()),
+        _ = sigint.recv() => break Ok(()),
+        _ = shutdown.wait_for(|x| *x) => break Ok(()),
+      }
+    };
+
+    // Stop accepting, then let connections finish the event they are handling before they close.
+    drop(listener);
+    server.log(LogLevel::Info, format!("Shutting down, waiting up to {:?} for in-flight events", self.shutdown_timeout));
+    server.shutdown.send_replace(true);
+
+    let drained = tokio::time::timeout(self.shutdown_timeout, async {
+      while connections.join_next().await.is_some() { }
+    }).await;
+
+    if drained.is_err() {
+      server.log(LogLevel::Warn, format!("{} connections did not finish in time and were aborted", connections.len()));
+      connections.shutdown().await;
+    }
+
+    server.shutdown_instances().await;
+    result
+  }
+}
+
 #[derive(Clone)]
 pub struct PluginServer {
   plugin_factories: Arc<RwLock<HashMap<String, RegisteredFactory>>>,
   instances: Arc<RwLock<HashMap<i32, Instance>>>,
-  instance_counter: Arc<AtomicI32>
+  instance_counter: Arc<AtomicI32>,
+  log_level: LogLevel,
+  shutdown: Arc<watch::Sender<bool>>,
+  error_handler: ErrorHandler,
+  recorder: Option<Recorder>,
+  wire_mode: WireMode
 }
 
 impl PluginServer {
-  fn new(plugin_factories: Arc<RwLock<HashMap<String, RegisteredFactory>>>) -> PluginServer {
+  fn new(plugin_factories: Arc<RwLock<HashMap<String, RegisteredFactory>>>, log_level: LogLevel, error_handler: Option<ErrorHandler>, recorder: Option<Recorder>, wire_mode: WireMode) -> PluginServer {
+    let error_handler = error_handler.unwrap_or_else(|| Arc::new(move |e: &KongError| {
+      if LogLevel::Error <= log_level {
+        eprintln!("[kong_rs] [error] Connection closed with an error: {}", e);
+      }
+    }));
+
     Self {
       plugin_factories,
       instances: Arc::new(RwLock::new(HashMap::new())),
-      instance_counter: Arc::new(AtomicI32::new(0))
+      instance_counter: Arc::new(AtomicI32::new(0)),
+      log_level,
+      shutdown: Arc::new(watch::Sender::new(false)),
+      error_handler,
+      recorder,
+      wire_mode
     }
   }
 
-  pub async fn register<F: ErasedPluginFactory + 'static>(&mut self, factory: F) {
-    self.plugin_factories.write().await.insert(factory.get_info().name, RegisteredFactory { time: SystemTime::now(), factory: Box::new(factory) });
+  async fn shutdown_instances(&self) {
+    let instances: Vec<Instance> = self.instances.write().await.drain().map(|(_, inst)| inst).collect();
+    for inst in instances {
+      inst.plugin._shutdown().await;
+    }
+  }
+
+  fn log<M: Display>(&self, level: LogLevel, message: M) {
+    if level <= self.log_level {
+      eprintln!("[kong_rs] [{}] {}", Into::<&str>::into(level), message);
+    }
+  }
+
+  pub async fn register<F: ErasedPluginFactory + 'static>(&mut self, factory: F) -> KongResult<()> {
+    let info = factory.get_info();
+    config::check_defaults(&info.fields).map_err(KongError::ConfigError)?;
+    self.plugin_factories.write().await.insert(info.name, RegisteredFactory { time: SystemTime::now(), factory: Box::new(factory) });
+    Ok(())
   }
 
   pub async fn handle(&self, stream: Stream) -> KongResult<()> {
+    if self.wire_mode == WireMode::Multiplexed {
+      return self.handle_multiplexed(stream).await;
+    }
+
+    let mut shutdown = self.shutdown.subscribe();
+    let connection = self.recorder.as_ref().map_or(0, Recorder::next_id);
     loop {
-      let req = stream.read_message::<RpcCall>().await?;
-      
-      if let Some(response) = self.handle_call(stream.clone(), req).await? {
-        stream.write_message(&response).await?;
-      } else {
-        stream.write_frame(&[]).await?;
+      // Shutdown only interrupts a connection between calls, so an in-flight event always runs to completion.
+      let req = tokio::select! {
+        req = stream.read_message::<RpcCall>() => match req {
+          // Kong closing the connection between calls is the normal way for it to end.
+          Err(KongError::ConnectionClosed) => return Ok(()),
+          req => req?
+        },
+        _ = shutdown.wait_for(|x| *x) => return Ok(()),
+      };
+
+      self.respond(&stream, connection, req, Arc::new(stream.clone())).await?;
+    }
+  }
+
+  // Reads frames for the whole connection, handing each PdkReturn to the call waiting on it and running every RpcCall
+  // as a task of its own, so a slow event doesn't hold up the calls behind it.
+  async fn handle_multiplexed(&self, stream: Stream) -> KongResult<()> {
+    let mut shutdown = self.shutdown.subscribe();
+    let connection = self.recorder.as_ref().map_or(0, Recorder::next_id);
+    let pending = Pending::default();
+    let mut calls = JoinSet::new();
+    // The sequence each task is answering, to reply for it if the task panics.
+    let mut sequences = HashMap::new();
+    let mut shutting_down = false;
+
+    loop {
+      tokio::select! {
+        frame = stream.read_frame() => {
+          let frame = match frame {
+            Err(KongError::ConnectionClosed) => return Ok(()),
+            frame => frame?
+          };
+
+          if let Some(ret) = multiplex::pdk_return(&frame) {
+            let (event_id, sequence) = (ret.event_id, ret.sequence);
+            if !pending.resolve(ret) {
+              self.log(LogLevel::Debug, format!("Dropped a PdkReturn for event {} call {} that nothing was waiting on", event_id, sequence));
+            }
+            continue;
+          }
+
+          // Once shutting down, the connection only stays open for the calls already in flight.
+          if shutting_down {
+            continue;
+          }
+
+          let req = RpcCall::decode(&*frame)?;
+          if req.sequence == 0 {
+            return Err(KongError::InvalidValueError("Calls on a multiplexed connection need a non-zero sequence".to_owned()));
+          }
+
+          let server = self.clone();
+          let stream = stream.clone();
+          let sequence = req.sequence;
+          let transport = Arc::new(MultiplexedTransport::new(stream.clone(), pending.clone(), sequence));
+          let task = calls.spawn(async move {
+            if let Err(e) = server.respond(&stream, connection, req, transport).await {
+              server.fail_call(&stream, sequence, e).await;
+            }
+          });
+          sequences.insert(task.id(), sequence);
+        },
+        Some(result) = calls.join_next_with_id(), if !calls.is_empty() => {
+          // A failed call only fails itself. Kong gets an empty reply for it, and the connection carries on.
+          match result {
+            Ok((id, ())) => { sequences.remove(&id); },
+            Err(e) => if let Some(sequence) = sequences.remove(&e.id()) {
+              let (server, stream, error) = (self.clone(), stream.clone(), KongError::PanicError(e.to_string()));
+              calls.spawn(async move { server.fail_call(&stream, sequence, error).await });
+            }
+          }
+          if shutting_down && calls.is_empty() {
+            return Ok(());
+          }
+        },
+        _ = shutdown.wait_for(|x| *x), if !shutting_down => {
+          shutting_down = true;
+          if calls.is_empty() {
+            return Ok(());
+          }
+        },
       }
     }
   }
+
+  // Answers a call that failed on a multiplexed connection with an empty reply, which is as close as RpcReturn comes
+  // to an error, so Kong isn't left waiting on it.
+  async fn fail_call(&self, stream: &Stream, sequence: i64, error: KongError) {
+    (self.error_handler)(&error);
+    stream.write_message(&RpcReturn { sequence, r#return: None }).await.ok();
+  }
+
+  // Handles a call and writes the reply. Nothing to return is an empty frame, except on a multiplexed connection where
+  // every reply has to carry its sequence.
+  async fn respond(&self, stream: &Stream, connection: u64, request: RpcCall, transport: Arc<dyn Transport>) -> KongResult<()> {
+    let call = self.recorder.as_ref().map(|_| request.encode_to_vec());
+    let sequence = request.sequence;
+    let reply = match self.handle_call(transport, request).await? {
+      Some(reply) => reply.encode_to_vec(),
+      None if self.wire_mode == WireMode::Multiplexed => RpcReturn { sequence, r#return: None }.encode_to_vec(),
+      None => vec![]
+    };
+    stream.write_frame(&reply).await?;
+
+    if let (Some(recorder), Some(call)) = (&self.recorder, call) {
+      recorder.record(TraceEntry::Rpc { connection, call, reply });
+    }
+    Ok(())
+  }
 }
 
 impl PluginServer {
-  async fn handle_call(&self, stream: Stream, request: RpcCall) -> KongResult<Option<RpcReturn>> {
+  async fn handle_call(&self, transport: Arc<dyn Transport>, request: RpcCall) -> KongResult<Option<RpcReturn>> {
     let resp = match request.call {
       Some(Call::CmdGetPluginNames(_)) => {
         Some(Return::PluginNames(PluginNames {
@@ -133,8 +641,7 @@ impl PluginServer {
       },
       Some(Call::CmdGetPluginInfo(get_info)) => {
         let factories = self.plugin_factories.read().await;
-        let factory = factories.get(&get_info.name);
-        if let Some(factory) = factory {
+        if let Some(factory) = find_factory(&factories, &get_info.name) {
           let info = factory.factory.get_info();
           let schema = Schema { name: info.name.clone(), fields: info.fields };
           Some(Return::PluginInfo(PluginInfo {
@@ -152,18 +659,23 @@ impl PluginServer {
       },
       Some(Call::CmdStartInstance(inst_req)) => {
         let factories = self.plugin_factories.read().await;
-        // let factory = factories.get(&inst_req.name);
-
-        // TODO: We can only have one plugin per pluginserver, and it inherits the name of the process.
-        //       In such case, the first factory is the only one...
-        // TODO: When Kong starts to support multiple plugins, deal with it then.
-        let factory = factories.values().next();
-        if let Some(factory) = factory {
-          let plugin = factory.factory.new(std::str::from_utf8(&inst_req.config)?).await;
+        // Kong only sees a failed start as an empty reply, which it reports as the instance not existing, so the
+        // reason is printed whatever the log level.
+        let plugin = match find_factory(&factories, &inst_req.name) {
+          Some(factory) => factory.factory.new(std::str::from_utf8(&inst_req.config)?).await.map_err(|e| {
+            eprintln!("[kong_rs] [error] Could not start an instance of {}: {}", inst_req.name, e);
+          }).ok(),
+          None => {
+            eprintln!("[kong_rs] [error] Could not start an instance of {}: no plugin with that name is registered", inst_req.name);
+            None
+          }
+        };
+
+        if let Some(plugin) = plugin {
           let inst = Instance {
             id: self.instance_counter.fetch_add(1, std::sync::atomic::Ordering::Relaxed),
             start_time: SystemTime::now(),
-            plugin
+            plugin: plugin.into()
           };
 
           let ret = Return::InstanceStatus(InstanceStatus {
@@ -173,6 +685,14 @@ impl PluginServer {
             started_at: inst.start_time.duration_since(SystemTime::UNIX_EPOCH).unwrap().as_secs() as i64,
           });
 
+          if let Some(recorder) = &self.recorder {
+            recorder.record(TraceEntry::Instance {
+              instance_id: inst.id,
+              name: inst.plugin.name(),
+              config: String::from_utf8_lossy(&inst_req.config).into_owned()
+            });
+          }
+
           self.instances.write().await.insert(inst.id, inst);
 
           Some(ret)
@@ -193,16 +713,22 @@ impl PluginServer {
         })
       },
       Some(Call::CmdCloseInstance(close_req)) => {
-        self.instances.write().await.remove(&close_req.instance_id);
+        let inst = self.instances.write().await.remove(&close_req.instance_id);
+        if let Some(inst) = inst {
+          inst.plugin._shutdown().await;
+        }
         None
       },
       Some(Call::CmdHandleEvent(event)) => {
         let phase = Phase::try_from(event.event_name.as_str()).map_err(|_| KongError::InvalidValueError("Cannot decode phase from event name".to_owned()))?;
-        let instances = self.instances.read().await;
-        let inst = instances.get(&event.instance_id);
+        let inst = self.instances.read().await.get(&event.instance_id).cloned();
 
         if let Some(inst) = inst {
-          inst.plugin._call_phase(&phase, &Pdk::new(stream.clone())).await;
+          let transport = match &self.recorder {
+            Some(recorder) => recorder.event(inst.id, &phase, transport),
+            None => transport
+          };
+          inst.plugin._call_phase(&phase, &Pdk::new(transport)).await?;
 
           Some(Return::InstanceStatus(InstanceStatus {
             name: inst.plugin.name(),
@@ -1,196 +1,100 @@
-use std::{str::FromStr, sync::Arc};
+use std::{fmt::Display, sync::Arc};
 
-use http::{HeaderMap, HeaderName, HeaderValue};
 use prost::Message;
+use tokio::sync::Mutex;
 
-use crate::KongResult;
+use crate::{KongError, KongResult};
 
 // From https://github.com/jgramoll/kong-rust-pdk, slightly adjusted.
 
-#[derive(Clone)]
-pub struct Stream(pub Arc<tokio::net::UnixStream>);
+pub const DEFAULT_MAX_FRAME_SIZE: usize = 64 * 1024 * 1024;
+const MIN_READ_SIZE: usize = 8 * 1024;
 
-impl Stream {
-  pub fn new(stream: tokio::net::UnixStream) -> Self {
-    Self(Arc::new(stream))
-  }
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FrameError {
+  Oversized { len: usize, max: usize },
+  Truncated { expected: usize, received: usize }
 }
 
-impl Stream {
-  pub async fn write_method(&self, method: &str) -> KongResult<usize> {
-    let res1 = self.write_frame(method.as_bytes()).await?;
-    // empty frame for 0 args
-    let res2 = self.write_frame(&[]).await?;
-
-    Ok(res1 + res2)
-  }
-
-  async fn write_method_with_args<T: Message>(
-    &self,
-    method: &str,
-    args: &T,
-  ) -> KongResult<usize> {
-    let res1 = self.write_frame(method.as_bytes()).await?;
-    let res2 = self.write_frame(&args.encode_to_vec()).await?;
-
-    Ok(res1 + res2)
-  }
-
-  pub async fn ask<T: prost::Message>(&self, method: &str, args: &T) -> KongResult<()> {
-    self.write_method_with_args(method, args).await?;
-    self.read_frame().await?;
-    Ok(())
-  }
-
-  pub async fn ask_message_with_args<T: prost::Message, R: prost::Message + Default>(
-    &self,
-    method: &str,
-    args: &T,
-  ) -> KongResult<R> {
-    self.write_method_with_args(method, args).await?;
-    let out = self.read_message::<R>().await?;
-    Ok(out)
-  }
-
-  pub async fn ask_message<R: prost::Message + Default>(
-    &self,
-    method: &str,
-  ) -> KongResult<R> {
-    self.write_method(method).await?;
-    let out = self.read_message::<R>().await?;
-    Ok(out)
-  }
-
-  #[allow(dead_code)]
-  pub async fn send_string(&self, method: &str, v: String) -> KongResult<()> {
-    self.ask(method, &kong_rs_protos::String { v }).await
-  }
-
-  pub async fn send_int(&self, method: &str, v: i32) -> KongResult<()> {
-    self.ask(method, &kong_rs_protos::Int { v }).await
-  }
-
-  pub async fn ask_string(&self, method: &str) -> KongResult<String> {
-    self.write_method(method).await?;
-    let s = self.read_message::<kong_rs_protos::String>().await?;
-    Ok(s.v)
-  }
-
-  pub async fn ask_string_with_args<T: prost::Message>(
-    &self,
-    method: &str,
-    args: &T,
-  ) -> KongResult<String> {
-    self.write_method_with_args(method, args).await?;
-    let s = self.read_message::<kong_rs_protos::String>().await?;
-    Ok(s.v)
-  }
-
-  pub async fn ask_int(&self, method: &str) -> KongResult<i32> {
-    self.write_method(method).await?;
-    let s = self.read_message::<kong_rs_protos::Int>().await?;
-    Ok(s.v)
-  }
-
-  #[allow(dead_code)]
-  pub async fn ask_int_with_args<T: prost::Message>(
-    &self,
-    method: &str,
-    args: &T,
-  ) -> KongResult<i32> {
-    self.write_method_with_args(method, args).await?;
-    let s = self.read_message::<kong_rs_protos::Int>().await?;
-    Ok(s.v)
-  }
-
-  pub async fn ask_number(&self, method: &str) -> KongResult<f64> {
-    self.write_method(method).await?;
-    let s = self.read_message::<kong_rs_protos::Number>().await?;
-    Ok(s.v)
-  }
-
-  fn unwrap_single_header(name: &HeaderName, kind: prost_types::value::Kind, ret: &mut HeaderMap) -> KongResult<()> {
-    match kind {
-      prost_types::value::Kind::NullValue(_) => (),
-      prost_types::value::Kind::NumberValue(n) => {
-        ret.append(name, HeaderValue::from_str(&n.to_string())?);
-      }
-      prost_types::value::Kind::StringValue(str) => {
-        ret.append(name, HeaderValue::from_str(&str)?);
-      },
-      prost_types::value::Kind::BoolValue(b) => {
-        ret.append(name, HeaderValue::from_str(&b.to_string())?);
-      }
-      prost_types::value::Kind::StructValue(_) => {
-        // TODO: How do?
-      },
-      prost_types::value::Kind::ListValue(l) => {
-        for v in l.values {
-          if let Some(kind) = v.kind {
-            Self::unwrap_single_header(name, kind, ret)?;
-          }
-        }
-      }
+impl Display for FrameError {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    match self {
+      FrameError::Oversized { len, max } => write!(f, "frame of {} bytes exceeds the maximum of {} bytes", len, max),
+      FrameError::Truncated { expected, received } => write!(f, "connection closed after {} of {} bytes", received, expected),
     }
-    Ok(())
   }
+}
 
-  pub fn unwrap_headers(&self, st: prost_types::Struct) -> KongResult<HeaderMap> {
-    let mut ret = HeaderMap::default();
+// Frames are a little-endian u32 length followed by that many bytes. Reads go through a buffer that
+// outlives any single call, so a read that is cancelled part way through a frame loses nothing.
+#[derive(Clone)]
+pub struct Stream {
+  socket: Arc<tokio::net::UnixStream>,
+  read_buffer: Arc<Mutex<Vec<u8>>>,
+  write_lock: Arc<Mutex<()>>,
+  max_frame_size: usize
+}
 
-    for (name, v) in st.fields {
-      if let Some(kind) = v.kind {
-        let name = HeaderName::from_str(&name).unwrap();
-        Self::unwrap_single_header(&name, kind, &mut ret)?;
-      }
+impl Stream {
+  pub fn new(stream: tokio::net::UnixStream) -> Self {
+    Self {
+      socket: Arc::new(stream),
+      read_buffer: Arc::new(Mutex::new(Vec::new())),
+      write_lock: Arc::new(Mutex::new(())),
+      max_frame_size: DEFAULT_MAX_FRAME_SIZE
     }
+  }
 
-    Ok(ret)
+  pub fn with_max_frame_size(mut self, max_frame_size: usize) -> Self {
+    self.max_frame_size = max_frame_size;
+    self
   }
 }
 
 impl Stream {
-  // read bytes from stream to given array
-  pub async fn read(&self, mut out: &mut [u8]) -> KongResult<usize> {
-    loop {
-      self.0.readable().await?;
-      match self.0.try_read(&mut out) {
-        Ok(0) => return Err(std::io::Error::from(std::io::ErrorKind::ConnectionAborted).into()),
-        Ok(n) => {
-          if n > 0 {
-            break Ok(n);
-          }
-        }
-        Err(ref e) if e.kind() == tokio::io::ErrorKind::WouldBlock => {
-          continue;
-        }
-        Err(e) => {
-          break Err(e.into());
-        }
+  // Reads from the socket until the buffer holds at least `len` bytes. Returns false if the connection
+  // closed first.
+  async fn fill(&self, buffer: &mut Vec<u8>, len: usize) -> KongResult<bool> {
+    while buffer.len() < len {
+      self.socket.readable().await?;
+
+      let start = buffer.len();
+      buffer.resize(start + (len - start).max(MIN_READ_SIZE), 0);
+      let result = self.socket.try_read(&mut buffer[start..]);
+      buffer.truncate(start + result.as_ref().map_or(0, |n| *n));
+
+      match result {
+        Ok(0) => return Ok(false),
+        Ok(_) => (),
+        Err(ref e) if e.kind() == tokio::io::ErrorKind::WouldBlock => continue,
+        Err(e) => return Err(e.into()),
       }
     }
-  }
-
-  async fn read_i32(&self) -> KongResult<i32> {
-    let mut bytes = [0; 4];
-    let len = self.read(&mut bytes).await?;
-    debug_assert!(len == 4);
-    Ok(i32::from_le_bytes(bytes))
+    Ok(true)
   }
 
   pub async fn read_frame(&self) -> KongResult<Vec<u8>> {
-    // read len + msg
-    let len = self.read_i32().await? as usize;
-    if len == 0 {
-        return Ok(vec![]);
+    let mut buffer = self.read_buffer.lock().await;
+
+    if !self.fill(&mut buffer, 4).await? {
+      return match buffer.len() {
+        0 => Err(KongError::ConnectionClosed),
+        received => Err(FrameError::Truncated { expected: 4, received }.into())
+      };
     }
 
-    let mut buf = vec![0; len];
-    let read_len = self.read(&mut buf).await?;
-    debug_assert_eq!(read_len, len);
+    let len = u32::from_le_bytes([buffer[0], buffer[1], buffer[2], buffer[3]]) as usize;
+    if len > self.max_frame_size {
+      return Err(FrameError::Oversized { len, max: self.max_frame_size }.into());
+    }
 
-    let (bytes, _) = buf.split_at(read_len);
-    Ok(bytes.to_vec())
+    if !self.fill(&mut buffer, 4 + len).await? {
+      return Err(FrameError::Truncated { expected: len, received: buffer.len() - 4 }.into());
+    }
+
+    let frame = buffer[4..4 + len].to_vec();
+    buffer.drain(..4 + len);
+    Ok(frame)
   }
 
   pub async fn read_message<T: Message + Default>(&self) -> KongResult<T> {
@@ -201,34 +105,36 @@ impl Stream {
 }
 
 impl Stream {
-  async fn write(&self, buf: &[u8]) -> KongResult<usize> {
-    loop {
-      self.0.writable().await?;
-
-      match self.0.try_write(buf) {
-        Ok(n) => {
-          break Ok(n);
-        }
-        Err(ref e) if e.kind() == tokio::io::ErrorKind::WouldBlock => {
-          continue;
-        }
-        Err(e) => {
-          break Err(e.into());
-        }
+  async fn write_all(&self, buf: &[u8]) -> KongResult<()> {
+    let mut written = 0;
+    while written < buf.len() {
+      self.socket.writable().await?;
+
+      match self.socket.try_write(&buf[written..]) {
+        Ok(n) => written += n,
+        Err(ref e) if e.kind() == tokio::io::ErrorKind::WouldBlock => continue,
+        Err(e) => return Err(e.into()),
       }
     }
+    Ok(())
   }
 
   pub async fn write_frame(&self, buf: &[u8]) -> KongResult<usize> {
-    // send len + msg
-    let len = buf.len();
-    let res1 = self.write(&(len as u32).to_le_bytes()).await?;
-    let res2 = self.write(buf).await?;
+    if buf.len() > self.max_frame_size {
+      return Err(FrameError::Oversized { len: buf.len(), max: self.max_frame_size }.into());
+    }
+
+    // send len + msg as a single write, so frames from different tasks can never interleave
+    let mut frame = Vec::with_capacity(4 + buf.len());
+    frame.extend_from_slice(&(buf.len() as u32).to_le_bytes());
+    frame.extend_from_slice(buf);
 
-    Ok(res1 + res2)
+    let _guard = self.write_lock.lock().await;
+    self.write_all(&frame).await?;
+    Ok(frame.len())
   }
 
   pub async fn write_message<T: Message>(&self, msg: &T) -> KongResult<usize> {
     self.write_frame(&msg.encode_to_vec()).await
   }
-}
\ No newline at end of file
+}
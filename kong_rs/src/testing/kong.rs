@@ -0,0 +1,376 @@
+use std::{collections::HashMap, path::Path, sync::{atomic::{AtomicI64, Ordering}, Arc}, time::Duration};
+
+use kong_rs_protos::{
+  pdk_arg::Data, rpc_call::Call, rpc_return::Return, CmdCloseInstance, CmdGetInstanceStatus, CmdGetPluginInfo,
+  CmdGetPluginNames, CmdHandleEvent, CmdStartInstance, InstanceStatus, PdkArg, PdkCall, PdkReturn, PluginInfo, RpcCall, RpcReturn
+};
+use prost::Message;
+use tokio::{net::UnixStream, sync::{mpsc, Mutex}, task::JoinHandle};
+
+use crate::{multiplex, stream::Stream, transport::Transport, KongError, KongResult, Phase};
+
+// How long connect() keeps retrying while the plugin server is still starting up.
+pub const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+
+// Plays Kong's side of the plugin socket, for end-to-end tests of a plugin server. PDK calls made while handling
+// an event are answered by a Transport, such as a MockSession from MockKong::start.
+pub struct KongClient {
+  stream: Stream,
+  sequence: AtomicI64,
+  // Held for a whole exchange, as the lockstep protocol only allows one call in flight per connection.
+  call_lock: Mutex<()>,
+  demux: Option<Demux>
+}
+
+impl KongClient {
+  pub fn new(stream: Stream) -> Self {
+    Self { stream, sequence: AtomicI64::new(1), call_lock: Mutex::new(()), demux: None }
+  }
+
+  // Speaks the multiplexed wire mode, for a server using WireMode::Multiplexed. Calls can be made concurrently, for
+  // example several handle_events at once.
+  pub fn multiplexed(stream: Stream) -> Self {
+    let routes: Routes = Arc::new(std::sync::Mutex::new(Some(HashMap::new())));
+    let reader = tokio::spawn(demultiplex(stream.clone(), routes.clone()));
+    Self { stream, sequence: AtomicI64::new(1), call_lock: Mutex::new(()), demux: Some(Demux { routes, reader }) }
+  }
+
+  pub async fn connect<P: AsRef<Path>>(path: P) -> KongResult<Self> {
+    Ok(Self::new(connect_socket(path.as_ref()).await?))
+  }
+
+  pub async fn connect_multiplexed<P: AsRef<Path>>(path: P) -> KongResult<Self> {
+    Ok(Self::multiplexed(connect_socket(path.as_ref()).await?))
+  }
+
+  pub async fn get_plugin_names(&self) -> KongResult<Vec<String>> {
+    match self.rpc(Call::CmdGetPluginNames(CmdGetPluginNames {}), None).await? {
+      Some(Return::PluginNames(names)) => Ok(names.names),
+      other => Err(unexpected("CmdGetPluginNames", other))
+    }
+  }
+
+  pub async fn get_plugin_info(&self, name: &str) -> KongResult<Option<PluginInfo>> {
+    match self.rpc(Call::CmdGetPluginInfo(CmdGetPluginInfo { name: name.to_owned() }), None).await? {
+      Some(Return::PluginInfo(info)) => Ok(Some(info)),
+      None => Ok(None),
+      other => Err(unexpected("CmdGetPluginInfo", other))
+    }
+  }
+
+  // Returns None if the server couldn't start the instance, for example because the config is invalid.
+  pub async fn start_instance(&self, name: &str, config: &str) -> KongResult<Option<InstanceStatus>> {
+    let call = Call::CmdStartInstance(CmdStartInstance { name: name.to_owned(), config: config.as_bytes().to_vec() });
+    self.instance_status("CmdStartInstance", call, None).await
+  }
+
+  pub async fn get_instance_status(&self, instance_id: i32) -> KongResult<Option<InstanceStatus>> {
+    self.instance_status("CmdGetInstanceStatus", Call::CmdGetInstanceStatus(CmdGetInstanceStatus { instance_id }), None).await
+  }
+
+  pub async fn close_instance(&self, instance_id: i32) -> KongResult<()> {
+    self.rpc(Call::CmdCloseInstance(CmdCloseInstance { instance_id }), None).await?;
+    Ok(())
+  }
+
+  pub async fn handle_event(&self, instance_id: i32, phase: Phase, pdk: &dyn Transport) -> KongResult<Option<InstanceStatus>> {
+    let call = Call::CmdHandleEvent(CmdHandleEvent { instance_id, event_name: Into::<&str>::into(phase).to_owned() });
+    self.instance_status("CmdHandleEvent", call, Some(pdk)).await
+  }
+
+  async fn instance_status(&self, name: &str, call: Call, pdk: Option<&dyn Transport>) -> KongResult<Option<InstanceStatus>> {
+    match self.rpc(call, pdk).await? {
+      Some(Return::InstanceStatus(status)) => Ok(Some(status)),
+      None => Ok(None),
+      other => Err(unexpected(name, other))
+    }
+  }
+
+  async fn rpc(&self, call: Call, pdk: Option<&dyn Transport>) -> KongResult<Option<Return>> {
+    if let Some(demux) = &self.demux {
+      return self.rpc_multiplexed(demux, call, pdk).await;
+    }
+
+    let _guard = self.call_lock.lock().await;
+    let sequence = self.sequence.fetch_add(1, Ordering::Relaxed);
+    self.stream.write_message(&RpcCall { sequence, call: Some(call) }).await?;
+
+    loop {
+      let frame = self.stream.read_frame().await?;
+
+      // While an event is handled the server sends PDK calls, each a method name frame followed by an args frame.
+      // An encoded RpcReturn can never start with "kong.", so anything else is the reply.
+      if frame.starts_with(b"kong.") {
+        let method = std::str::from_utf8(&frame)?;
+        let args = self.stream.read_frame().await?;
+        let pdk = pdk.ok_or_else(|| KongError::InvalidValueError(format!("PDK call to {} outside of an event", method)))?;
+        self.stream.write_frame(&pdk.call(method, &args).await?).await?;
+        continue;
+      }
+
+      let reply = RpcReturn::decode(&*frame)?;
+      return match reply.r#return {
+        Some(_) if reply.sequence != sequence => Err(KongError::InvalidValueError(format!(
+          "Reply to call {} had sequence {}", sequence, reply.sequence
+        ))),
+        r => Ok(r)
+      };
+    }
+  }
+
+  async fn rpc_multiplexed(&self, demux: &Demux, call: Call, pdk: Option<&dyn Transport>) -> KongResult<Option<Return>> {
+    let sequence = self.sequence.fetch_add(1, Ordering::Relaxed);
+    let (tx, mut rx) = mpsc::unbounded_channel();
+    demux.routes.lock().unwrap().as_mut().ok_or(KongError::ConnectionClosed)?.insert(sequence, tx);
+
+    let result = async {
+      self.stream.write_message(&RpcCall { sequence, call: Some(call) }).await?;
+      loop {
+        match rx.recv().await.ok_or(KongError::ConnectionClosed)? {
+          Incoming::Pdk(call) => {
+            // Failures are sent back as an error arg, which is how Kong reports a PDK call that raised. Every call is
+            // answered, as the plugin server waits on the reply.
+            let arg = match pdk {
+              Some(pdk) => answer(pdk, &call).await,
+              None => Err(KongError::InvalidValueError(format!("PDK call to {} outside of an event", call.cmd)))
+            }.unwrap_or_else(|e| Some(PdkArg { data: Some(Data::Error(e.to_string())) }));
+            self.stream.write_message(&PdkReturn { sequence: call.sequence, event_id: call.event_id, cmd: call.cmd, arg }).await?;
+          },
+          Incoming::Return(reply) => return Ok(reply.r#return)
+        }
+      }
+    }.await;
+
+    if let Some(routes) = demux.routes.lock().unwrap().as_mut() {
+      routes.remove(&sequence);
+    }
+    result
+  }
+}
+
+async fn connect_socket(path: &Path) -> KongResult<Stream> {
+  let started = tokio::time::Instant::now();
+  loop {
+    match UnixStream::connect(path).await {
+      Ok(socket) => return Ok(Stream::new(socket)),
+      Err(_) if started.elapsed() < CONNECT_TIMEOUT => tokio::time::sleep(Duration::from_millis(20)).await,
+      Err(e) => return Err(e.into())
+    }
+  }
+}
+
+enum Incoming {
+  Pdk(PdkCall),
+  Return(RpcReturn)
+}
+
+// Where frames for each call in flight go, by the call's sequence. None once the connection has closed.
+type Routes = Arc<std::sync::Mutex<Option<HashMap<i64, mpsc::UnboundedSender<Incoming>>>>>;
+
+struct Demux {
+  routes: Routes,
+  reader: JoinHandle<()>
+}
+
+impl Drop for Demux {
+  fn drop(&mut self) {
+    self.reader.abort();
+  }
+}
+
+// A PDK call belongs to the call whose sequence is its event id, and a reply to the call with its sequence.
+async fn demultiplex(stream: Stream, routes: Routes) {
+  while let Ok(frame) = stream.read_frame().await {
+    let (sequence, incoming) = match multiplex::pdk_call(&frame) {
+      Some(call) => (call.event_id, Incoming::Pdk(call)),
+      None => match RpcReturn::decode(&*frame) {
+        Ok(reply) => (reply.sequence, Incoming::Return(reply)),
+        Err(_) => break
+      }
+    };
+
+    if let Some(route) = routes.lock().unwrap().as_ref().and_then(|x| x.get(&sequence)) {
+      route.send(incoming).ok();
+    }
+  }
+
+  // Dropping the senders wakes every call still waiting with ConnectionClosed.
+  routes.lock().unwrap().take();
+}
+
+async fn answer(pdk: &dyn Transport, call: &PdkCall) -> KongResult<Option<PdkArg>> {
+  let args = multiplex::decode_args(&call.cmd, call.args.clone())?;
+  let reply = pdk.call(&call.cmd, &args).await?;
+  multiplex::encode_reply(&call.cmd, &reply)
+}
+
+fn unexpected(call: &str, reply: Option<Return>) -> KongError {
+  KongError::InvalidValueError(format!("Unexpected reply to {}: {:?}", call, reply))
+}
+
+#[cfg(test)]
+mod tests {
+  use std::time::Duration;
+
+  use tokio::time::Instant;
+
+  use crate::{
+    config::{PluginConfig, PluginConfigFieldVariant}, testing::MockKong, transport::Transport, ConfigFactory, FromConfig, KongError,
+    KongResult, Pdk, Phase, Plugin, PluginResult, PluginServerBroker, WireMode
+  };
+
+  use super::KongClient;
+
+  #[derive(serde::Serialize, serde::Deserialize)]
+  struct Config { }
+
+  impl PluginConfigFieldVariant for Config {
+    fn ty() -> &'static str { "record" }
+  }
+
+  impl PluginConfig for Config { }
+
+  // Sleeps for as long as the x-delay header says, then logs that it's done.
+  struct Sleepy;
+
+  #[async_trait::async_trait]
+  impl Plugin for Sleepy {
+    type Config = Config;
+    const NAME: &str = "sleepy";
+    const VERSION: &str = "0.1.0";
+    const PRIORITY: i32 = 0;
+    const PHASES: &[Phase] = &[Phase::Access];
+
+    async fn access(&self, pdk: &Pdk) -> PluginResult<Vec<u8>> {
+      let delay: u64 = pdk.request().get_header("x-delay".to_owned()).await.unwrap().parse().unwrap();
+      tokio::time::sleep(Duration::from_millis(delay)).await;
+      pdk.log().info(format!("slept {}ms", delay)).await.unwrap();
+      Ok(None)
+    }
+
+    fn default_config() -> Self::Config { Config { } }
+  }
+
+  #[async_trait::async_trait]
+  impl FromConfig for Sleepy {
+    async fn from_config(_config: Self::Config) -> KongResult<Self> { Ok(Sleepy) }
+  }
+
+  #[tokio::test]
+  async fn serves_kong_in_lockstep() {
+    let path = std::env::temp_dir().join(format!("kong_rs_lockstep_{}.socket", std::process::id()));
+    let broker = PluginServerBroker::new().with_socket_path(&path);
+    broker.register(ConfigFactory::<Sleepy>::new()).await.unwrap();
+
+    let client = async {
+      let client = KongClient::connect(&path).await.unwrap();
+      assert_eq!(client.get_plugin_names().await.unwrap(), vec!["sleepy"]);
+      let info = client.get_plugin_info("sleepy").await.unwrap().unwrap();
+      assert_eq!((info.name.as_str(), info.version.as_str(), info.priority, info.phases), ("sleepy", "0.1.0", 0, vec!["access".to_owned()]));
+
+      assert!(client.start_instance("sleepy", "{").await.unwrap().is_none());
+      let started = client.start_instance("sleepy", "{}").await.unwrap().unwrap();
+      assert_eq!(started.name, "sleepy");
+
+      let session = MockKong::new().with_header("x-delay", "0").start();
+      let handled = client.handle_event(started.instance_id, Phase::Access, &session).await.unwrap().unwrap();
+      assert_eq!(handled.instance_id, started.instance_id);
+      let recorded = session.recorded();
+      assert_eq!(recorded.calls, vec!["kong.request.get_header", "kong.log.info"]);
+      assert_eq!(recorded.logs_at("info"), vec!["slept 0ms"]);
+
+      let status = client.get_instance_status(started.instance_id).await.unwrap().unwrap();
+      assert_eq!((status.name.as_str(), status.instance_id), ("sleepy", started.instance_id));
+      client.close_instance(started.instance_id).await.unwrap();
+      assert!(client.get_instance_status(started.instance_id).await.unwrap().is_none());
+
+      drop(client);
+      broker.shutdown();
+    };
+
+    let (served, ()) = tokio::join!(broker.run(["sleepy".to_owned()].into_iter()), client);
+    served.unwrap();
+  }
+
+  #[tokio::test]
+  async fn handles_events_concurrently_on_one_connection() {
+    let path = std::env::temp_dir().join(format!("kong_rs_multiplexed_{}.socket", std::process::id()));
+    let broker = PluginServerBroker::new().with_socket_path(&path).with_wire_mode(WireMode::Multiplexed);
+    broker.register(ConfigFactory::<Sleepy>::new()).await.unwrap();
+
+    let client = async {
+      let client = KongClient::connect_multiplexed(&path).await.unwrap();
+      let id = client.start_instance("sleepy", "{}").await.unwrap().unwrap().instance_id;
+      let slow = MockKong::new().with_header("x-delay", "300").start();
+      let fast = MockKong::new().with_header("x-delay", "0").start();
+
+      let started = Instant::now();
+      let (slow_done, (fast_done, started_other)) = tokio::join!(
+        async {
+          client.handle_event(id, Phase::Access, &slow).await.unwrap().unwrap();
+          started.elapsed()
+        },
+        async {
+          tokio::time::sleep(Duration::from_millis(20)).await;
+          client.handle_event(id, Phase::Access, &fast).await.unwrap().unwrap();
+          let fast_done = started.elapsed();
+          // Starting an instance takes the lock on the instances, which the slow event mustn't be holding.
+          client.start_instance("sleepy", "{}").await.unwrap().unwrap();
+          (fast_done, started.elapsed())
+        }
+      );
+
+      assert!(fast_done < Duration::from_millis(200), "fast event took {:?}", fast_done);
+      assert!(started_other < Duration::from_millis(200), "starting an instance took {:?}", started_other);
+      assert!(slow_done >= Duration::from_millis(300));
+      assert_eq!(slow.recorded().logs_at("info"), vec!["slept 300ms"]);
+      assert_eq!(fast.recorded().logs_at("info"), vec!["slept 0ms"]);
+
+      drop(client);
+      broker.shutdown();
+    };
+
+    let (served, ()) = tokio::join!(broker.run(["sleepy".to_owned()].into_iter()), client);
+    served.unwrap();
+  }
+
+  // Fails every PDK call, so the event fails too.
+  struct Broken;
+
+  #[async_trait::async_trait]
+  impl Transport for Broken {
+    async fn call(&self, method: &str, _args: &[u8]) -> KongResult<Vec<u8>> {
+      Err(KongError::InvalidValueError(format!("{} failed", method)))
+    }
+  }
+
+  #[tokio::test]
+  async fn a_failed_event_only_fails_itself() {
+    let path = std::env::temp_dir().join(format!("kong_rs_failed_event_{}.socket", std::process::id()));
+    let broker = PluginServerBroker::new().with_socket_path(&path).with_wire_mode(WireMode::Multiplexed);
+    broker.register(ConfigFactory::<Sleepy>::new()).await.unwrap();
+
+    let client = async {
+      let client = KongClient::connect_multiplexed(&path).await.unwrap();
+      let id = client.start_instance("sleepy", "{}").await.unwrap().unwrap().instance_id;
+      let session = MockKong::new().with_header("x-delay", "100").start();
+
+      let (failed, handled) = tokio::join!(
+        client.handle_event(id, Phase::Access, &Broken),
+        client.handle_event(id, Phase::Access, &session)
+      );
+      assert!(failed.unwrap().is_none());
+      assert_eq!(handled.unwrap().unwrap().instance_id, id);
+      assert_eq!(session.recorded().logs_at("info"), vec!["slept 100ms"]);
+
+      // The connection is still up for the calls after it.
+      assert!(client.get_instance_status(id).await.unwrap().is_some());
+
+      drop(client);
+      broker.shutdown();
+    };
+
+    let (served, ()) = tokio::join!(broker.run(["sleepy".to_owned()].into_iter()), client);
+    served.unwrap();
+  }
+}
@@ -0,0 +1,771 @@
+use std::{collections::BTreeMap, sync::{Arc, Mutex}, time::{SystemTime, UNIX_EPOCH}};
+
+use http::{HeaderMap, HeaderName, HeaderValue};
+use kong_rs_protos::{AuthenticateArgs, AuthenticatedCredential, Consumer, ConsumerSpec, ExitArgs, Kv, RawBodyResult, Route, Service, Target};
+use prost::Message;
+use serde_json::json;
+
+use crate::{
+  pdk::{client, ctx, log, unwrap_headers, ngx::{self, Subsystem}, request, response::{self, ResponsePDK}, router, service, Value},
+  plugin::ErasedPlugin,
+  transport::Transport,
+  KongError, KongResult, Pdk, Phase
+};
+
+mod kong;
+
+pub use kong::KongClient;
+
+// A fake Kong for exercising plugins without a gateway. The request is scripted up front, every PDK call is
+// answered from the script by an in-memory transport, and whatever the plugin changes is recorded:
+//
+//   let recorded = MockKong::new().with_path("/users").with_header("x-api-key", "abc")
+//     .run(&plugin, Phase::Access).await?;
+//   assert_eq!(recorded.exit.unwrap().status, 401);
+
+#[derive(Debug, Clone)]
+pub struct MockKong {
+  method: String,
+  scheme: String,
+  host: String,
+  port: usize,
+  path: String,
+  raw_query: String,
+  http_version: f64,
+  headers: HeaderMap,
+  body: Vec<u8>,
+
+  client_ip: String,
+  client_port: usize,
+  route: Route,
+  service: Service,
+  consumer: Consumer,
+  credential: AuthenticatedCredential,
+
+  service_status: usize,
+  service_headers: HeaderMap,
+  service_body: Vec<u8>,
+
+  subsystem: Subsystem,
+  tls_version: String,
+  start_time: f64,
+  vars: BTreeMap<String, String>,
+  shared: BTreeMap<String, Value>
+}
+
+impl Default for MockKong {
+  fn default() -> Self {
+    Self::new()
+  }
+}
+
+impl MockKong {
+  pub fn new() -> Self {
+    Self {
+      method: "GET".to_owned(),
+      scheme: "http".to_owned(),
+      host: "localhost".to_owned(),
+      port: 8000,
+      path: "/".to_owned(),
+      raw_query: String::new(),
+      http_version: 1.1,
+      headers: HeaderMap::new(),
+      body: vec![],
+      client_ip: "127.0.0.1".to_owned(),
+      client_port: 40000,
+      route: Route::default(),
+      service: Service::default(),
+      consumer: Consumer::default(),
+      credential: AuthenticatedCredential::default(),
+      service_status: 200,
+      service_headers: HeaderMap::new(),
+      service_body: vec![],
+      subsystem: Subsystem::Http,
+      tls_version: String::new(),
+      start_time: SystemTime::now().duration_since(UNIX_EPOCH).map_or(0.0, |x| x.as_secs_f64()),
+      vars: BTreeMap::new(),
+      shared: BTreeMap::new()
+    }
+  }
+
+  pub fn with_method<S: Into<String>>(mut self, method: S) -> Self {
+    self.method = method.into();
+    self
+  }
+
+  pub fn with_scheme<S: Into<String>>(mut self, scheme: S) -> Self {
+    self.scheme = scheme.into();
+    self
+  }
+
+  pub fn with_host<S: Into<String>>(mut self, host: S) -> Self {
+    self.host = host.into();
+    self
+  }
+
+  pub fn with_port(mut self, port: usize) -> Self {
+    self.port = port;
+    self
+  }
+
+  // Either a bare path, or a path and query string.
+  pub fn with_path<S: Into<String>>(mut self, path: S) -> Self {
+    let path = path.into();
+    match path.split_once('?') {
+      Some((path, query)) => {
+        self.path = path.to_owned();
+        self.raw_query = query.to_owned();
+      },
+      None => self.path = path
+    }
+    self
+  }
+
+  pub fn with_query<S: Into<String>>(mut self, raw_query: S) -> Self {
+    self.raw_query = raw_query.into();
+    self
+  }
+
+  pub fn with_http_version(mut self, http_version: f64) -> Self {
+    self.http_version = http_version;
+    self
+  }
+
+  pub fn with_header(mut self, name: &str, value: &str) -> Self {
+    self.headers.append(header_name(name), HeaderValue::from_str(value).expect("invalid header value"));
+    self
+  }
+
+  pub fn with_headers(mut self, headers: HeaderMap) -> Self {
+    self.headers = headers;
+    self
+  }
+
+  pub fn with_body<B: Into<Vec<u8>>>(mut self, body: B) -> Self {
+    self.body = body.into();
+    self
+  }
+
+  pub fn with_client_ip<S: Into<String>>(mut self, ip: S) -> Self {
+    self.client_ip = ip.into();
+    self
+  }
+
+  pub fn with_client_port(mut self, port: usize) -> Self {
+    self.client_port = port;
+    self
+  }
+
+  pub fn with_route(mut self, route: Route) -> Self {
+    self.route = route;
+    self
+  }
+
+  pub fn with_service(mut self, service: Service) -> Self {
+    self.service = service;
+    self
+  }
+
+  // The consumer an earlier auth plugin identified, as returned by kong.client.get_consumer.
+  pub fn with_consumer(mut self, consumer: Consumer) -> Self {
+    self.consumer = consumer;
+    self
+  }
+
+  pub fn with_credential(mut self, credential: AuthenticatedCredential) -> Self {
+    self.credential = credential;
+    self
+  }
+
+  // What the upstream replied with, for the response and log phases.
+  pub fn with_service_response<B: Into<Vec<u8>>>(mut self, status: usize, headers: HeaderMap, body: B) -> Self {
+    self.service_status = status;
+    self.service_headers = headers;
+    self.service_body = body.into();
+    self
+  }
+
+  pub fn with_subsystem(mut self, subsystem: Subsystem) -> Self {
+    self.subsystem = subsystem;
+    self
+  }
+
+  pub fn with_tls_version<S: Into<String>>(mut self, version: S) -> Self {
+    self.tls_version = version.into();
+    self
+  }
+
+  pub fn with_start_time(mut self, start_time: f64) -> Self {
+    self.start_time = start_time;
+    self
+  }
+
+  // An nginx variable, read through kong.nginx.get_var.
+  pub fn with_var<K: Into<String>, V: Into<String>>(mut self, key: K, value: V) -> Self {
+    self.vars.insert(key.into(), value.into());
+    self
+  }
+
+  // A value already in kong.ctx.shared, as if set by a plugin that ran earlier.
+  pub fn with_shared<K: Into<String>>(mut self, key: K, value: Value) -> Self {
+    self.shared.insert(key.into(), value);
+    self
+  }
+
+  // Returns a PDK whose calls are answered from the script.
+  pub fn start(self) -> MockSession {
+    let state = Arc::new(Mutex::new(State::new(self)));
+    MockSession { pdk: Pdk::new(Arc::new(MockTransport(state.clone()))), state }
+  }
+
+  // Runs a single phase the way the plugin server would, including turning returned responses into an exit.
+  pub async fn run<P: ErasedPlugin + ?Sized>(self, plugin: &P, phase: Phase) -> KongResult<Recorded> {
+    let session = self.start();
+    let result = plugin._call_phase(&phase, session.pdk()).await;
+    let recorded = session.recorded();
+    // A call the mock couldn't answer fails the run, even if the plugin shrugged the error off.
+    if !recorded.failures.is_empty() {
+      return Err(KongError::InvalidValueError(recorded.failures.join("; ")));
+    }
+    result?;
+    Ok(recorded)
+  }
+}
+
+pub struct MockSession {
+  pdk: Pdk,
+  state: Arc<Mutex<State>>
+}
+
+impl MockSession {
+  pub fn pdk(&self) -> &Pdk {
+    &self.pdk
+  }
+
+  pub fn recorded(&self) -> Recorded {
+    self.state.lock().unwrap().recorded.clone()
+  }
+}
+
+// Lets a session answer the PDK calls of a plugin running out of process, through KongClient::handle_event.
+#[async_trait::async_trait]
+impl Transport for MockSession {
+  async fn call(&self, method: &str, args: &[u8]) -> KongResult<Vec<u8>> {
+    self.state.lock().unwrap().answer(method, args)
+  }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct LogLine {
+  // As in the PDK method name, e.g. "err" or "info".
+  pub level: String,
+  pub message: String
+}
+
+#[derive(Debug, Clone)]
+pub struct Exit {
+  pub status: usize,
+  pub body: Vec<u8>,
+  pub headers: HeaderMap
+}
+
+// The request as it will be sent upstream. Starts out as the client's request.
+#[derive(Debug, Clone, Default)]
+pub struct UpstreamRequest {
+  pub scheme: String,
+  pub method: String,
+  pub path: String,
+  pub raw_query: String,
+  pub headers: HeaderMap,
+  pub body: Vec<u8>,
+  pub upstream: Option<String>,
+  pub target: Option<(String, usize)>
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Recorded {
+  // Every PDK method called, in order.
+  pub calls: Vec<String>,
+  pub logs: Vec<LogLine>,
+  pub exit: Option<Exit>,
+  pub upstream: UpstreamRequest,
+  // The response to the client, which starts out as the service's response.
+  pub status: usize,
+  pub headers: HeaderMap,
+  pub body: Vec<u8>,
+  pub shared: BTreeMap<String, Value>,
+  pub ctx: BTreeMap<String, Value>,
+  pub serialize_values: BTreeMap<String, Value>,
+  pub authenticated: Option<AuthenticateArgs>,
+  // The calls the mock couldn't answer: unknown methods, or arguments that didn't decode.
+  pub failures: Vec<String>
+}
+
+impl Recorded {
+  pub fn logs_at(&self, level: &str) -> Vec<&str> {
+    self.logs.iter().filter(|x| x.level == level).map(|x| x.message.as_str()).collect()
+  }
+}
+
+struct MockTransport(Arc<Mutex<State>>);
+
+#[async_trait::async_trait]
+impl Transport for MockTransport {
+  async fn call(&self, method: &str, args: &[u8]) -> KongResult<Vec<u8>> {
+    self.0.lock().unwrap().answer(method, args)
+  }
+}
+
+struct State {
+  script: MockKong,
+  recorded: Recorded
+}
+
+fn header_name(name: &str) -> HeaderName {
+  HeaderName::from_bytes(name.as_bytes()).expect("invalid header name")
+}
+
+fn kv(args: &[u8]) -> KongResult<(String, Value)> {
+  let kv = Kv::decode(args)?;
+  Ok((kv.k, kv.v.and_then(|x| x.kind).map(Into::into).unwrap_or(Value::Null)))
+}
+
+fn kv_header(args: &[u8]) -> KongResult<(HeaderName, HeaderValue)> {
+  let (name, value) = kv(args)?;
+  let value = match value {
+    Value::String(s) => s,
+    Value::Number(n) => n.to_string(),
+    Value::Bool(b) => b.to_string(),
+    _ => String::new()
+  };
+  Ok((header_name(&name), HeaderValue::from_str(&value)?))
+}
+
+fn string(v: &str) -> Vec<u8> {
+  kong_rs_protos::String { v: v.to_owned() }.encode_to_vec()
+}
+
+fn int(v: usize) -> Vec<u8> {
+  kong_rs_protos::Int { v: v as i32 }.encode_to_vec()
+}
+
+fn value(v: Option<&Value>) -> Vec<u8> {
+  prost_types::Value { kind: v.cloned().map(Into::into) }.encode_to_vec()
+}
+
+fn first_header(headers: &HeaderMap, name: &str) -> String {
+  headers.get(name).and_then(|x| x.to_str().ok()).unwrap_or("").to_owned()
+}
+
+fn limited_headers(headers: &HeaderMap, max: i32) -> HeaderMap {
+  let mut limited = HeaderMap::new();
+  for name in headers.keys().take(max.max(0) as usize) {
+    for value in headers.get_all(name) {
+      limited.append(name, value.clone());
+    }
+  }
+  limited
+}
+
+// As set_headers does, each header given replaces every existing value under that name.
+fn replace_headers(target: &mut HeaderMap, headers: HeaderMap) {
+  for name in headers.keys() {
+    target.remove(name);
+    for value in headers.get_all(name) {
+      target.append(name, value.clone());
+    }
+  }
+}
+
+fn percent_decode(s: &str) -> String {
+  let bytes = s.as_bytes();
+  let mut out = Vec::with_capacity(bytes.len());
+  let mut i = 0;
+  while i < bytes.len() {
+    match bytes[i] {
+      b'+' => out.push(b' '),
+      b'%' if let Some(byte) = s.get(i + 1..i + 3).and_then(|x| u8::from_str_radix(x, 16).ok()) => {
+        out.push(byte);
+        i += 2;
+      },
+      b => out.push(b)
+    }
+    i += 1;
+  }
+  String::from_utf8_lossy(&out).into_owned()
+}
+
+// As kong.request.get_query: flags without a value are true, and repeated keys become lists.
+fn parse_query(raw_query: &str) -> BTreeMap<String, Value> {
+  let mut query = BTreeMap::new();
+  for pair in raw_query.split('&').filter(|x| !x.is_empty()) {
+    let (key, value) = match pair.split_once('=') {
+      Some((key, value)) => (percent_decode(key), Value::String(percent_decode(value))),
+      None => (percent_decode(pair), Value::Bool(true))
+    };
+    match query.remove(&key) {
+      None => { query.insert(key, value); },
+      Some(Value::List(mut values)) => { values.push(value); query.insert(key, Value::List(values)); },
+      Some(existing) => { query.insert(key, Value::List(vec![existing, value])); }
+    }
+  }
+  query
+}
+
+fn to_json(value: &Value) -> serde_json::Value {
+  match value {
+    Value::Null => serde_json::Value::Null,
+    Value::Number(n) => json!(n),
+    Value::String(s) => json!(s),
+    Value::Bool(b) => json!(b),
+    Value::Struct(fields) => fields.iter().map(|(k, v)| (k.clone(), to_json(v))).collect::<serde_json::Map<_, _>>().into(),
+    Value::List(values) => values.iter().map(to_json).collect()
+  }
+}
+
+fn set_path(target: &mut serde_json::Value, path: &str, value: serde_json::Value) {
+  if !target.is_object() {
+    *target = json!({});
+  }
+  let obj = target.as_object_mut().unwrap();
+  match path.split_once('.') {
+    Some((key, rest)) => set_path(obj.entry(key).or_insert(serde_json::Value::Null), rest, value),
+    None => { obj.insert(path.to_owned(), value); }
+  }
+}
+
+fn headers_json(headers: &HeaderMap) -> serde_json::Value {
+  let mut out = serde_json::Map::new();
+  for name in headers.keys() {
+    let mut values: Vec<_> = headers.get_all(name).iter().map(|x| json!(x.to_str().unwrap_or(""))).collect();
+    out.insert(name.to_string(), if values.len() == 1 { values.remove(0) } else { values.into() });
+  }
+  out.into()
+}
+
+impl State {
+  fn new(script: MockKong) -> Self {
+    let recorded = Recorded {
+      upstream: UpstreamRequest {
+        scheme: match script.service.protocol.as_str() {
+          "" => script.scheme.clone(),
+          protocol => protocol.to_owned()
+        },
+        method: script.method.clone(),
+        path: script.path.clone(),
+        raw_query: script.raw_query.clone(),
+        headers: script.headers.clone(),
+        body: script.body.clone(),
+        ..Default::default()
+      },
+      status: script.service_status,
+      headers: script.service_headers.clone(),
+      body: script.service_body.clone(),
+      shared: script.shared.clone(),
+      ..Default::default()
+    };
+    Self { script, recorded }
+  }
+
+  fn answer(&mut self, method: &str, args: &[u8]) -> KongResult<Vec<u8>> {
+    self.recorded.calls.push(method.to_owned());
+
+    let reply = if let Ok(m) = method.parse() {
+      self.client(m, args)
+    } else if let Ok(m) = method.parse() {
+      self.ctx(m, args)
+    } else if let Ok(m) = method.parse() {
+      self.log(m, args)
+    } else if let Ok(m) = method.parse() {
+      Ok(self.ngx(m, args))
+    } else if let Ok(m) = method.parse() {
+      self.request(m, args)
+    } else if let Ok(m) = method.parse() {
+      self.response(m, args)
+    } else if let Ok(m) = method.parse() {
+      Ok(self.router(m))
+    } else if let Ok(m) = method.parse() {
+      self.service(m, args)
+    } else if let Ok(m) = method.parse() {
+      self.service_request(m, args)
+    } else if let Ok(m) = method.parse() {
+      Ok(self.service_response(m, args))
+    } else {
+      Err(KongError::InvalidValueError("no such PDK method".to_owned()))
+    };
+
+    reply.map_err(|e| {
+      let failure = format!("MockKong couldn't answer {}: {}", method, e);
+      self.recorded.failures.push(failure.clone());
+      KongError::InvalidValueError(failure)
+    })
+  }
+
+  fn client(&mut self, method: client::Methods, args: &[u8]) -> KongResult<Vec<u8>> {
+    use client::Methods::*;
+    let script = &self.script;
+    Ok(match method {
+      GetIp | GetForwardedIp => string(&script.client_ip),
+      GetPort | GetForwardedPort => int(script.client_port),
+      GetCredential => script.credential.encode_to_vec(),
+      GetConsumer => script.consumer.encode_to_vec(),
+      LoadConsumer => {
+        let spec = ConsumerSpec::decode(args)?;
+        let found = match spec.by_username {
+          true => script.consumer.username == spec.id,
+          false => script.consumer.id == spec.id
+        };
+        if found { script.consumer.encode_to_vec() } else { vec![] }
+      },
+      Authenticate => {
+        let auth = AuthenticateArgs::decode(args)?;
+        self.script.consumer = auth.consumer.clone().unwrap_or_default();
+        self.script.credential = auth.credential.clone().unwrap_or_default();
+        self.recorded.authenticated = Some(auth);
+        vec![]
+      },
+      GetProtocol => string(&script.scheme)
+    })
+  }
+
+  fn ctx(&mut self, method: ctx::Methods, args: &[u8]) -> KongResult<Vec<u8>> {
+    use ctx::Methods::*;
+    let recorded = &mut self.recorded;
+    Ok(match method {
+      SharedSet => { let (k, v) = kv(args)?; recorded.shared.insert(k, v); vec![] },
+      Set => { let (k, v) = kv(args)?; recorded.ctx.insert(k, v); vec![] },
+      SharedGet => value(recorded.shared.get(&kong_rs_protos::String::decode(args)?.v)),
+      Get => value(recorded.ctx.get(&kong_rs_protos::String::decode(args)?.v))
+    })
+  }
+
+  fn log(&mut self, method: log::Methods, args: &[u8]) -> KongResult<Vec<u8>> {
+    use log::Methods::*;
+    Ok(match method {
+      Alert | Crit | Error | Warn | Notice | Info | Debug => {
+        let level: &str = method.into();
+        let message = prost_types::ListValue::decode(args)?.values.into_iter()
+          .filter_map(|x| x.kind)
+          .map(|x| match Value::from(x) {
+            Value::String(s) => s,
+            v => to_json(&v).to_string()
+          })
+          .collect::<Vec<_>>()
+          .join(" ");
+        self.recorded.logs.push(LogLine { level: level.trim_start_matches("kong.log.").to_owned(), message });
+        vec![]
+      },
+      Serialize => string(&self.serialized().to_string()),
+      SetSerializeValue => { let (k, v) = kv(args)?; self.recorded.serialize_values.insert(k, v); vec![] }
+    })
+  }
+
+  fn serialized(&self) -> serde_json::Value {
+    let (script, recorded) = (&self.script, &self.recorded);
+    let query = parse_query(&script.raw_query).iter().map(|(k, v)| (k.clone(), to_json(v))).collect::<serde_json::Map<_, _>>();
+    let uri = match script.raw_query.as_str() {
+      "" => script.path.clone(),
+      query => format!("{}?{}", script.path, query)
+    };
+
+    let mut log = json!({
+      "request": {
+        "method": script.method,
+        "uri": uri,
+        "url": format!("{}://{}:{}{}", script.scheme, script.host, script.port, uri),
+        "size": script.body.len(),
+        "querystring": query,
+        "headers": headers_json(&script.headers)
+      },
+      "response": {
+        "status": recorded.exit.as_ref().map_or(recorded.status, |x| x.status),
+        "size": recorded.body.len(),
+        "headers": headers_json(&recorded.headers)
+      },
+      "client_ip": script.client_ip,
+      "started_at": (script.start_time * 1000.0) as u64,
+      "route": { "id": script.route.id, "name": script.route.name },
+      "service": { "id": script.service.id, "name": script.service.name },
+      "consumer": { "id": script.consumer.id, "username": script.consumer.username }
+    });
+
+    // Keys set through set_serialize_value are dotted paths into the log.
+    for (key, value) in &recorded.serialize_values {
+      set_path(&mut log, key, to_json(value));
+    }
+    log
+  }
+
+  fn ngx(&self, method: ngx::Methods, args: &[u8]) -> Vec<u8> {
+    use ngx::Methods::*;
+    let script = &self.script;
+    match method {
+      GetVar => {
+        let key = kong_rs_protos::String::decode(args).unwrap_or_default().v;
+        string(script.vars.get(&key).map_or("", |x| x.as_str()))
+      },
+      GetTls1VersionStr => string(&script.tls_version),
+      ReqStartTime => kong_rs_protos::Number { v: script.start_time }.encode_to_vec(),
+      GetSubsystem => string(script.subsystem.into())
+    }
+  }
+
+  fn request(&self, method: request::Methods, args: &[u8]) -> KongResult<Vec<u8>> {
+    use request::Methods::*;
+    let script = &self.script;
+    Ok(match method {
+      GetScheme | GetForwardedScheme => string(&script.scheme),
+      GetHost | GetForwardedHost => string(&script.host),
+      GetPort | GetForwardedPort => int(script.port),
+      GetHttpVersion => kong_rs_protos::Number { v: script.http_version }.encode_to_vec(),
+      GetMethod => string(&script.method),
+      GetPath => string(&script.path),
+      GetPathWithQuery => match script.raw_query.as_str() {
+        "" => string(&script.path),
+        query => string(&format!("{}?{}", script.path, query))
+      },
+      GetRawQuery => string(&script.raw_query),
+      GetQueryArg => {
+        let name = kong_rs_protos::String::decode(args)?.v;
+        match parse_query(&script.raw_query).remove(&name) {
+          Some(Value::String(s)) => string(&s),
+          Some(Value::List(values)) => match values.into_iter().next() {
+            Some(Value::String(s)) => string(&s),
+            _ => string("")
+          },
+          _ => string("")
+        }
+      },
+      GetQuery => {
+        let max = kong_rs_protos::Int::decode(args)?.v.max(0) as usize;
+        prost_types::Struct {
+          fields: parse_query(&script.raw_query).into_iter().take(max)
+            .map(|(k, v)| (k, prost_types::Value { kind: Some(v.into()) }))
+            .collect()
+        }.encode_to_vec()
+      },
+      GetHeader => string(&first_header(&script.headers, &kong_rs_protos::String::decode(args)?.v)),
+      GetHeaders => {
+        let max = kong_rs_protos::Int::decode(args)?.v;
+        ResponsePDK::headers_to_struct(limited_headers(&script.headers, max)).encode_to_vec()
+      },
+      GetRawBody => RawBodyResult { kind: Some(kong_rs_protos::raw_body_result::Kind::Content(script.body.clone())) }.encode_to_vec()
+    })
+  }
+
+  fn response(&mut self, method: response::Methods, args: &[u8]) -> KongResult<Vec<u8>> {
+    use response::Methods::*;
+    let recorded = &mut self.recorded;
+    Ok(match method {
+      GetStatus => int(recorded.exit.as_ref().map_or(recorded.status, |x| x.status)),
+      GetHeader => string(&first_header(&recorded.headers, &kong_rs_protos::String::decode(args)?.v)),
+      GetHeaders => {
+        let max = kong_rs_protos::Int::decode(args)?.v;
+        ResponsePDK::headers_to_struct(limited_headers(&recorded.headers, max)).encode_to_vec()
+      },
+      GetSource => string(if recorded.exit.is_some() { "exit" } else { "service" }),
+      SetStatus => { recorded.status = kong_rs_protos::Int::decode(args)?.v as usize; vec![] },
+      SetHeader => { let (k, v) = kv_header(args)?; recorded.headers.insert(k, v); vec![] },
+      AddHeader => { let (k, v) = kv_header(args)?; recorded.headers.append(k, v); vec![] },
+      ClearHeader => { recorded.headers.remove(kong_rs_protos::String::decode(args)?.v); vec![] },
+      SetHeaders => { replace_headers(&mut recorded.headers, unwrap_headers(prost_types::Struct::decode(args)?)?); vec![] },
+      Exit => {
+        let exit = ExitArgs::decode(args)?;
+        let headers = exit.headers.map(unwrap_headers).transpose()?.unwrap_or_default();
+        recorded.exit = Some(self::Exit { status: exit.status as usize, body: exit.body, headers });
+        vec![]
+      }
+    })
+  }
+
+  fn router(&self, method: router::Methods) -> Vec<u8> {
+    match method {
+      router::Methods::GetRoute => self.script.route.encode_to_vec(),
+      router::Methods::GetService => self.script.service.encode_to_vec()
+    }
+  }
+
+  fn service(&mut self, method: service::Methods, args: &[u8]) -> KongResult<Vec<u8>> {
+    let upstream = &mut self.recorded.upstream;
+    Ok(match method {
+      service::Methods::SetUpstream => {
+        upstream.upstream = Some(kong_rs_protos::String::decode(args)?.v);
+        kong_rs_protos::Bool { v: true }.encode_to_vec()
+      },
+      service::Methods::SetTarget => {
+        let target = Target::decode(args)?;
+        upstream.target = Some((target.host, target.port as usize));
+        vec![]
+      }
+    })
+  }
+
+  fn service_request(&mut self, method: service::request::Methods, args: &[u8]) -> KongResult<Vec<u8>> {
+    use service::request::Methods::*;
+    let upstream = &mut self.recorded.upstream;
+    match method {
+      SetScheme => upstream.scheme = kong_rs_protos::String::decode(args)?.v,
+      SetPath => upstream.path = kong_rs_protos::String::decode(args)?.v,
+      SetRawQuery => upstream.raw_query = kong_rs_protos::String::decode(args)?.v,
+      SetMethod => upstream.method = kong_rs_protos::String::decode(args)?.v,
+      SetQuery => {
+        let query = prost_types::Struct::decode(args)?;
+        upstream.raw_query = query.fields.into_iter()
+          .filter_map(|(k, v)| v.kind.map(|x| (k, Value::from(x))))
+          .flat_map(|(k, v)| match v {
+            Value::List(values) => values.into_iter().map(|x| (k.clone(), x)).collect(),
+            v => vec![(k, v)]
+          })
+          .map(|(k, v)| match v {
+            Value::Bool(true) => k,
+            Value::String(s) => format!("{}={}", k, s),
+            v => format!("{}={}", k, to_json(&v))
+          })
+          .collect::<Vec<_>>()
+          .join("&");
+      },
+      SetHeader => { let (k, v) = kv_header(args)?; upstream.headers.insert(k, v); },
+      AddHeader => { let (k, v) = kv_header(args)?; upstream.headers.append(k, v); },
+      ClearHeader => { upstream.headers.remove(kong_rs_protos::String::decode(args)?.v); },
+      SetHeaders => replace_headers(&mut upstream.headers, unwrap_headers(prost_types::Struct::decode(args)?)?),
+      SetRawBody => upstream.body = kong_rs_protos::ByteString::decode(args)?.v
+    }
+    Ok(vec![])
+  }
+
+  fn service_response(&self, method: service::response::Methods, args: &[u8]) -> Vec<u8> {
+    use service::response::Methods::*;
+    let script = &self.script;
+    match method {
+      GetStatus => int(script.service_status),
+      GetHeader => string(&first_header(&script.service_headers, &kong_rs_protos::String::decode(args).unwrap_or_default().v)),
+      GetHeaders => {
+        let max = kong_rs_protos::Int::decode(args).unwrap_or_default().v;
+        ResponsePDK::headers_to_struct(limited_headers(&script.service_headers, max)).encode_to_vec()
+      },
+      GetRawBody => kong_rs_protos::ByteString { v: script.service_body.clone() }.encode_to_vec()
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use crate::transport::Transport;
+
+  use super::MockKong;
+
+  #[tokio::test]
+  async fn fails_calls_it_cannot_answer() {
+    let session = MockKong::new().start();
+
+    let error = session.call("kong.nope", &[]).await.unwrap_err();
+    assert!(error.to_string().contains("kong.nope"), "{}", error);
+    // Truncated protobuf.
+    assert!(session.call("kong.ctx.shared.set", &[0x0a, 0x05]).await.is_err());
+    assert!(session.call("kong.request.get_method", &[]).await.is_ok());
+
+    let recorded = session.recorded();
+    assert_eq!(recorded.failures.len(), 2);
+    assert_eq!(recorded.calls, vec!["kong.nope", "kong.ctx.shared.set", "kong.request.get_method"]);
+  }
+}
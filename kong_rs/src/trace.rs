@@ -0,0 +1,400 @@
+use std::{fmt::Display, fs::File, io::{LineWriter, Write}, path::Path, sync::{atomic::{AtomicU64, Ordering}, Arc, Mutex}};
+
+use tokio::sync::{mpsc, oneshot};
+
+use crate::{plugin::{ErasedPlugin, Phase}, transport::Transport, KongError, KongResult, Pdk};
+
+// Traces of the plugin socket, written as JSON lines while serving with -record and read back with -replay.
+// Frames and PDK arguments are kept as hex-encoded protobuf, exactly as they crossed the socket. Hex is not
+// redaction: headers, bodies and anything else a plugin reads, credentials included, can be read back from a trace.
+
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum TraceEntry {
+  // An RPC frame from Kong and the frame sent back, which is empty when there was nothing to return.
+  Rpc {
+    connection: u64,
+    #[serde(with = "hex")]
+    call: Vec<u8>,
+    #[serde(with = "hex")]
+    reply: Vec<u8>
+  },
+  // An instance the server started, with the config Kong sent for it.
+  Instance { instance_id: i32, name: String, config: String },
+  // An event Kong asked an instance to handle. The PDK calls made while handling it carry the same event id.
+  Event { event: u64, instance_id: i32, phase: String },
+  // A PDK call. A call that failed has no reply, and the error the plugin got instead.
+  Call {
+    event: u64,
+    method: String,
+    #[serde(with = "hex")]
+    args: Vec<u8>,
+    #[serde(with = "hex")]
+    reply: Vec<u8>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    error: Option<String>
+  }
+}
+
+enum Message {
+  Entry(TraceEntry),
+  // Answered once everything sent before it is written out.
+  Flush(oneshot::Sender<()>)
+}
+
+#[derive(Clone)]
+pub struct Recorder {
+  entries: mpsc::UnboundedSender<Message>,
+  next_id: Arc<AtomicU64>
+}
+
+impl Recorder {
+  // Entries are written out by a blocking task, so recording never holds up the runtime on file I/O. Has to be called
+  // from within a tokio runtime.
+  pub fn new<W: Write + Send + 'static>(mut out: W) -> Self {
+    let (entries, mut received) = mpsc::unbounded_channel();
+    tokio::task::spawn_blocking(move || {
+      while let Some(message) = received.blocking_recv() {
+        match message {
+          Message::Entry(entry) => if let Ok(line) = serde_json::to_string(&entry) {
+            writeln!(out, "{}", line).ok();
+          },
+          Message::Flush(done) => {
+            out.flush().ok();
+            done.send(()).ok();
+          }
+        }
+      }
+      out.flush().ok();
+    });
+    Self { entries, next_id: Arc::new(AtomicU64::new(0)) }
+  }
+
+  pub fn create<P: AsRef<Path>>(path: P) -> KongResult<Self> {
+    let file = File::create(path.as_ref())
+      .map_err(|e| KongError::LaunchError(format!("Could not create {}: {}", path.as_ref().display(), e)))?;
+    Ok(Self::new(LineWriter::new(file)))
+  }
+
+  // Ids for connections and events, unique within the trace.
+  pub fn next_id(&self) -> u64 {
+    self.next_id.fetch_add(1, Ordering::Relaxed)
+  }
+
+  // A trace that can't be written shouldn't take requests down with it, so failures are dropped.
+  pub fn record(&self, entry: TraceEntry) {
+    self.entries.send(Message::Entry(entry)).ok();
+  }
+
+  // Waits until everything recorded so far is written out.
+  pub async fn flush(&self) {
+    let (done, flushed) = oneshot::channel();
+    if self.entries.send(Message::Flush(done)).is_ok() {
+      flushed.await.ok();
+    }
+  }
+
+  // Records the start of an event, returning a transport that records the PDK calls made through it.
+  pub fn event(&self, instance_id: i32, phase: &Phase, inner: Arc<dyn Transport>) -> Arc<dyn Transport> {
+    let event = self.next_id();
+    self.record(TraceEntry::Event { event, instance_id, phase: Into::<&str>::into(phase.clone()).to_owned() });
+    Arc::new(RecordingTransport { recorder: self.clone(), event, inner })
+  }
+}
+
+struct RecordingTransport {
+  recorder: Recorder,
+  event: u64,
+  inner: Arc<dyn Transport>
+}
+
+#[async_trait::async_trait]
+impl Transport for RecordingTransport {
+  async fn call(&self, method: &str, args: &[u8]) -> KongResult<Vec<u8>> {
+    let result = self.inner.call(method, args).await;
+    let (reply, error) = match &result {
+      Ok(reply) => (reply.clone(), None),
+      Err(e) => (vec![], Some(e.to_string()))
+    };
+    self.recorder.record(TraceEntry::Call { event: self.event, method: method.to_owned(), args: args.to_vec(), reply, error });
+    result
+  }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct RecordedCall {
+  pub method: String,
+  pub args: Vec<u8>,
+  pub reply: Vec<u8>,
+  pub error: Option<String>
+}
+
+#[derive(Debug, Clone)]
+pub struct RecordedEvent {
+  pub event: u64,
+  pub instance_id: i32,
+  pub phase: String,
+  // The instance's plugin and config, if its start was recorded too.
+  pub plugin: Option<String>,
+  pub config: Option<String>,
+  pub calls: Vec<RecordedCall>
+}
+
+// What replaying a trace found. Events are skipped when the trace doesn't have the start of their instance.
+#[derive(Debug, Clone, Default)]
+pub struct ReplayReport {
+  pub replayed: Vec<(RecordedEvent, Vec<CallDiff>)>,
+  pub skipped: Vec<RecordedEvent>
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum CallDiff {
+  // A recorded call the plugin no longer makes. `index` is its position among the event's calls.
+  Missing { index: usize, method: String },
+  // A call the trace has no reply for. It is answered with an empty message.
+  Unexpected { method: String, args: Vec<u8> },
+  ArgsChanged { index: usize, method: String, expected: Vec<u8>, actual: Vec<u8> }
+}
+
+impl Display for CallDiff {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    match self {
+      CallDiff::Missing { index, method } => write!(f, "call #{} to {} was not made", index, method),
+      CallDiff::Unexpected { method, args } if args.is_empty() => write!(f, "unexpected call to {}", method),
+      CallDiff::Unexpected { method, args } => write!(f, "unexpected call to {} with args {}", method, hex::encode(args)),
+      CallDiff::ArgsChanged { index, method, expected, actual } => write!(
+        f, "call #{} to {} had args {}, expected {}", index, method, hex::encode(actual), hex::encode(expected)
+      ),
+    }
+  }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Trace {
+  pub entries: Vec<TraceEntry>
+}
+
+impl Trace {
+  pub fn parse(source: &str) -> KongResult<Self> {
+    let entries = source.lines().enumerate()
+      .filter(|(_, line)| !line.trim().is_empty())
+      .map(|(i, line)| serde_json::from_str(line).map_err(|e| KongError::InvalidValueError(format!("line {}: {}", i + 1, e))))
+      .collect::<KongResult<_>>()?;
+    Ok(Self { entries })
+  }
+
+  pub fn read<P: AsRef<Path>>(path: P) -> KongResult<Self> {
+    let source = std::fs::read_to_string(path.as_ref())
+      .map_err(|e| KongError::LaunchError(format!("Could not read {}: {}", path.as_ref().display(), e)))?;
+    Self::parse(&source)
+  }
+
+  pub fn events(&self) -> Vec<RecordedEvent> {
+    let mut instances = std::collections::HashMap::new();
+    let mut events: Vec<RecordedEvent> = vec![];
+
+    for entry in &self.entries {
+      match entry {
+        TraceEntry::Instance { instance_id, name, config } => { instances.insert(*instance_id, (name.clone(), config.clone())); },
+        TraceEntry::Event { event, instance_id, phase } => {
+          let instance = instances.get(instance_id);
+          events.push(RecordedEvent {
+            event: *event,
+            instance_id: *instance_id,
+            phase: phase.clone(),
+            plugin: instance.map(|x| x.0.clone()),
+            config: instance.map(|x| x.1.clone()),
+            calls: vec![]
+          });
+        },
+        TraceEntry::Call { event, method, args, reply, error } => {
+          if let Some(recorded) = events.iter_mut().rev().find(|x| x.event == *event) {
+            recorded.calls.push(RecordedCall { method: method.clone(), args: args.clone(), reply: reply.clone(), error: error.clone() });
+          }
+        },
+        TraceEntry::Rpc { .. } => ()
+      }
+    }
+    events
+  }
+}
+
+impl RecordedEvent {
+  // Runs the event's phase again, answering each PDK call with the recorded reply (or failing it, if it failed when
+  // recorded), and returns where the calls the plugin makes now differ from the recorded ones.
+  pub async fn replay<P: ErasedPlugin + ?Sized>(&self, plugin: &P) -> KongResult<Vec<CallDiff>> {
+    let phase = Phase::try_from(self.phase.as_str())
+      .map_err(|_| KongError::InvalidValueError(format!("Unknown phase in trace: {}", self.phase)))?;
+
+    let transport = Arc::new(ReplayTransport { state: Mutex::new(ReplayState { calls: self.calls.clone(), next: 0, diffs: vec![] }) });
+    plugin._call_phase(&phase, &Pdk::new(transport.clone())).await?;
+
+    let mut state = transport.state.lock().unwrap();
+    for index in state.next..state.calls.len() {
+      let method = state.calls[index].method.clone();
+      state.diffs.push(CallDiff::Missing { index, method });
+    }
+    Ok(std::mem::take(&mut state.diffs))
+  }
+}
+
+struct ReplayState {
+  calls: Vec<RecordedCall>,
+  next: usize,
+  diffs: Vec<CallDiff>
+}
+
+struct ReplayTransport {
+  state: Mutex<ReplayState>
+}
+
+#[async_trait::async_trait]
+impl Transport for ReplayTransport {
+  async fn call(&self, method: &str, args: &[u8]) -> KongResult<Vec<u8>> {
+    let mut state = self.state.lock().unwrap();
+    let state = &mut *state;
+
+    // Calls the plugin skipped over are reported as missing, so one added or removed call doesn't throw off the rest.
+    let Some(offset) = state.calls[state.next..].iter().position(|x| x.method == method) else {
+      state.diffs.push(CallDiff::Unexpected { method: method.to_owned(), args: args.to_vec() });
+      return Ok(vec![]);
+    };
+
+    for index in state.next..state.next + offset {
+      state.diffs.push(CallDiff::Missing { index, method: state.calls[index].method.clone() });
+    }
+
+    let index = state.next + offset;
+    state.next = index + 1;
+    let recorded = &state.calls[index];
+    if recorded.args != args {
+      state.diffs.push(CallDiff::ArgsChanged { index, method: method.to_owned(), expected: recorded.args.clone(), actual: args.to_vec() });
+    }
+    match &recorded.error {
+      Some(error) => Err(KongError::InvalidValueError(error.clone())),
+      None => Ok(recorded.reply.clone())
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use std::{io::Write, sync::{atomic::{AtomicBool, Ordering}, Arc, Mutex}};
+
+  use crate::{plugin::{ErasedPlugin, Phase}, transport::Transport, KongError, KongResult, Pdk};
+
+  use super::{CallDiff, Recorder, Trace};
+
+  // Instance 2's start is missing, as in a trace started while Kong was already running.
+  const TRACE: &str = r#"
+{"type":"instance","instance_id":1,"name":"headers","config":"{}"}
+{"type":"event","event":0,"instance_id":1,"phase":"access"}
+{"type":"call","event":0,"method":"kong.request.get_header","args":"0a0161","reply":"0a0178"}
+{"type":"call","event":0,"method":"kong.request.get_header","args":"0a0162","reply":"0a0179"}
+{"type":"event","event":1,"instance_id":2,"phase":"log"}
+"#;
+
+  // Reads each of its headers in turn.
+  struct Headers(&'static [&'static str]);
+
+  #[async_trait::async_trait]
+  impl ErasedPlugin for Headers {
+    async fn _call_phase(&self, _phase: &Phase, pdk: &Pdk) -> KongResult<()> {
+      for name in self.0 {
+        pdk.request().get_header(name.to_string()).await?;
+      }
+      Ok(())
+    }
+
+    async fn _shutdown(&self) { }
+
+    fn name(&self) -> String { "headers".to_owned() }
+  }
+
+  async fn replay(headers: &'static [&'static str]) -> Vec<CallDiff> {
+    let events = Trace::parse(TRACE).unwrap().events();
+    events[0].replay(&Headers(headers)).await.unwrap()
+  }
+
+  #[test]
+  fn groups_calls_by_event() {
+    let events = Trace::parse(TRACE).unwrap().events();
+    assert_eq!(events.len(), 2);
+    assert_eq!((events[0].plugin.as_deref(), events[0].config.as_deref()), (Some("headers"), Some("{}")));
+    assert_eq!(events[0].calls.iter().map(|x| x.args.clone()).collect::<Vec<_>>(), vec![b"\x0a\x01a".to_vec(), b"\x0a\x01b".to_vec()]);
+    assert_eq!((events[1].plugin.as_ref(), events[1].calls.len()), (None, 0));
+  }
+
+  #[tokio::test]
+  async fn unchanged_calls_replay_cleanly() {
+    assert_eq!(replay(&["a", "b"]).await, vec![]);
+  }
+
+  #[tokio::test]
+  async fn reports_an_added_call() {
+    assert_eq!(replay(&["a", "b", "c"]).await, vec![
+      CallDiff::Unexpected { method: "kong.request.get_header".to_owned(), args: b"\x0a\x01c".to_vec() }
+    ]);
+  }
+
+  #[tokio::test]
+  async fn reports_a_removed_call() {
+    assert_eq!(replay(&["a"]).await, vec![
+      CallDiff::Missing { index: 1, method: "kong.request.get_header".to_owned() }
+    ]);
+  }
+
+  #[tokio::test]
+  async fn reports_changed_args() {
+    assert_eq!(replay(&["a", "c"]).await, vec![
+      CallDiff::ArgsChanged { index: 1, method: "kong.request.get_header".to_owned(), expected: b"\x0a\x01b".to_vec(), actual: b"\x0a\x01c".to_vec() }
+    ]);
+  }
+
+  #[test]
+  fn rejects_malformed_lines() {
+    let error = Trace::parse("{\"type\":\"event\"}").unwrap_err();
+    assert!(error.to_string().contains("line 1"), "{}", error);
+  }
+
+  #[derive(Clone, Default)]
+  struct Buffer(Arc<Mutex<Vec<u8>>>);
+
+  impl Write for Buffer {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+      self.0.lock().unwrap().extend_from_slice(buf);
+      Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> { Ok(()) }
+  }
+
+  // Answers the first call, and fails the rest as a dropped connection would.
+  struct Flaky(AtomicBool);
+
+  #[async_trait::async_trait]
+  impl Transport for Flaky {
+    async fn call(&self, _method: &str, _args: &[u8]) -> KongResult<Vec<u8>> {
+      match self.0.swap(true, Ordering::Relaxed) {
+        false => Ok(b"\x0a\x01x".to_vec()),
+        true => Err(KongError::ConnectionClosed)
+      }
+    }
+  }
+
+  #[tokio::test]
+  async fn records_and_replays_failed_calls() {
+    let buffer = Buffer::default();
+    let recorder = Recorder::new(buffer.clone());
+    let transport = recorder.event(1, &Phase::Access, Arc::new(Flaky(AtomicBool::new(false))));
+    assert!(Headers(&["a", "b"])._call_phase(&Phase::Access, &Pdk::new(transport)).await.is_err());
+    recorder.flush().await;
+
+    let trace = String::from_utf8(buffer.0.lock().unwrap().clone()).unwrap();
+    let events = Trace::parse(&trace).unwrap().events();
+    assert_eq!(events[0].calls.iter().map(|x| x.error.as_deref()).collect::<Vec<_>>(), vec![None, Some("Connection closed")]);
+
+    let error = events[0].replay(&Headers(&["a", "b"])).await.unwrap_err();
+    assert!(error.to_string().contains("Connection closed"), "{}", error);
+  }
+}
@@ -0,0 +1,88 @@
+use prost::Message;
+
+use crate::{stream::Stream, KongResult};
+
+// How PDK calls reach Kong. Stream speaks the plugin socket's framing, but anything that can answer a call
+// (an in-memory fake, a recorder, a remote connection) can stand in for it without plugins noticing.
+#[async_trait::async_trait]
+pub trait Transport: Send + Sync {
+  // Sends a method with its encoded arguments, which are empty for methods that take none, and returns
+  // Kong's encoded reply.
+  async fn call(&self, method: &str, args: &[u8]) -> KongResult<Vec<u8>>;
+}
+
+#[async_trait::async_trait]
+impl Transport for Stream {
+  async fn call(&self, method: &str, args: &[u8]) -> KongResult<Vec<u8>> {
+    self.write_frame(method.as_bytes()).await?;
+    self.write_frame(args).await?;
+    self.read_frame().await
+  }
+}
+
+impl dyn Transport {
+  pub async fn ask<T: Message>(&self, method: &str, args: &T) -> KongResult<()> {
+    self.call(method, &args.encode_to_vec()).await?;
+    Ok(())
+  }
+
+  pub async fn ask_message_with_args<T: Message, R: Message + Default>(
+    &self,
+    method: &str,
+    args: &T,
+  ) -> KongResult<R> {
+    let out = self.call(method, &args.encode_to_vec()).await?;
+    Ok(R::decode(&*out)?)
+  }
+
+  pub async fn ask_message<R: Message + Default>(
+    &self,
+    method: &str,
+  ) -> KongResult<R> {
+    let out = self.call(method, &[]).await?;
+    Ok(R::decode(&*out)?)
+  }
+
+  #[allow(dead_code)]
+  pub async fn send_string(&self, method: &str, v: String) -> KongResult<()> {
+    self.ask(method, &kong_rs_protos::String { v }).await
+  }
+
+  pub async fn send_int(&self, method: &str, v: i32) -> KongResult<()> {
+    self.ask(method, &kong_rs_protos::Int { v }).await
+  }
+
+  pub async fn ask_string(&self, method: &str) -> KongResult<String> {
+    let s: kong_rs_protos::String = self.ask_message(method).await?;
+    Ok(s.v)
+  }
+
+  pub async fn ask_string_with_args<T: Message>(
+    &self,
+    method: &str,
+    args: &T,
+  ) -> KongResult<String> {
+    let s: kong_rs_protos::String = self.ask_message_with_args(method, args).await?;
+    Ok(s.v)
+  }
+
+  pub async fn ask_int(&self, method: &str) -> KongResult<i32> {
+    let s: kong_rs_protos::Int = self.ask_message(method).await?;
+    Ok(s.v)
+  }
+
+  #[allow(dead_code)]
+  pub async fn ask_int_with_args<T: Message>(
+    &self,
+    method: &str,
+    args: &T,
+  ) -> KongResult<i32> {
+    let s: kong_rs_protos::Int = self.ask_message_with_args(method, args).await?;
+    Ok(s.v)
+  }
+
+  pub async fn ask_number(&self, method: &str) -> KongResult<f64> {
+    let s: kong_rs_protos::Number = self.ask_message(method).await?;
+    Ok(s.v)
+  }
+}
@@ -0,0 +1,5 @@
+// The configs #[derive(PluginConfig)] refuses, rather than rendering a schema that doesn't match them.
+#[test]
+fn rejects_unsupported_configs() {
+  trybuild::TestCases::new().compile_fail("tests/ui/*.rs");
+}
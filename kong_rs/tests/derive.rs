@@ -0,0 +1,361 @@
+// Checks the schemas #[derive(PluginConfig)] renders, and that parse_config accepts and rejects configs the way
+// Kong would with that schema.
+
+use std::collections::HashMap;
+
+use kong_rs::{config::{self, PluginConfig, PluginConfigFieldVariant}, KongError, PluginConfig};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+
+fn schema<C: PluginConfig + Default>() -> Value {
+  serde_json::to_value(C::default().render_this()).unwrap()
+}
+
+fn fields(schema: &Value) -> Vec<&str> {
+  schema["fields"].as_array().unwrap().iter().map(|x| x.as_object().unwrap().keys().next().unwrap().as_str()).collect()
+}
+
+fn field<'a>(schema: &'a Value, name: &str) -> &'a Value {
+  schema["fields"].as_array().unwrap().iter().find_map(|x| x.get(name)).unwrap()
+}
+
+fn parse<C: PluginConfig + Default>(config: Value) -> C {
+  config::parse_config_value(config, &C::default().render_this()).unwrap()
+}
+
+// The paths of the errors a config is rejected with.
+fn rejects<C: PluginConfig + Default + std::fmt::Debug>(config: Value) -> Vec<String> {
+  match config::parse_config_value::<C>(config, &C::default().render_this()) {
+    Err(KongError::ConfigError(errors)) => errors.into_iter().map(|x| x.path).collect(),
+    x => panic!("expected config errors, got {:?}", x)
+  }
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize, PluginConfig)]
+struct Fields {
+  #[kong(description = "A tag", len_min = 2, len_max = 8, match = "^%w+$", not_match = "^admin$")]
+  tag: String,
+  #[kong(default = 3, between = [1, 10])]
+  retries: u8,
+  #[kong(gt = 0)]
+  timeout: f64,
+  #[kong(one_of = ["GET", "POST"])]
+  method: String,
+  #[kong(referenceable, encrypted)]
+  secret: Option<String>
+}
+
+impl Default for Fields {
+  fn default() -> Self {
+    Self { tag: "main".to_owned(), retries: 3, timeout: 1.5, method: "GET".to_owned(), secret: None }
+  }
+}
+
+#[test]
+fn renders_field_attributes() {
+  assert_eq!(schema::<Fields>(), json!({
+    "type": "record",
+    "fields": [
+      { "tag": { "type": "string", "required": true, "default": "main", "description": "A tag", "len_min": 2, "len_max": 8, "match": "^%w+$", "not_match": "^admin$" } },
+      { "retries": { "type": "integer", "required": true, "default": 3, "between": [1, 10] } },
+      { "timeout": { "type": "number", "required": true, "default": 1.5, "gt": 0 } },
+      { "method": { "type": "string", "required": true, "default": "GET", "one_of": ["GET", "POST"] } },
+      { "secret": { "type": "string", "required": false, "referenceable": true, "encrypted": true } }
+    ]
+  }));
+}
+
+#[test]
+fn checks_field_attributes() {
+  assert_eq!(parse::<Fields>(json!({})), Fields::default());
+  assert_eq!(
+    parse::<Fields>(json!({ "tag": "edge", "retries": 10, "timeout": 0.1, "method": "POST", "secret": "s3cr3t" })),
+    Fields { tag: "edge".to_owned(), retries: 10, timeout: 0.1, method: "POST".to_owned(), secret: Some("s3cr3t".to_owned()) }
+  );
+
+  assert_eq!(rejects::<Fields>(json!({ "tag": "a" })), ["tag"]);
+  assert_eq!(rejects::<Fields>(json!({ "tag": "muchtoolong" })), ["tag"]);
+  assert_eq!(rejects::<Fields>(json!({ "tag": "a-b" })), ["tag"]);
+  assert_eq!(rejects::<Fields>(json!({ "tag": "admin" })), ["tag"]);
+  assert_eq!(rejects::<Fields>(json!({ "retries": 0 })), ["retries"]);
+  assert_eq!(rejects::<Fields>(json!({ "retries": 11 })), ["retries"]);
+  assert_eq!(rejects::<Fields>(json!({ "timeout": 0 })), ["timeout"]);
+  assert_eq!(rejects::<Fields>(json!({ "method": "PUT" })), ["method"]);
+  assert_eq!(rejects::<Fields>(json!({ "unknown": true })), ["unknown"]);
+}
+
+#[derive(Debug, Default, PartialEq, Serialize, Deserialize, PluginConfig)]
+#[kong(at_least_one_of(a, b), mutually_required(c, d))]
+#[kong(mutually_exclusive(b, e))]
+#[kong(conditional(if_field = mode, if_eq = "strict", then_field = a, then_required))]
+#[kong(conditional(if_field = mode, if_one_of = ["off", "none"], then_field = c, then_eq = 0))]
+struct Checks {
+  a: Option<String>,
+  b: Option<String>,
+  c: Option<u32>,
+  d: Option<u32>,
+  e: Option<String>,
+  mode: String
+}
+
+#[test]
+fn renders_entity_checks() {
+  assert_eq!(schema::<Checks>()["entity_checks"], json!([
+    { "at_least_one_of": ["a", "b"] },
+    { "mutually_required": ["c", "d"] },
+    { "mutually_exclusive": ["b", "e"] },
+    { "conditional": { "if_field": "mode", "if_match": { "eq": "strict" }, "then_field": "a", "then_match": { "required": true } } },
+    { "conditional": { "if_field": "mode", "if_match": { "one_of": ["off", "none"] }, "then_field": "c", "then_match": { "eq": 0 } } }
+  ]));
+}
+
+#[test]
+fn checks_entity_checks() {
+  assert_eq!(parse::<Checks>(json!({ "a": "x", "c": 1, "d": 2 })).a.as_deref(), Some("x"));
+  assert_eq!(parse::<Checks>(json!({ "b": "x", "mode": "strict", "a": "y" })).mode, "strict");
+  assert_eq!(parse::<Checks>(json!({ "b": "x", "mode": "off" })).c, None);
+  assert_eq!(parse::<Checks>(json!({ "b": "x", "mode": "off", "c": 0, "d": 0 })).c, Some(0));
+
+  // at_least_one_of
+  assert_eq!(rejects::<Checks>(json!({})).len(), 1);
+  // mutually_required
+  assert_eq!(rejects::<Checks>(json!({ "a": "x", "c": 1 })).len(), 1);
+  // mutually_exclusive
+  assert_eq!(rejects::<Checks>(json!({ "b": "x", "e": "y" })).len(), 1);
+  // The conditionals.
+  assert_eq!(rejects::<Checks>(json!({ "b": "x", "mode": "strict" })), ["a"]);
+  assert_eq!(rejects::<Checks>(json!({ "b": "x", "mode": "none", "c": 1, "d": 1 })), ["c"]);
+}
+
+#[derive(Debug, Default, PartialEq, Serialize, Deserialize, PluginConfig)]
+#[kong(only_one_of(a, b))]
+struct OneOf {
+  a: Option<String>,
+  b: Option<String>
+}
+
+#[test]
+fn checks_only_one_of() {
+  assert_eq!(schema::<OneOf>()["entity_checks"], json!([{ "only_one_of": ["a", "b"] }]));
+
+  assert_eq!(parse::<OneOf>(json!({ "b": "x" })).b.as_deref(), Some("x"));
+  assert_eq!(rejects::<OneOf>(json!({})), [""]);
+  assert_eq!(rejects::<OneOf>(json!({ "a": "x", "b": "y" })), [""]);
+}
+
+#[derive(Debug, Default, PartialEq, Serialize, Deserialize, PluginConfig)]
+#[serde(rename_all = "kebab-case")]
+enum Level {
+  #[default]
+  Info,
+  #[serde(alias = "warn")]
+  Warning,
+  #[serde(rename = "ERR")]
+  Error
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize, PluginConfig)]
+#[serde(rename_all = "snake_case", rename_all_fields = "camelCase")]
+enum Sink {
+  File { file_path: String },
+  Udp { host_name: String, #[serde(rename = "PORT")] port: u16 }
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize, PluginConfig)]
+#[serde(rename_all = "camelCase")]
+#[kong(mutually_exclusive(log_level, r#type))]
+struct Renamed {
+  log_level: Option<Level>,
+  #[serde(rename(serialize = "ignored", deserialize = "x-type"))]
+  r#type: Option<String>,
+  sink: Sink
+}
+
+impl Default for Renamed {
+  fn default() -> Self {
+    Self { log_level: None, r#type: None, sink: Sink::File { file_path: "/tmp/log".to_owned() } }
+  }
+}
+
+#[test]
+fn renders_renames() {
+  let schema = schema::<Renamed>();
+  assert_eq!(fields(&schema), ["logLevel", "x-type", "sink"]);
+  assert_eq!(field(&schema, "logLevel")["one_of"], json!(["info", "warning", "warn", "ERR"]));
+  assert_eq!(schema["entity_checks"], json!([{ "mutually_exclusive": ["logLevel", "x-type"] }]));
+
+  let sink = field(&schema, "sink");
+  assert_eq!(fields(sink), ["file", "udp"]);
+  assert_eq!(fields(field(sink, "file")), ["filePath"]);
+  assert_eq!(fields(field(sink, "udp")), ["hostName", "PORT"]);
+}
+
+#[test]
+fn checks_renames() {
+  let config = parse::<Renamed>(json!({ "logLevel": "warn", "sink": { "udp": { "hostName": "localhost", "PORT": 514 } } }));
+  assert_eq!(config.log_level, Some(Level::Warning));
+  assert_eq!(config.sink, Sink::Udp { host_name: "localhost".to_owned(), port: 514 });
+  assert_eq!(parse::<Renamed>(json!({ "x-type": "json" })).r#type.as_deref(), Some("json"));
+
+  assert_eq!(rejects::<Renamed>(json!({ "logLevel": "Info" })), ["logLevel"]);
+  assert_eq!(rejects::<Renamed>(json!({ "log_level": "info" })), ["log_level"]);
+  assert_eq!(rejects::<Renamed>(json!({ "logLevel": "info", "x-type": "json" })).len(), 1);
+  assert_eq!(rejects::<Renamed>(json!({ "sink": { "udp": { "host_name": "localhost", "PORT": 514 } } })).len(), 2);
+}
+
+fn default_port() -> u16 { 8125 }
+
+#[derive(Debug, Default, PartialEq, Serialize, Deserialize, PluginConfig)]
+#[kong(at_least_one_of(host, socket))]
+struct Endpoint {
+  host: Option<String>,
+  socket: Option<String>,
+  #[serde(default = "default_port")]
+  port: u16
+}
+
+#[derive(Debug, Default, PartialEq, Serialize, Deserialize, PluginConfig)]
+struct Stats {
+  #[serde(flatten)]
+  endpoint: Endpoint,
+  #[serde(default)]
+  prefix: String,
+  #[serde(skip)]
+  connections: u32,
+  nested: Option<Endpoint>
+}
+
+#[test]
+fn renders_skip_default_and_flatten() {
+  let schema = schema::<Stats>();
+  assert_eq!(fields(&schema), ["host", "socket", "port", "prefix", "nested"]);
+  assert_eq!(schema["entity_checks"], json!([{ "at_least_one_of": ["host", "socket"] }]));
+
+  // Rendered without a value to take defaults from, fields fall back on their serde defaults.
+  let nested = field(&schema, "nested");
+  assert_eq!(nested["required"], json!(false));
+  assert_eq!(field(nested, "port"), &json!({ "type": "integer", "required": true, "default": 8125, "between": [0, 65535] }));
+  assert_eq!(field(nested, "host"), &json!({ "type": "string", "required": false }));
+}
+
+#[test]
+fn checks_skip_default_and_flatten() {
+  let config = parse::<Stats>(json!({ "host": "localhost", "nested": { "socket": "/run/statsd" } }));
+  assert_eq!(config.endpoint, Endpoint { host: Some("localhost".to_owned()), socket: None, port: 0 });
+  assert_eq!(config.nested, Some(Endpoint { host: None, socket: Some("/run/statsd".to_owned()), port: 8125 }));
+
+  assert_eq!(rejects::<Stats>(json!({})).len(), 1);
+  assert_eq!(rejects::<Stats>(json!({ "host": "localhost", "nested": {} })).len(), 1);
+  assert_eq!(rejects::<Stats>(json!({ "host": "localhost", "connections": 1 })), ["connections"]);
+  assert_eq!(rejects::<Stats>(json!({ "host": "localhost", "endpoint": {} })), ["endpoint"]);
+}
+
+#[derive(Debug, Default, PartialEq, Serialize, Deserialize, PluginConfig)]
+struct Port(#[kong(between = [1, 65535])] u16);
+
+#[derive(Debug, Default, PartialEq, Serialize, Deserialize, PluginConfig)]
+struct Generic<T, const N: usize> where T: Clone {
+  #[kong(len_max = N)]
+  values: Vec<T>,
+  lookup: HashMap<String, T>,
+  port: Port
+}
+
+#[test]
+fn renders_generics_and_newtypes() {
+  let schema = schema::<Generic<u8, 2>>();
+  assert_eq!(field(&schema, "values"), &json!({
+    "type": "array", "required": true, "default": [], "len_max": 2,
+    "elements": { "type": "integer", "between": [0, 255] }
+  }));
+  assert_eq!(field(&schema, "lookup")["values"], json!({ "type": "integer", "between": [0, 255] }));
+  assert_eq!(field(&schema, "port"), &json!({ "type": "integer", "required": true, "default": 0, "between": [1, 65535] }));
+}
+
+#[test]
+fn checks_generics_and_newtypes() {
+  let config = parse::<Generic<u8, 2>>(json!({ "values": [1, 2], "lookup": { "a": 3 }, "port": 8000 }));
+  assert_eq!(config, Generic { values: vec![1, 2], lookup: HashMap::from([("a".to_owned(), 3)]), port: Port(8000) });
+
+  assert_eq!(rejects::<Generic<u8, 2>>(json!({ "values": [1, 2, 3], "port": 1 })), ["values"]);
+  assert_eq!(rejects::<Generic<u8, 2>>(json!({ "values": [256], "port": 1 })), ["values[0]"]);
+  assert_eq!(rejects::<Generic<u8, 2>>(json!({ "port": 0 })), ["port"]);
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize, PluginConfig)]
+#[serde(rename_all = "snake_case")]
+enum Auth {
+  Basic { user: String, #[kong(len_min = 8)] password: String },
+  Token(#[kong(match = "^%x+$")] String),
+  #[serde(skip)]
+  #[allow(dead_code)]
+  Internal(u32)
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize, PluginConfig)]
+struct Upstream {
+  auth: Auth
+}
+
+impl Default for Upstream {
+  fn default() -> Self {
+    Self { auth: Auth::Token("ff".to_owned()) }
+  }
+}
+
+#[test]
+fn renders_data_enums() {
+  assert_eq!(field(&schema::<Upstream>(), "auth"), &json!({
+    "type": "record",
+    "required": true,
+    "default": { "token": "ff" },
+    "fields": [
+      { "basic": { "type": "record", "required": false, "fields": [
+        { "user": { "type": "string", "required": true } },
+        { "password": { "type": "string", "required": true, "len_min": 8 } }
+      ] } },
+      { "token": { "type": "string", "required": false, "match": "^%x+$" } }
+    ],
+    "entity_checks": [{ "only_one_of": ["basic", "token"] }]
+  }));
+}
+
+#[test]
+fn checks_data_enums() {
+  assert_eq!(parse::<Upstream>(json!({})), Upstream::default());
+  assert_eq!(parse::<Upstream>(json!({ "auth": { "token": "c0ffee" } })).auth, Auth::Token("c0ffee".to_owned()));
+  assert_eq!(
+    parse::<Upstream>(json!({ "auth": { "basic": { "user": "kong", "password": "hunter22" } } })).auth,
+    Auth::Basic { user: "kong".to_owned(), password: "hunter22".to_owned() }
+  );
+
+  assert_eq!(rejects::<Upstream>(json!({ "auth": { "token": "tea" } })), ["auth.token"]);
+  assert_eq!(rejects::<Upstream>(json!({ "auth": { "basic": { "user": "kong", "password": "short" } } })), ["auth.basic.password"]);
+  assert_eq!(rejects::<Upstream>(json!({ "auth": { "token": "ff", "basic": { "user": "kong", "password": "hunter22" } } })).len(), 1);
+  assert_eq!(rejects::<Upstream>(json!({ "auth": {} })).len(), 1);
+  assert_eq!(rejects::<Upstream>(json!({ "auth": { "internal": 1 } })), ["auth.internal", "auth"]);
+}
+
+#[derive(Debug, Serialize, Deserialize, PluginConfig)]
+struct Conflicting {
+  #[kong(default = 5)]
+  retries: u8,
+  #[kong(default = 1.0)]
+  ratio: f64
+}
+
+impl Default for Conflicting {
+  fn default() -> Self {
+    Self { retries: 3, ratio: 1.0 }
+  }
+}
+
+#[test]
+fn reports_kong_defaults_that_disagree_with_default_impls() {
+  let errors = config::check_defaults(&Conflicting::default().render_this().fields.unwrap()).unwrap_err();
+  assert_eq!(errors.len(), 1);
+  assert_eq!(errors[0].path, "retries");
+  assert!(errors[0].message.contains("#[kong(default = 5)]"), "{}", errors[0].message);
+
+  assert!(config::check_defaults(&Fields::default().render_this().fields.unwrap()).is_ok());
+}
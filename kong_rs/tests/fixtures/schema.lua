@@ -0,0 +1,138 @@
+return {
+  name = "golden",
+  fields = {
+    { greeting = {
+      type = "string",
+      default = "hello",
+      description = "Says \"hi\",\nthen leaves",
+      match = "^[%w%-]+$",
+      required = true,
+    } },
+    { format = {
+      type = "string",
+      default = "json",
+      one_of = { "json", "text" },
+      required = true,
+    } },
+    { interval = {
+      type = "integer",
+      between = { 0, 4294967295 },
+      default = 5,
+      one_of = { 1, 5, 15 },
+      required = true,
+    } },
+    { sample_rate = {
+      type = "number",
+      between = { 0.0, 1.0 },
+      default = 0.5,
+      required = true,
+    } },
+    { endpoint = {
+      type = "record",
+      entity_checks = {
+        { at_least_one_of = { "host", "socket" } },
+        { mutually_exclusive = { "host", "socket" } },
+        { conditional = {
+          if_field = "tls",
+          if_match = {
+            eq = true,
+          },
+          then_field = "host",
+          then_match = {
+            required = true,
+          },
+        } },
+      },
+      fields = {
+        { host = {
+          type = "string",
+          required = false,
+        } },
+        { socket = {
+          type = "string",
+          required = false,
+        } },
+        { port = {
+          type = "integer",
+          between = { 1, 65535 },
+          default = 8125,
+          required = true,
+        } },
+        { tls = {
+          type = "boolean",
+          default = false,
+          required = true,
+        } },
+      },
+      required = true,
+    } },
+    { fallbacks = {
+      type = "array",
+      default = { },
+      elements = {
+        type = "record",
+        entity_checks = {
+          { at_least_one_of = { "host", "socket" } },
+          { mutually_exclusive = { "host", "socket" } },
+          { conditional = {
+            if_field = "tls",
+            if_match = {
+              eq = true,
+            },
+            then_field = "host",
+            then_match = {
+              required = true,
+            },
+          } },
+        },
+        fields = {
+          { host = {
+            type = "string",
+            required = false,
+          } },
+          { socket = {
+            type = "string",
+            required = false,
+          } },
+          { port = {
+            type = "integer",
+            between = { 1, 65535 },
+            required = true,
+          } },
+          { tls = {
+            type = "boolean",
+            required = true,
+          } },
+        },
+      },
+      len_max = 4,
+      required = true,
+    } },
+    { tags = {
+      type = "set",
+      default = { "edge", "say \"cheese\"" },
+      elements = {
+        type = "string",
+      },
+      required = true,
+    } },
+    { ["x-headers"] = {
+      type = "map",
+      default = {
+        ["x-note"] = "line one\nline two",
+      },
+      keys = {
+        type = "string",
+      },
+      required = true,
+      values = {
+        type = "string",
+      },
+    } },
+    { ["end"] = {
+      type = "integer",
+      between = { 0, 4294967295 },
+      required = false,
+    } },
+  },
+}
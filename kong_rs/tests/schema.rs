@@ -0,0 +1,105 @@
+// Golden tests for the schema.lua and JSON Schema renderings of a config. Run with BLESS=1 to rewrite the expected
+// files after an intended change, and review the diff.
+
+use std::collections::{BTreeMap, BTreeSet};
+
+use kong_rs::{config::{self, PluginConfigFieldVariant}, PluginConfig};
+use serde::{Deserialize, Serialize};
+
+#[derive(Serialize, Deserialize, PluginConfig)]
+#[serde(rename_all = "snake_case")]
+enum Format {
+  Json,
+  Text
+}
+
+#[derive(Serialize, Deserialize, PluginConfig)]
+#[kong(at_least_one_of(host, socket), mutually_exclusive(host, socket))]
+#[kong(conditional(if_field = tls, if_eq = true, then_field = host, then_required))]
+struct Endpoint {
+  host: Option<String>,
+  socket: Option<String>,
+  #[kong(between = [1, 65535])]
+  port: u16,
+  tls: bool
+}
+
+#[derive(Serialize, Deserialize, PluginConfig)]
+struct Config {
+  #[kong(description = "Says \"hi\",\nthen leaves", match = "^[%w%-]+$")]
+  greeting: String,
+  format: Format,
+  #[kong(one_of = [1, 5, 15])]
+  interval: u32,
+  #[kong(between = [0.0, 1.0])]
+  sample_rate: f64,
+  endpoint: Endpoint,
+  #[kong(len_max = 4)]
+  fallbacks: Vec<Endpoint>,
+  tags: BTreeSet<String>,
+  #[serde(rename = "x-headers")]
+  headers: BTreeMap<String, String>,
+  #[serde(rename = "end")]
+  end: Option<u32>
+}
+
+impl Default for Config {
+  fn default() -> Self {
+    Self {
+      greeting: "hello".to_owned(),
+      format: Format::Json,
+      interval: 5,
+      sample_rate: 0.5,
+      endpoint: Endpoint { host: Some("localhost".to_owned()), socket: None, port: 8125, tls: false },
+      fallbacks: vec![],
+      tags: BTreeSet::from(["edge".to_owned(), "say \"cheese\"".to_owned()]),
+      headers: BTreeMap::from([("x-note".to_owned(), "line one\nline two".to_owned())]),
+      end: None
+    }
+  }
+}
+
+fn check_golden(path: &str, actual: &str) {
+  let path = std::path::Path::new(env!("CARGO_MANIFEST_DIR")).join(path);
+  if std::env::var_os("BLESS").is_some() {
+    std::fs::write(&path, actual).unwrap();
+    return;
+  }
+
+  let expected = std::fs::read_to_string(&path).unwrap();
+  assert!(expected == actual, "{} is out of date (rerun with BLESS=1 to update it); got:\n{}", path.display(), actual);
+}
+
+#[test]
+fn renders_lua_schema() {
+  let lua = config::lua_schema("golden", &Config::default().render_this().fields.unwrap());
+  check_golden("tests/fixtures/schema.lua", &lua);
+
+  // The module has to load in the LuaJIT Kong runs on, and its strings have to survive the round trip.
+  let vm = mlua::Lua::new();
+  let schema: mlua::Table = vm.load(&lua).eval().unwrap();
+  assert_eq!(schema.get::<_, String>("name").unwrap(), "golden");
+
+  let fields: Vec<mlua::Table> = schema.get::<_, mlua::Table>("fields").unwrap().sequence_values().collect::<Result<_, _>>().unwrap();
+  let field = |name: &str| -> mlua::Table {
+    fields.iter().find_map(|x| x.get::<_, Option<mlua::Table>>(name).unwrap()).unwrap()
+  };
+
+  let greeting = field("greeting");
+  assert_eq!(greeting.get::<_, String>("description").unwrap(), "Says \"hi\",\nthen leaves");
+  assert_eq!(greeting.get::<_, String>("match").unwrap(), "^[%w%-]+$");
+
+  let tags: Vec<String> = field("tags").get::<_, mlua::Table>("default").unwrap().sequence_values().collect::<Result<_, _>>().unwrap();
+  assert_eq!(tags, ["edge", "say \"cheese\""]);
+  let headers = field("x-headers").get::<_, mlua::Table>("default").unwrap();
+  assert_eq!(headers.get::<_, String>("x-note").unwrap(), "line one\nline two");
+
+  assert_eq!(field("end").get::<_, String>("type").unwrap(), "integer");
+  assert_eq!(field("endpoint").get::<_, mlua::Table>("entity_checks").unwrap().len().unwrap(), 3);
+}
+
+#[test]
+fn renders_json_schema() {
+  let schema = config::json_schema("golden", &Config::default().render_this().fields.unwrap());
+  check_golden("tests/fixtures/schema.json", &(serde_json::to_string_pretty(&schema).unwrap() + "\n"));
+}
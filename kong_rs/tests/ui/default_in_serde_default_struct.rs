@@ -0,0 +1,8 @@
+#[derive(Default, serde::Serialize, serde::Deserialize, kong_rs::PluginConfig)]
+#[serde(default)]
+struct Config {
+  #[kong(default = 3)]
+  retries: u8
+}
+
+fn main() {}
@@ -0,0 +1,7 @@
+#[derive(serde::Serialize, serde::Deserialize, kong_rs::PluginConfig)]
+enum Auth {
+  Token(#[kong(default = "secret")] String),
+  Key(String)
+}
+
+fn main() {}
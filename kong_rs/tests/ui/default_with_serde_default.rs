@@ -0,0 +1,8 @@
+#[derive(serde::Serialize, serde::Deserialize, kong_rs::PluginConfig)]
+struct Config {
+  #[serde(default)]
+  #[kong(default = 3)]
+  retries: u8
+}
+
+fn main() {}
@@ -0,0 +1,7 @@
+#[derive(serde::Serialize, serde::Deserialize, kong_rs::PluginConfig)]
+#[kong(description = "The config")]
+struct Config {
+  host: String
+}
+
+fn main() {}
@@ -0,0 +1,10 @@
+use std::collections::HashMap;
+
+#[derive(serde::Serialize, serde::Deserialize, kong_rs::PluginConfig)]
+struct Config {
+  host: String,
+  #[serde(flatten)]
+  extra: HashMap<String, String>
+}
+
+fn main() {}
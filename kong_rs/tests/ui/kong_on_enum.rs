@@ -0,0 +1,8 @@
+#[derive(serde::Serialize, serde::Deserialize, kong_rs::PluginConfig)]
+#[kong(only_one_of(a, b))]
+enum Mode {
+  A,
+  B
+}
+
+fn main() {}
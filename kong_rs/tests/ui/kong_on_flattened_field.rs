@@ -0,0 +1,13 @@
+#[derive(serde::Serialize, serde::Deserialize, kong_rs::PluginConfig)]
+struct Endpoint {
+  host: String
+}
+
+#[derive(serde::Serialize, serde::Deserialize, kong_rs::PluginConfig)]
+struct Config {
+  #[serde(flatten)]
+  #[kong(description = "Where to send to")]
+  endpoint: Endpoint
+}
+
+fn main() {}
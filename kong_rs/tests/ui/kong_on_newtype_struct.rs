@@ -0,0 +1,5 @@
+#[derive(serde::Serialize, serde::Deserialize, kong_rs::PluginConfig)]
+#[kong(between = [1, 65535])]
+struct Port(u16);
+
+fn main() {}
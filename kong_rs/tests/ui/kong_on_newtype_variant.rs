@@ -0,0 +1,8 @@
+#[derive(serde::Serialize, serde::Deserialize, kong_rs::PluginConfig)]
+enum Auth {
+  #[kong(len_min = 8)]
+  Token(String),
+  Key(String)
+}
+
+fn main() {}
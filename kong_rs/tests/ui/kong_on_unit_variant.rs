@@ -0,0 +1,8 @@
+#[derive(serde::Serialize, serde::Deserialize, kong_rs::PluginConfig)]
+enum Mode {
+  #[kong(description = "The first mode")]
+  A,
+  B
+}
+
+fn main() {}
@@ -0,0 +1,7 @@
+#[derive(serde::Serialize, serde::Deserialize, kong_rs::PluginConfig)]
+struct Config {
+  #[serde(alias = "retry_count")]
+  retries: u8
+}
+
+fn main() {}
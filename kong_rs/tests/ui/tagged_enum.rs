@@ -0,0 +1,8 @@
+#[derive(serde::Serialize, serde::Deserialize, kong_rs::PluginConfig)]
+#[serde(tag = "type")]
+enum Auth {
+  Token { token: String },
+  Key { key: String }
+}
+
+fn main() {}
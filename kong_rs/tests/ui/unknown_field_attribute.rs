@@ -0,0 +1,7 @@
+#[derive(serde::Serialize, serde::Deserialize, kong_rs::PluginConfig)]
+struct Config {
+  #[kong(min = 1)]
+  retries: u8
+}
+
+fn main() {}
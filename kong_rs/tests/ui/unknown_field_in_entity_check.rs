@@ -0,0 +1,8 @@
+#[derive(serde::Serialize, serde::Deserialize, kong_rs::PluginConfig)]
+#[kong(at_least_one_of(host, socket))]
+struct Config {
+  host: Option<String>,
+  path: Option<String>
+}
+
+fn main() {}
@@ -1,69 +1,488 @@
 use proc_macro::TokenStream;
+use proc_macro2::TokenStream as TokenStream2;
 use quote::quote;
-use syn::{parse_macro_input, DeriveInput};
+use syn::{ext::IdentExt, parse_macro_input, DeriveInput, Expr, LitStr};
 
-#[proc_macro_derive(PluginConfig)]
-pub fn plugin_config_derive(item: TokenStream) -> TokenStream {
-  let DeriveInput {
-    attrs: _, vis: _, ident, generics: _, data
-  } = parse_macro_input!(item as DeriveInput);
+mod serde_attrs;
 
-  match data {
-    syn::Data::Struct(st) => {
-      let (without_default, with_default): (Vec<_>, Vec<_>) = st.fields.into_iter().map(|field| {
-        let ident = field.ident.expect("Struct fields must be named");
-        let name = ident.to_string();
-        let ty = field.ty;
-        
-        (
-          quote! { std::collections::HashMap::from([(#name.to_owned(), <#ty as kong_rs::config::PluginConfigFieldVariant>::render(None, false))]) },
-          quote! { std::collections::HashMap::from([(#name.to_owned(), <#ty as kong_rs::config::PluginConfigFieldVariant>::render(Some(default.#ident), false))]) }
-        )
-      }).unzip();
+// Rejects #[kong(...)] attributes where there is nothing for them to apply to, rather than dropping them.
+fn no_kong_attrs(attrs: &[syn::Attribute], message: &str) -> syn::Result<()> {
+  match attrs.iter().find(|x| x.path().is_ident("kong")) {
+    Some(attr) => Err(syn::Error::new_spanned(attr, message)),
+    None => Ok(())
+  }
+}
 
-      quote! {
-        impl kong_rs::config::PluginConfigFieldVariant for #ident {
-          fn ty() -> &'static str { "record" }
-          fn render(default: Option<Self>, skip_required: bool) -> kong_rs::config::RenderedConfigFieldVariant {
-            let mut required = Some(Self::required());
-            if skip_required {
-              required = None;
+// Parses the #[kong(...)] attributes on a field into statements that override parts of its rendered schema. `name`
+// identifies the field in errors. `no_default` is why a default can't be given, where one can't.
+fn field_overrides(attrs: &[syn::Attribute], name: &str, no_default: Option<&str>) -> syn::Result<Vec<TokenStream2>> {
+  let mut overrides = vec![];
+
+  for attr in attrs.iter().filter(|x| x.path().is_ident("kong")) {
+    attr.parse_nested_meta(|meta| {
+      let key = meta.path.get_ident().map(|x| x.unraw().to_string()).unwrap_or_default();
+
+      let stmt = match key.as_str() {
+        "default" if no_default.is_some() => return Err(meta.error(no_default.unwrap())),
+        "default" => {
+          let value: Expr = meta.value()?.parse()?;
+          quote! { kong_rs::config::override_default(&mut field, #name, kong_rs::config::to_value(#value)); }
+        },
+        "gt" => {
+          let value: Expr = meta.value()?.parse()?;
+          quote! { field.gt = Some(kong_rs::config::to_value(#value)); }
+        },
+        "description" | "match" | "not_match" => {
+          let value: LitStr = meta.value()?.parse()?;
+          let ident = syn::Ident::new_raw(&key, meta.path.get_ident().unwrap().span());
+          quote! { field.#ident = Some(#value.to_owned()); }
+        },
+        "len_min" | "len_max" => {
+          let value: Expr = meta.value()?.parse()?;
+          let ident = syn::Ident::new(&key, meta.path.get_ident().unwrap().span());
+          quote! { field.#ident = Some(#value); }
+        },
+        "between" => {
+          let value: syn::ExprArray = meta.value()?.parse()?;
+          if value.elems.len() != 2 {
+            return Err(syn::Error::new_spanned(value, "between takes exactly two values: [min, max]"));
+          }
+          let (min, max) = (&value.elems[0], &value.elems[1]);
+          quote! { field.between = Some([kong_rs::config::to_value(#min), kong_rs::config::to_value(#max)]); }
+        },
+        "one_of" => {
+          let value: syn::ExprArray = meta.value()?.parse()?;
+          let elems = value.elems.iter();
+          quote! { field.one_of = Some(vec![#(kong_rs::config::to_value(#elems)),*]); }
+        },
+        "referenceable" | "encrypted" => {
+          let ident = syn::Ident::new(&key, meta.path.get_ident().unwrap().span());
+          quote! { field.#ident = Some(true); }
+        },
+        _ => return Err(meta.error("unknown kong attribute"))
+      };
+
+      overrides.push(stmt);
+      Ok(())
+    })?;
+  }
+
+  Ok(overrides)
+}
+
+// Maps a field's ident to the name it is deserialized from.
+fn field_name(fields: &[(syn::Ident, String)], ident: syn::Ident) -> syn::Result<String> {
+  match fields.iter().find(|(x, _)| *x == ident) {
+    Some((_, name)) => Ok(name.clone()),
+    None => Err(syn::Error::new_spanned(&ident, format!("no field named `{}`", ident)))
+  }
+}
+
+fn field_list(fields: &[(syn::Ident, String)], meta: &syn::meta::ParseNestedMeta) -> syn::Result<Vec<String>> {
+  let content;
+  syn::parenthesized!(content in meta.input);
+  let idents = content.parse_terminated(syn::Ident::parse_any, syn::Token![,])?;
+  idents.into_iter().map(|x| field_name(fields, x)).collect()
+}
+
+// Parses the #[kong(...)] attributes on a struct into the entity checks of its record.
+fn entity_checks(attrs: &[syn::Attribute], fields: &[(syn::Ident, String)]) -> syn::Result<Vec<TokenStream2>> {
+  let mut checks = vec![];
+
+  for attr in attrs.iter().filter(|x| x.path().is_ident("kong")) {
+    attr.parse_nested_meta(|meta| {
+      let key = meta.path.get_ident().map(|x| x.to_string()).unwrap_or_default();
+
+      let check = match key.as_str() {
+        "at_least_one_of" | "only_one_of" | "mutually_exclusive" | "mutually_required" => {
+          let variant = match key.as_str() {
+            "at_least_one_of" => quote! { AtLeastOneOf },
+            "only_one_of" => quote! { OnlyOneOf },
+            "mutually_exclusive" => quote! { MutuallyExclusive },
+            _ => quote! { MutuallyRequired }
+          };
+          let names = field_list(fields, &meta)?;
+          quote! { kong_rs::config::EntityCheck::#variant(vec![#(#names.to_owned()),*]) }
+        },
+        "conditional" => {
+          let (mut if_field, mut then_field) = (None, None);
+          let (mut if_match, mut then_match) = (vec![], vec![]);
+
+          meta.parse_nested_meta(|inner| {
+            let key = inner.path.get_ident().map(|x| x.to_string()).unwrap_or_default();
+            match key.as_str() {
+              "if_field" => if_field = Some(field_name(fields, inner.value()?.parse()?)?),
+              "then_field" => then_field = Some(field_name(fields, inner.value()?.parse()?)?),
+              "if_eq" | "then_eq" => {
+                let value: Expr = inner.value()?.parse()?;
+                let target = if key == "if_eq" { &mut if_match } else { &mut then_match };
+                target.push(quote! { eq: Some(kong_rs::config::to_value(#value)), });
+              },
+              "if_one_of" | "then_one_of" => {
+                let value: syn::ExprArray = inner.value()?.parse()?;
+                let elems = value.elems.iter();
+                let target = if key == "if_one_of" { &mut if_match } else { &mut then_match };
+                target.push(quote! { one_of: Some(vec![#(kong_rs::config::to_value(#elems)),*]), });
+              },
+              "then_required" => then_match.push(quote! { required: Some(true), }),
+              _ => return Err(inner.error("unknown conditional attribute"))
             }
+            Ok(())
+          })?;
+
+          let (Some(if_field), Some(then_field)) = (if_field, then_field) else {
+            return Err(meta.error("conditional requires if_field and then_field"));
+          };
+          if if_match.is_empty() || then_match.is_empty() {
+            return Err(meta.error("conditional requires one of if_eq/if_one_of and one of then_eq/then_one_of/then_required"));
+          }
 
-            kong_rs::config::RenderedConfigFieldVariant {
-              ty: Self::ty().to_owned(),
-              required,
-              default: None,
-              one_of: Self::variants(),
-              elements: None,
-              fields: Some(match default {
-                Some(default) => vec![#(#with_default),*],
-                None => vec![#(#without_default),*],
-              })
+          quote! {
+            kong_rs::config::EntityCheck::Conditional {
+              if_field: #if_field.to_owned(),
+              if_match: kong_rs::config::FieldMatch { #(#if_match)* ..Default::default() },
+              then_field: #then_field.to_owned(),
+              then_match: kong_rs::config::FieldMatch { #(#then_match)* ..Default::default() }
             }
           }
+        },
+        _ => return Err(meta.error("unknown kong attribute; records only take entity checks, field attributes go on the fields"))
+      };
+
+      checks.push(check);
+      Ok(())
+    })?;
+  }
+
+  Ok(checks)
+}
+
+fn default_expr(default: &serde_attrs::DefaultValue, ty: impl quote::ToTokens) -> TokenStream2 {
+  match default {
+    serde_attrs::DefaultValue::Trait => quote! { <#ty as Default>::default() },
+    serde_attrs::DefaultValue::Path(path) => quote! { #path() }
+  }
+}
+
+// Maps are recognized by name, as that's all a derive can see.
+fn is_map(ty: &syn::Type) -> bool {
+  match ty {
+    syn::Type::Path(path) => path.path.segments.last().is_some_and(|x| matches!(x.ident.to_string().as_str(), "HashMap" | "BTreeMap" | "IndexMap" | "Map")),
+    _ => false
+  }
+}
+
+struct Record {
+  with_default: Vec<TokenStream2>,
+  without_default: Vec<TokenStream2>,
+  checks: Vec<TokenStream2>,
+  bounds: Vec<syn::WherePredicate>
+}
+
+// Renders named fields into pushes onto `fields` and `entity_checks`, taking defaults from `default` if there is one.
+// `container_default` is whether the record has a #[serde(default)] of its own.
+fn record(fields: syn::FieldsNamed, rename_all: Option<serde_attrs::RenameRule>, attrs: &[syn::Attribute], container_default: bool) -> syn::Result<Record> {
+  let mut parsed = vec![];
+  for field in fields.named {
+    let serde = serde_attrs::field(&field.attrs)?;
+    let ident = field.ident.clone().unwrap();
+    let name = serde.rename.clone().unwrap_or_else(|| match rename_all {
+      Some(rule) => rule.apply_to_field(&ident.unraw().to_string()),
+      None => ident.unraw().to_string()
+    });
+    parsed.push((ident, name, field, serde));
+  }
+
+  let names: Vec<_> = parsed.iter().map(|(ident, name, _, _)| (ident.clone(), name.clone())).collect();
+  let mut record = Record { with_default: vec![], without_default: vec![], checks: entity_checks(attrs, &names)?, bounds: vec![] };
+
+  for (ident, name, field, serde) in parsed {
+    if serde.skip {
+      continue;
+    }
+
+    if let Some(alias) = serde.aliases.first() {
+      return Err(syn::Error::new_spanned(alias, "serde alias is not supported by PluginConfig on fields, as Kong schemas have no aliases"));
+    }
+
+    let ty = field.ty;
+
+    if serde.flatten {
+      if is_map(&ty) {
+        return Err(syn::Error::new_spanned(&ty, "Flattened maps aren't supported by PluginConfig, as Kong schemas can't take arbitrary keys next to fields"));
+      }
+      no_kong_attrs(&field.attrs, "#[kong] attributes on a flattened field are ignored; put them on the fields of the flattened type")?;
+      for (tokens, default) in [(&mut record.without_default, quote! { None }), (&mut record.with_default, quote! { Some(default.#ident) })] {
+        tokens.push(quote! {
+          let flattened = <#ty as kong_rs::config::PluginConfigFieldVariant>::render(#default, true);
+          fields.extend(flattened.fields.into_iter().flatten());
+          entity_checks.extend(flattened.entity_checks.into_iter().flatten());
+        });
+      }
+      continue;
+    }
+
+    // A serde default already says what the field defaults to, and is what Kong's default has to match.
+    let no_default = match (&serde.default, container_default) {
+      (Some(_), _) => Some("#[kong(default)] can't be combined with #[serde(default)]; the field already defaults to the serde default"),
+      (None, true) => Some("#[kong(default)] can't be used in a struct with #[serde(default)]; the field already defaults to the struct's default"),
+      (None, false) => None
+    };
+    let overrides = field_overrides(&field.attrs, &name, no_default)?;
+    if matches!(serde.default, Some(serde_attrs::DefaultValue::Trait)) {
+      record.bounds.push(syn::parse_quote! { #ty: Default });
+    }
+    let serde_default = serde.default.as_ref().map(|default| {
+      let expr = default_expr(default, &ty);
+      quote! {
+        if field.default.is_none() {
+          let value = kong_rs::config::to_value::<#ty>(#expr);
+          if !value.is_null() {
+            field.default = Some(value);
+          }
         }
+      }
+    });
 
-        impl kong_rs::config::PluginConfig for #ident { }
-      }.into()
-    },
-    syn::Data::Enum(en) => {
-      let variants = en.variants.into_iter().map(|variant| match variant.fields {
-        syn::Fields::Unit => {
-          variant.ident.to_string()
-        },
-        _ => panic!("Enums in configs can only be of the unit variant.")
+    for (tokens, default) in [(&mut record.without_default, quote! { None }), (&mut record.with_default, quote! { Some(default.#ident) })] {
+      tokens.push(quote! {
+        fields.push(std::collections::HashMap::from([(#name.to_owned(), {
+          let mut field = <#ty as kong_rs::config::PluginConfigFieldVariant>::render(#default, false);
+          #serde_default
+          #(#overrides)*
+          field
+        })]));
       });
-      
-      quote! {
-        impl kong_rs::config::PluginConfigFieldVariant for #ident {
-          fn ty() -> &'static str { "string" }
-          fn variants() -> Option<Vec<&'static str>> { Some(vec![#(#variants),*]) }
+    }
+  }
+
+  Ok(record)
+}
+
+// Every type parameter has to be renderable itself, and the config as a whole deserializable.
+fn impl_header(ident: &syn::Ident, generics: &syn::Generics, bounds: Vec<syn::WherePredicate>) -> (TokenStream2, TokenStream2) {
+  let mut generics = generics.clone();
+  let params: Vec<_> = generics.type_params().map(|x| x.ident.clone()).collect();
+
+  let where_clause = generics.make_where_clause();
+  for param in params {
+    where_clause.predicates.push(syn::parse_quote! { #param: kong_rs::config::PluginConfigFieldVariant });
+  }
+  where_clause.predicates.extend(bounds);
+
+  let (impl_generics, ty_generics, where_clause) = generics.split_for_impl();
+  let header = quote! { #impl_generics kong_rs::config::PluginConfigFieldVariant for #ident #ty_generics #where_clause };
+
+  let mut config_where = where_clause.cloned().unwrap();
+  config_where.predicates.push(syn::parse_quote! { Self: ::serde::de::DeserializeOwned + Send });
+  let config_header = quote! { #impl_generics kong_rs::config::PluginConfig for #ident #ty_generics #config_where };
+
+  (header, config_header)
+}
+
+fn derive_struct(ident: syn::Ident, generics: syn::Generics, attrs: Vec<syn::Attribute>, st: syn::DataStruct) -> syn::Result<TokenStream2> {
+  let container = serde_attrs::container(&attrs)?;
+
+  let fields = match st.fields {
+    syn::Fields::Named(fields) => fields,
+    // Newtypes are (de)serialized as the value they wrap, so they render as it too.
+    syn::Fields::Unnamed(fields) if fields.unnamed.len() == 1 => {
+      no_kong_attrs(&attrs, "#[kong] attributes on a newtype struct go on its field")?;
+      let field = fields.unnamed.into_iter().next().unwrap();
+      let ty = field.ty;
+      let overrides = field_overrides(&field.attrs, &ident.to_string(), None)?;
+      let (header, config_header) = impl_header(&ident, &generics, vec![]);
+
+      return Ok(quote! {
+        impl #header {
+          fn ty() -> &'static str { <#ty as kong_rs::config::PluginConfigFieldVariant>::ty() }
+          fn variants() -> Option<Vec<&'static str>> { <#ty as kong_rs::config::PluginConfigFieldVariant>::variants() }
+          fn between() -> Option<kong_rs::config::Between> { <#ty as kong_rs::config::PluginConfigFieldVariant>::between() }
+          fn pattern() -> Option<&'static str> { <#ty as kong_rs::config::PluginConfigFieldVariant>::pattern() }
+          fn required() -> bool { <#ty as kong_rs::config::PluginConfigFieldVariant>::required() }
+          fn render(default: Option<Self>, skip_required: bool) -> kong_rs::config::RenderedConfigFieldVariant {
+            let mut field = <#ty as kong_rs::config::PluginConfigFieldVariant>::render(default.map(|x| x.0), skip_required);
+            #(#overrides)*
+            field
+          }
         }
 
-        impl kong_rs::config::PluginConfig for #ident { }
-      }.into()
+        impl #config_header { }
+      });
     },
-    syn::Data::Union(_) => panic!("An enum cannot be a plugin config"),
+    fields => return Err(syn::Error::new_spanned(fields, "Tuple structs in configs must have exactly one field"))
+  };
+
+  let Record { with_default, without_default, checks, bounds } = record(fields, container.rename_all, &attrs, container.default.is_some())?;
+  let (header, config_header) = impl_header(&ident, &generics, bounds);
+
+  let container_default = container.default.as_ref().map(|default| {
+    let expr = default_expr(default, quote! { Self });
+    quote! { let default = default.or_else(|| Some(#expr)); }
+  });
+
+  Ok(quote! {
+    impl #header {
+      fn ty() -> &'static str { "record" }
+      fn render(default: Option<Self>, skip_required: bool) -> kong_rs::config::RenderedConfigFieldVariant {
+        #container_default
+        let mut required = Some(Self::required());
+        if skip_required {
+          required = None;
+        }
+
+        let mut fields: Vec<std::collections::HashMap<String, kong_rs::config::RenderedConfigFieldVariant>> = vec![];
+        let mut entity_checks: Vec<kong_rs::config::EntityCheck> = vec![#(#checks),*];
+        match default {
+          Some(default) => { #(#with_default)* },
+          None => { #(#without_default)* }
+        }
+
+        kong_rs::config::RenderedConfigFieldVariant {
+          ty: Self::ty().to_owned(),
+          required,
+          fields: Some(fields),
+          entity_checks: if entity_checks.is_empty() { None } else { Some(entity_checks) },
+          ..Default::default()
+        }
+      }
+    }
+
+    impl #config_header { }
+  })
+}
+
+fn derive_enum(ident: syn::Ident, generics: syn::Generics, attrs: Vec<syn::Attribute>, en: syn::DataEnum) -> syn::Result<TokenStream2> {
+  let container = serde_attrs::container(&attrs)?;
+  if let Some(span) = container.tagging {
+    return Err(syn::Error::new(span, "Configs only support externally tagged enums"));
+  }
+  no_kong_attrs(&attrs, "#[kong] attributes aren't supported on enums; put them on the fields of its variants")?;
+
+  let is_unit = |x: &syn::Variant| matches!(x.fields, syn::Fields::Unit);
+  if en.variants.iter().any(is_unit) && !en.variants.iter().all(is_unit) {
+    return Err(syn::Error::new_spanned(&ident, "Enums in configs must have either only unit variants or only data variants"));
   }
-}
\ No newline at end of file
+
+  let mut variants = vec![];
+  for variant in en.variants {
+    let serde = serde_attrs::field(&variant.attrs)?;
+    if serde.skip {
+      continue;
+    }
+
+    let name = serde.rename.clone().unwrap_or_else(|| match container.rename_all {
+      Some(rule) => rule.apply_to_variant(&variant.ident.unraw().to_string()),
+      None => variant.ident.unraw().to_string()
+    });
+    variants.push((name, variant, serde));
+  }
+
+  // Unit variants are plain strings.
+  if variants.iter().all(|(_, x, _)| is_unit(x)) {
+    for (_, variant, _) in &variants {
+      no_kong_attrs(&variant.attrs, "#[kong] attributes aren't supported on unit variants")?;
+    }
+    let names = variants.into_iter().flat_map(|(name, _, serde)| std::iter::once(name).chain(serde.aliases.into_iter().map(|x| x.value())));
+    let (header, config_header) = impl_header(&ident, &generics, vec![]);
+
+    return Ok(quote! {
+      impl #header {
+        fn ty() -> &'static str { "string" }
+        fn variants() -> Option<Vec<&'static str>> { Some(vec![#(#names),*]) }
+      }
+
+      impl #config_header { }
+    });
+  }
+
+  // Data variants are objects keyed by the variant, so they become a record of optional fields of which only
+  // one may be set.
+  let mut fields = vec![];
+  let mut bounds = vec![];
+  for (name, variant, serde) in &variants {
+    if let Some(alias) = serde.aliases.first() {
+      return Err(syn::Error::new_spanned(alias, "serde alias is not supported by PluginConfig on data variants, as Kong schemas have no aliases"));
+    }
+    let field = match &variant.fields {
+      syn::Fields::Unnamed(unnamed) if unnamed.unnamed.len() == 1 => {
+        no_kong_attrs(&variant.attrs, "#[kong] attributes on a newtype variant go on its field")?;
+        let inner = &unnamed.unnamed[0];
+        let ty = &inner.ty;
+        let overrides = field_overrides(&inner.attrs, name, Some("data enum variants can't have a default, as only one of them may be set"))?;
+        quote! {
+          let mut field = <#ty as kong_rs::config::PluginConfigFieldVariant>::render(None, false);
+          #(#overrides)*
+          field
+        }
+      },
+      syn::Fields::Named(named) => {
+        let rename_all = serde_attrs::container(&variant.attrs)?.rename_all.or(container.rename_all_fields);
+        let Record { without_default, checks, bounds: record_bounds, .. } = record(named.clone(), rename_all, &variant.attrs, false)?;
+        bounds.extend(record_bounds);
+        quote! {
+          let mut fields: Vec<std::collections::HashMap<String, kong_rs::config::RenderedConfigFieldVariant>> = vec![];
+          let mut entity_checks: Vec<kong_rs::config::EntityCheck> = vec![#(#checks),*];
+          #(#without_default)*
+          kong_rs::config::RenderedConfigFieldVariant {
+            ty: "record".to_owned(),
+            fields: Some(fields),
+            entity_checks: if entity_checks.is_empty() { None } else { Some(entity_checks) },
+            ..Default::default()
+          }
+        }
+      },
+      fields => return Err(syn::Error::new_spanned(fields, "Enum variants in configs must be unit, newtype or struct variants"))
+    };
+
+    fields.push(quote! {
+      std::collections::HashMap::from([(#name.to_owned(), {
+        let mut field = { #field };
+        field.required = Some(false);
+        field.default = None;
+        field
+      })])
+    });
+  }
+
+  let names = variants.iter().map(|(name, _, _)| name);
+  let (header, config_header) = impl_header(&ident, &generics, bounds);
+
+  Ok(quote! {
+    impl #header {
+      fn ty() -> &'static str { "record" }
+      fn render(default: Option<Self>, skip_required: bool) -> kong_rs::config::RenderedConfigFieldVariant {
+        let mut required = Some(Self::required());
+        if skip_required {
+          required = None;
+        }
+
+        kong_rs::config::RenderedConfigFieldVariant {
+          ty: Self::ty().to_owned(),
+          required,
+          default: default.map(kong_rs::config::to_value),
+          fields: Some(vec![#(#fields),*]),
+          entity_checks: Some(vec![kong_rs::config::EntityCheck::OnlyOneOf(vec![#(#names.to_owned()),*])]),
+          ..Default::default()
+        }
+      }
+    }
+
+    impl #config_header { }
+  })
+}
+
+#[proc_macro_derive(PluginConfig, attributes(kong))]
+pub fn plugin_config_derive(item: TokenStream) -> TokenStream {
+  let DeriveInput {
+    attrs, vis: _, ident, generics, data
+  } = parse_macro_input!(item as DeriveInput);
+
+  let result = match data {
+    syn::Data::Struct(st) => derive_struct(ident, generics, attrs, st),
+    syn::Data::Enum(en) => derive_enum(ident, generics, attrs, en),
+    syn::Data::Union(un) => Err(syn::Error::new_spanned(un.union_token, "A union cannot be a plugin config")),
+  };
+
+  result.unwrap_or_else(|e| e.to_compile_error()).into()
+}
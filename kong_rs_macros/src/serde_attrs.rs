@@ -0,0 +1,192 @@
+use syn::{meta::ParseNestedMeta, Expr, ExprPath, LitStr, Token};
+
+// The subset of serde's attributes that change which keys and values a config deserializes from, so the
+// rendered schema can match. Only the deserialize side of a rename matters here.
+
+#[derive(Clone, Copy)]
+pub enum RenameRule {
+  Lower,
+  Upper,
+  Pascal,
+  Camel,
+  Snake,
+  ScreamingSnake,
+  Kebab,
+  ScreamingKebab
+}
+
+impl RenameRule {
+  fn parse(lit: &LitStr) -> syn::Result<Self> {
+    Ok(match lit.value().as_str() {
+      "lowercase" => Self::Lower,
+      "UPPERCASE" => Self::Upper,
+      "PascalCase" => Self::Pascal,
+      "camelCase" => Self::Camel,
+      "snake_case" => Self::Snake,
+      "SCREAMING_SNAKE_CASE" => Self::ScreamingSnake,
+      "kebab-case" => Self::Kebab,
+      "SCREAMING-KEBAB-CASE" => Self::ScreamingKebab,
+      _ => return Err(syn::Error::new_spanned(lit, "unknown rename rule"))
+    })
+  }
+
+  // Fields are assumed to be snake_case, as serde does.
+  pub fn apply_to_field(self, field: &str) -> String {
+    match self {
+      Self::Lower | Self::Snake => field.to_owned(),
+      Self::Upper | Self::ScreamingSnake => field.to_ascii_uppercase(),
+      Self::Pascal | Self::Camel => {
+        let mut out = String::new();
+        let mut capitalize = matches!(self, Self::Pascal);
+        for c in field.chars() {
+          match c {
+            '_' => capitalize = true,
+            c if capitalize => { out.push(c.to_ascii_uppercase()); capitalize = false; },
+            c => out.push(c)
+          }
+        }
+        out
+      },
+      Self::Kebab => field.replace('_', "-"),
+      Self::ScreamingKebab => field.to_ascii_uppercase().replace('_', "-")
+    }
+  }
+
+  // Variants are assumed to be PascalCase, as serde does.
+  pub fn apply_to_variant(self, variant: &str) -> String {
+    match self {
+      Self::Pascal => variant.to_owned(),
+      Self::Lower => variant.to_ascii_lowercase(),
+      Self::Upper => variant.to_ascii_uppercase(),
+      Self::Camel => {
+        let mut chars = variant.chars();
+        chars.next().map(|x| x.to_ascii_lowercase()).into_iter().chain(chars).collect()
+      },
+      Self::Snake | Self::ScreamingSnake | Self::Kebab | Self::ScreamingKebab => {
+        let mut snake = String::new();
+        for (i, c) in variant.char_indices() {
+          if i > 0 && c.is_uppercase() {
+            snake.push('_');
+          }
+          snake.push(c.to_ascii_lowercase());
+        }
+        match self {
+          Self::Snake => snake,
+          Self::ScreamingSnake => snake.to_ascii_uppercase(),
+          Self::Kebab => snake.replace('_', "-"),
+          _ => snake.to_ascii_uppercase().replace('_', "-")
+        }
+      }
+    }
+  }
+}
+
+pub enum DefaultValue {
+  Trait,
+  Path(ExprPath)
+}
+
+#[derive(Default)]
+pub struct Container {
+  pub rename_all: Option<RenameRule>,
+  pub rename_all_fields: Option<RenameRule>,
+  pub default: Option<DefaultValue>,
+  // Set if the enum is internally, adjacently or un-tagged.
+  pub tagging: Option<proc_macro2::Span>
+}
+
+#[derive(Default)]
+pub struct Field {
+  pub rename: Option<String>,
+  pub aliases: Vec<LitStr>,
+  pub skip: bool,
+  pub default: Option<DefaultValue>,
+  pub flatten: bool
+}
+
+fn ignore(meta: &ParseNestedMeta) -> syn::Result<()> {
+  if meta.input.peek(Token![=]) {
+    meta.value()?.parse::<Expr>()?;
+  } else if meta.input.peek(syn::token::Paren) {
+    meta.parse_nested_meta(|inner| ignore(&inner))?;
+  }
+  Ok(())
+}
+
+// Either `key = "value"` or `key(serialize = "..", deserialize = "value")`.
+fn deserialize_name(meta: &ParseNestedMeta) -> syn::Result<Option<LitStr>> {
+  if meta.input.peek(Token![=]) {
+    return Ok(Some(meta.value()?.parse()?));
+  }
+
+  let mut name = None;
+  meta.parse_nested_meta(|inner| {
+    match inner.path.is_ident("deserialize") {
+      true => name = Some(inner.value()?.parse()?),
+      false => ignore(&inner)?
+    }
+    Ok(())
+  })?;
+  Ok(name)
+}
+
+fn default_value(meta: &ParseNestedMeta) -> syn::Result<DefaultValue> {
+  match meta.input.peek(Token![=]) {
+    true => Ok(DefaultValue::Path(meta.value()?.parse::<LitStr>()?.parse()?)),
+    false => Ok(DefaultValue::Trait)
+  }
+}
+
+fn serde_attrs(attrs: &[syn::Attribute], mut f: impl FnMut(&ParseNestedMeta) -> syn::Result<bool>) -> syn::Result<()> {
+  for attr in attrs.iter().filter(|x| x.path().is_ident("serde")) {
+    attr.parse_nested_meta(|meta| {
+      if !f(&meta)? {
+        ignore(&meta)?;
+      }
+      Ok(())
+    })?;
+  }
+  Ok(())
+}
+
+pub fn container(attrs: &[syn::Attribute]) -> syn::Result<Container> {
+  let mut container = Container::default();
+  serde_attrs(attrs, |meta| {
+    if meta.path.is_ident("rename_all") {
+      container.rename_all = deserialize_name(meta)?.map(|x| RenameRule::parse(&x)).transpose()?;
+    } else if meta.path.is_ident("rename_all_fields") {
+      container.rename_all_fields = deserialize_name(meta)?.map(|x| RenameRule::parse(&x)).transpose()?;
+    } else if meta.path.is_ident("tag") || meta.path.is_ident("content") || meta.path.is_ident("untagged") {
+      container.tagging = Some(syn::spanned::Spanned::span(&meta.path));
+      ignore(meta)?;
+    } else if meta.path.is_ident("default") {
+      container.default = Some(default_value(meta)?);
+    } else {
+      return Ok(false);
+    }
+    Ok(true)
+  })?;
+  Ok(container)
+}
+
+// Used for both struct fields and enum variants.
+pub fn field(attrs: &[syn::Attribute]) -> syn::Result<Field> {
+  let mut field = Field::default();
+  serde_attrs(attrs, |meta| {
+    if meta.path.is_ident("rename") {
+      field.rename = deserialize_name(meta)?.map(|x| x.value());
+    } else if meta.path.is_ident("alias") {
+      field.aliases.push(meta.value()?.parse()?);
+    } else if meta.path.is_ident("skip") || meta.path.is_ident("skip_deserializing") {
+      field.skip = true;
+    } else if meta.path.is_ident("default") {
+      field.default = Some(default_value(meta)?);
+    } else if meta.path.is_ident("flatten") {
+      field.flatten = true;
+    } else {
+      return Ok(false);
+    }
+    Ok(true)
+  })?;
+  Ok(field)
+}